@@ -0,0 +1,377 @@
+use crate::context::Context;
+use crate::record::{fingerprint_in, fingerprint_of, running_fingerprints};
+use crate::utils::get_systemimg_path;
+use anyhow::{Ok, anyhow};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BACKUP_DIR: &str = "backups";
+const INDEX_FILE: &str = "index.json";
+const OBJECT_DIR: &str = "objects";
+
+/// An original file wsu replaced, with what it takes to put it back as it was.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backup {
+    /// The install or image set the file belongs to.
+    pub scope: String,
+    /// Path relative to the rootfs.
+    pub path: String,
+    pub sha1: String,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Extended attributes, hex encoded.
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, String>,
+    pub created: u64,
+    /// Build fingerprint of the image the file was taken from, a backup is
+    /// stale once the image is replaced, by `waydroid upgrade` for example.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+impl Backup {
+    /// Whether the backup belongs to the image with `fingerprint`. Backups
+    /// made by older versions, and rootfs without a fingerprint, can't tell.
+    pub fn matches(&self, fingerprint: Option<&str>) -> bool {
+        match (self.fingerprint.as_deref(), fingerprint) {
+            (Some(own), Some(fingerprint)) => own == fingerprint,
+            _ => true,
+        }
+    }
+}
+
+pub struct BackupStore {
+    dir: PathBuf,
+    scope: String,
+    entries: Vec<Backup>,
+    /// Fingerprints of the images below the overlay, which has no build.prop of its own.
+    lower: Option<(String, String)>,
+}
+
+/// Backups of the Waydroid install are kept per system image, or per overlay
+/// when overlays are in use.
+pub fn install_scope(ctx: &Context) -> anyhow::Result<String> {
+    if ctx.has_overlay()? {
+        return Ok(ctx.overlay().to_string_lossy().to_string());
+    }
+    Ok(image_scope(&get_systemimg_path(ctx)?))
+}
+
+/// The store of the Waydroid install. Files patched into the overlay are
+/// fingerprinted against the images below it, read while the container runs.
+pub fn install_backups(ctx: &Context) -> anyhow::Result<BackupStore> {
+    let mut store = BackupStore::open(ctx, &install_scope(ctx)?)?;
+    if ctx.has_overlay()? {
+        store.lower = running_fingerprints(ctx).ok();
+    }
+    Ok(store)
+}
+
+pub fn image_scope(system: &Path) -> String {
+    fs::canonicalize(system)
+        .unwrap_or_else(|_| system.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+impl BackupStore {
+    pub fn open(ctx: &Context, scope: &str) -> anyhow::Result<Self> {
+        let dir = ctx.state_dir.join(BACKUP_DIR);
+        let index = dir.join(INDEX_FILE);
+        let entries = if index.exists() {
+            serde_json::from_str(&fs::read_to_string(&index)?)
+                .map_err(|e| anyhow!("Invalid {}: {}", index.to_string_lossy(), e))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            dir,
+            scope: scope.to_string(),
+            entries,
+            lower: None,
+        })
+    }
+
+    /// Every backup in the store, whatever its scope.
+    pub fn all(&self) -> &[Backup] {
+        &self.entries
+    }
+
+    pub fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Backup> {
+        let path = normalize(path);
+        self.entries
+            .iter()
+            .find(|entry| entry.scope == self.scope && entry.path == path)
+    }
+
+    /// Where the stored copy of `backup` lives.
+    pub fn stored_copy(&self, backup: &Backup) -> PathBuf {
+        self.object(&backup.sha1)
+    }
+
+    fn fingerprint(&self, rootfs: &Path, path: &str) -> Option<String> {
+        match &self.lower {
+            Some(fingerprints) => fingerprint_in(fingerprints, path),
+            None => fingerprint_of(rootfs, path),
+        }
+    }
+
+    fn object(&self, sha1: &str) -> PathBuf {
+        self.dir.join(OBJECT_DIR).join(sha1)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(
+            self.dir.join(INDEX_FILE),
+            serde_json::to_string_pretty(&self.entries)? + "\n",
+        )?;
+        Ok(())
+    }
+
+    fn store(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        metadata_from: &Path,
+        fingerprint: Option<String>,
+    ) -> anyhow::Result<()> {
+        let metadata = fs::symlink_metadata(metadata_from)?;
+        let sha1 = hash(data);
+        let object = self.object(&sha1);
+        if !object.exists() {
+            fs::create_dir_all(self.dir.join(OBJECT_DIR))?;
+            fs::write(&object, data)?;
+        }
+        self.entries.push(Backup {
+            scope: self.scope.clone(),
+            path: path.to_string(),
+            sha1,
+            size: data.len() as u64,
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            xattrs: read_xattrs(metadata_from)?,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            fingerprint,
+        });
+        self.save()
+    }
+
+    /// Drops the entry of `path` in this scope, and its stored copy when
+    /// nothing else refers to it.
+    fn forget(&mut self, path: &str) -> anyhow::Result<()> {
+        let Some(backup) = self.get(path).cloned() else {
+            return Ok(());
+        };
+        self.entries
+            .retain(|entry| !(entry.scope == backup.scope && entry.path == backup.path));
+        if !self.entries.iter().any(|entry| entry.sha1 == backup.sha1) {
+            let object = self.object(&backup.sha1);
+            if object.exists() {
+                fs::remove_file(object)?;
+            }
+        }
+        self.save()
+    }
+
+    /// Backs up `path` below `rootfs` unless a backup of it from the same
+    /// image already exists, returns whether one was made. A backup from an
+    /// image that has since been replaced is dropped for the new original.
+    pub fn backup(&mut self, rootfs: &Path, path: &str) -> anyhow::Result<bool> {
+        let path = normalize(path);
+        let fingerprint = self.fingerprint(rootfs, &path);
+        if let Some(existing) = self.get(&path) {
+            if existing.matches(fingerprint.as_deref()) {
+                return Ok(false);
+            }
+            self.forget(&path)?;
+        }
+        let file = rootfs.join(&path);
+        let data = fs::read(&file)
+            .map_err(|e| anyhow!("Couldn't back up {}: {}", file.to_string_lossy(), e))?;
+        self.store(&path, &data, &file, fingerprint)?;
+        Ok(true)
+    }
+
+    /// Moves a `<path>.gz` backup left by older wsu versions into the store.
+    /// Mode, owner and xattrs come from the file it backs up.
+    pub fn migrate_sidecar(&mut self, rootfs: &Path, path: &str) -> anyhow::Result<bool> {
+        let path = normalize(path);
+        let file = rootfs.join(&path);
+        let sidecar = rootfs.join(format!("{}.gz", path));
+        if !sidecar.exists() || self.get(&path).is_some() {
+            return Ok(false);
+        }
+        let mut data = Vec::new();
+        GzDecoder::new(File::open(&sidecar)?)
+            .read_to_end(&mut data)
+            .map_err(|e| anyhow!("Couldn't read {}: {}", sidecar.to_string_lossy(), e))?;
+        let metadata_from = if file.exists() { &file } else { &sidecar };
+        let fingerprint = self.fingerprint(rootfs, &path);
+        self.store(&path, &data, metadata_from, fingerprint)?;
+        fs::remove_file(sidecar)?;
+        Ok(true)
+    }
+
+    /// Checks that the stored copy of `backup` is intact.
+    pub fn verify(&self, backup: &Backup) -> anyhow::Result<()> {
+        let object = self.object(&backup.sha1);
+        let data = fs::read(&object).map_err(|e| anyhow!("{}", e))?;
+        if hash(&data) != backup.sha1 {
+            return Err(anyhow!("stored copy doesn't match its checksum"));
+        }
+        Ok(())
+    }
+
+    /// Puts the backup of `path` back below `rootfs` and drops it from the
+    /// store, returns false when there is none. A backup of a replaced image
+    /// is dropped without restoring it, the file there is already the original.
+    pub fn restore(&mut self, rootfs: &Path, path: &str) -> anyhow::Result<bool> {
+        let Some(backup) = self.get(path).cloned() else {
+            return Ok(false);
+        };
+        if !backup.matches(self.fingerprint(rootfs, &backup.path).as_deref()) {
+            self.forget(&backup.path)?;
+            return Ok(false);
+        }
+        self.verify(&backup)
+            .map_err(|e| anyhow!("Backup of {} is damaged: {}", backup.path, e))?;
+
+        let file = rootfs.join(&backup.path);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::symlink_metadata(&file).is_ok() {
+            fs::remove_file(&file)?;
+        }
+        fs::write(&file, fs::read(self.object(&backup.sha1))?)?;
+        fs::set_permissions(&file, Permissions::from_mode(backup.mode))?;
+        std::os::unix::fs::lchown(&file, Some(backup.uid), Some(backup.gid))?;
+        for (name, value) in &backup.xattrs {
+            write_xattr(&file, name, &from_hex(value)?)?;
+        }
+
+        self.forget(&backup.path)?;
+        Ok(true)
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.trim().trim_start_matches('/').to_string()
+}
+
+fn hash(data: &[u8]) -> String {
+    to_hex(&Sha1::digest(data))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow!("Invalid hex value {}", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| anyhow!(e)))
+        .collect()
+}
+
+fn read_xattrs(file: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    let c_file = CString::new(file.to_string_lossy().as_bytes())?;
+    // SAFETY: a null buffer with size 0 only queries the length
+    let len = unsafe { libc::llistxattr(c_file.as_ptr(), std::ptr::null_mut(), 0) };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENOTSUP) {
+            return Ok(BTreeMap::new());
+        }
+        return Err(anyhow!(
+            "Couldn't list xattrs of {}: {}",
+            file.to_string_lossy(),
+            err
+        ));
+    }
+    let mut names = vec![0u8; len as usize];
+    // SAFETY: names has room for len bytes
+    let len =
+        unsafe { libc::llistxattr(c_file.as_ptr(), names.as_mut_ptr() as *mut _, names.len()) };
+    if len < 0 {
+        return Err(anyhow!(
+            "Couldn't list xattrs of {}: {}",
+            file.to_string_lossy(),
+            io::Error::last_os_error()
+        ));
+    }
+    names.truncate(len as usize);
+
+    let mut xattrs = BTreeMap::new();
+    for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name)?;
+        // SAFETY: a null buffer with size 0 only queries the length
+        let size =
+            unsafe { libc::lgetxattr(c_file.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            continue;
+        }
+        let mut value = vec![0u8; size as usize];
+        // SAFETY: value has room for size bytes
+        let size = unsafe {
+            libc::lgetxattr(
+                c_file.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr() as *mut _,
+                value.len(),
+            )
+        };
+        if size < 0 {
+            continue;
+        }
+        value.truncate(size as usize);
+        xattrs.insert(String::from_utf8_lossy(name).to_string(), to_hex(&value));
+    }
+    Ok(xattrs)
+}
+
+fn write_xattr(file: &Path, name: &str, value: &[u8]) -> anyhow::Result<()> {
+    let c_file = CString::new(file.to_string_lossy().as_bytes())?;
+    let c_name = CString::new(name)?;
+    // SAFETY: all pointers are valid for the duration of the call
+    let ret = unsafe {
+        libc::lsetxattr(
+            c_file.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const _,
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "Couldn't set {} on {}: {}",
+            name,
+            file.to_string_lossy(),
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
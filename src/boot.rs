@@ -0,0 +1,72 @@
+use crate::container::WaydroidContainer;
+use crate::context::Context;
+use crate::error::WsuError;
+use crate::magisk_files::waydroid_su;
+use anyhow::Ok;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long restarts wait for Android before giving up.
+pub const BOOT_TIMEOUT: Duration = Duration::from_secs(120);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far Android has to get.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootStage {
+    /// `sys.boot_completed` is set.
+    Boot,
+    /// Booted and the Magisk daemon runs.
+    Magiskd,
+}
+
+impl BootStage {
+    fn describe(&self) -> &'static str {
+        match self {
+            BootStage::Boot => "Android to boot",
+            BootStage::Magiskd => "the Magisk daemon",
+        }
+    }
+}
+
+pub fn is_booted(ctx: &Context) -> bool {
+    waydroid_su(ctx, vec!["getprop", "sys.boot_completed"], true).is_ok_and(|out| out == "1")
+}
+
+pub fn magiskd_running(ctx: &Context) -> bool {
+    waydroid_su(ctx, vec!["pidof", "magiskd"], true).is_ok()
+}
+
+pub fn reached(ctx: &Context, stage: BootStage) -> bool {
+    match stage {
+        BootStage::Boot => is_booted(ctx),
+        BootStage::Magiskd => is_booted(ctx) && magiskd_running(ctx),
+    }
+}
+
+/// Polls the container until Android reaches `stage`. The container doesn't
+/// have to be running yet, it may still be starting.
+pub fn wait_for(ctx: &Context, stage: BootStage, timeout: Duration) -> anyhow::Result<()> {
+    ctx.report()
+        .step(&format!("Waiting for {}", stage.describe()));
+    let started = Instant::now();
+    while !reached(ctx, stage) {
+        if started.elapsed() >= timeout {
+            return Err(WsuError::Timeout(format!(
+                "Gave up waiting for {} after {}s",
+                stage.describe(),
+                timeout.as_secs()
+            ))
+            .into());
+        }
+        sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Restarts the container and waits until the Magisk daemon is up again.
+pub fn restart_and_wait(ctx: &Context, waydroid: &mut WaydroidContainer) -> anyhow::Result<()> {
+    ctx.report().step("Restarting Waydroid");
+    waydroid.stop(false)?;
+    waydroid.start()?;
+    wait_for(ctx, BootStage::Magiskd, BOOT_TIMEOUT)
+}
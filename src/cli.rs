@@ -1,4 +1,4 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 #[derive(Parser)]
 #[command(
     name = "wsu",
@@ -9,6 +9,44 @@ use clap::{Args, Parser, Subcommand};
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    #[arg(
+        long,
+        global = true,
+        help = "Waydroid directory [default: /var/lib/waydroid]"
+    )]
+    pub waydroid_dir: Option<String>,
+    #[arg(long, global = true, help = "LXC path [default: <WAYDROID_DIR>/lxc]")]
+    pub lxc_path: Option<String>,
+    #[arg(long, global = true, help = "LXC container name [default: waydroid]")]
+    pub container: Option<String>,
+    #[arg(long, global = true, help = "Android data directory on the host")]
+    pub data_dir: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "wsu state directory [default: /var/lib/wsu]"
+    )]
+    pub state_dir: Option<String>,
+    #[arg(
+        short,
+        long,
+        global = true,
+        conflicts_with_all = ["verbose", "json"],
+        help = "Only print errors and requested output"
+    )]
+    pub quiet: bool,
+    #[arg(
+        short,
+        long,
+        global = true,
+        conflicts_with = "json",
+        help = "Show every command run on the host and in the container"
+    )]
+    pub verbose: bool,
+    #[arg(long, global = true, help = "Report progress as JSON lines")]
+    pub json: bool,
+    #[arg(short, long, global = true, help = "Answer yes to every confirmation")]
+    pub yes: bool,
 }
 
 #[derive(Subcommand)]
@@ -22,7 +60,9 @@ pub enum Commands {
     #[command(about = "Remove Magisk")]
     Remove,
     #[command(about = "Update Magisk")]
-    Update,
+    Update(UpdateArgs),
+    #[command(about = "Re-apply Magisk patches after a Waydroid image upgrade")]
+    Repair(RepairArgs),
     #[command(about = "Manage magisk modules")]
     Module {
         #[command(subcommand)]
@@ -48,6 +88,195 @@ pub enum Commands {
         #[command(subcommand)]
         command: SuperuserCommand,
     },
+    #[command(about = "Check Magisk installation for problems")]
+    Doctor,
+    #[command(about = "Check and fix SELinux labels of Magisk files")]
+    Selinux {
+        #[command(subcommand)]
+        command: SelinuxCommand,
+    },
+    #[command(about = "Manage custom sepolicy rules")]
+    Sepolicy {
+        #[command(subcommand)]
+        command: SepolicyCommand,
+    },
+    #[command(about = "Manage backups of files replaced by wsu")]
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommand,
+    },
+    #[command(about = "List installed packages")]
+    Packages(PackagesArgs),
+    #[command(about = "Export Magisk state to a file")]
+    Export(ExportArgs),
+    #[command(about = "Apply Magisk state from a file")]
+    Apply(ApplyArgs),
+    #[command(about = "Patch standalone system and vendor images with Magisk")]
+    PatchImage(PatchImageArgs),
+    #[command(about = "Remove Magisk from standalone system and vendor images")]
+    UnpatchImage(UnpatchImageArgs),
+    #[command(about = "Show the log of commands that changed the install")]
+    History(HistoryArgs),
+    #[command(about = "Wait until Android has booted")]
+    Wait(WaitArgs),
+}
+
+impl Commands {
+    /// Whether the command changes the install, its images or Magisk's state,
+    /// and so goes into the history log.
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Commands::Install(_)
+            | Commands::Setup
+            | Commands::Remove
+            | Commands::Update(_)
+            | Commands::Repair(_)
+            | Commands::PatchImage(_)
+            | Commands::UnpatchImage(_) => true,
+            Commands::Module { command } => !matches!(command, ModuleCommand::List),
+            Commands::Magiskhide { command } => match command {
+                MagiskhideCommand::Sulist { command } => command.is_some(),
+                MagiskhideCommand::Status | MagiskhideCommand::Ls => false,
+                _ => true,
+            },
+            Commands::Denylist { command } => {
+                !matches!(command, DenylistCommand::Status | DenylistCommand::Ls)
+            }
+            Commands::Zygisk { command } => !matches!(command, ZygiskCommand::Status),
+            Commands::Superuser { command } => !matches!(command, SuperuserCommand::List),
+            Commands::Selinux { command } => matches!(command, SelinuxCommand::Relabel),
+            Commands::Sepolicy { command } => {
+                matches!(command, SepolicyCommand::Add(_) | SepolicyCommand::Rm(_))
+            }
+            Commands::Backup { command } => matches!(command, BackupCommand::Restore(_)),
+            Commands::Apply(args) => !args.dry_run,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum WaitFor {
+    Boot,
+    Magiskd,
+}
+
+#[derive(Args)]
+pub struct WaitArgs {
+    #[arg(long, default_value_t = 120, help = "Seconds to wait before giving up")]
+    pub timeout: u64,
+    #[arg(long = "for", value_enum, default_value_t = WaitFor::Boot, help = "Wait for sys.boot_completed or also for the Magisk daemon")]
+    pub stage: WaitFor,
+}
+
+#[derive(Args)]
+pub struct HistoryArgs {
+    #[arg(long, help = "Only entries of this user")]
+    pub user: Option<String>,
+    #[arg(
+        long,
+        help = "Only entries of this command, e.g. \"module\" or \"module install\""
+    )]
+    pub command: Option<String>,
+    #[arg(long, help = "Only failed commands")]
+    pub failed: bool,
+    #[arg(short = 'n', long, help = "Show only the last N entries")]
+    pub limit: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct PatchImageArgs {
+    #[arg(long, help = "Path to system image")]
+    pub system: String,
+    #[arg(long, help = "Path to vendor image")]
+    pub vendor: String,
+    #[arg(long, help = "Path to Magisk apk")]
+    pub apk: String,
+    #[arg(long, help = "Override the ABI detected from the image")]
+    pub abi: Option<String>,
+}
+
+#[derive(Args)]
+pub struct UnpatchImageArgs {
+    #[arg(long, help = "Path to system image")]
+    pub system: String,
+    #[arg(long, help = "Path to vendor image")]
+    pub vendor: String,
+}
+
+#[derive(Subcommand)]
+pub enum SelinuxCommand {
+    #[command(about = "Compare labels against the image's file contexts")]
+    Check,
+    #[command(about = "Fix mislabeled files")]
+    Relabel,
+}
+
+#[derive(Subcommand)]
+pub enum SepolicyCommand {
+    #[command(about = "Add a rule that is loaded on every boot")]
+    Add(SepolicyAddArgs),
+    #[command(about = "Remove a rule")]
+    Rm(SepolicyRuleArg),
+    #[command(about = "List rules")]
+    Ls,
+    #[command(about = "Show what the installed and live policy add to the stock one")]
+    Diff,
+}
+
+#[derive(Args)]
+pub struct SepolicyAddArgs {
+    #[arg(
+        required = true,
+        help = "Policy statement, e.g. \"allow untrusted_app shell_data_file file read\""
+    )]
+    pub rule: Vec<String>,
+    #[arg(long, help = "Also apply the rule to the running policy")]
+    pub live: bool,
+}
+
+#[derive(Args)]
+pub struct SepolicyRuleArg {
+    #[arg(required = true, help = "Policy statement to remove")]
+    pub rule: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum BackupCommand {
+    #[command(about = "List backed up files")]
+    List,
+    #[command(about = "Check stored backups against their checksums")]
+    Verify,
+    #[command(about = "Put a backed up file back into the Waydroid image")]
+    Restore(BackupRestoreArg),
+}
+
+#[derive(Args)]
+pub struct BackupRestoreArg {
+    #[arg(help = "Path inside the image, e.g. vendor/etc/selinux/precompiled_sepolicy")]
+    pub path: String,
+}
+
+#[derive(Args)]
+pub struct PackagesArgs {
+    #[arg(help = "Show only packages matching filter (optional)")]
+    pub filter: Option<String>,
+    #[arg(short, long, help = "Hide system apps")]
+    pub user_only: bool,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    #[arg(short, long, help = "Output file (prints to stdout if omitted)")]
+    pub output: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ApplyArgs {
+    #[arg(help = "Path to exported state file")]
+    pub file: String,
+    #[arg(long, help = "Only show changes that would be made")]
+    pub dry_run: bool,
 }
 
 #[derive(Args)]
@@ -56,6 +285,22 @@ pub struct InstallArgs {
     pub apk: Option<String>,
     #[arg(short, long, help = "Kitsune Magisk")]
     pub kitsune: bool,
+    #[arg(long, help = "Override the ABI detected from the Waydroid image")]
+    pub abi: Option<String>,
+}
+
+#[derive(Args)]
+pub struct UpdateArgs {
+    #[arg(long, help = "Override the ABI detected from the Waydroid image")]
+    pub abi: Option<String>,
+}
+
+#[derive(Args)]
+pub struct RepairArgs {
+    #[arg(short, long, help = "Repair even if the images haven't changed")]
+    pub force: bool,
+    #[arg(long, help = "Override the ABI detected from the Waydroid image")]
+    pub abi: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -88,13 +333,27 @@ pub enum DenylistCommand {
     #[command(about = "Disable Denylist")]
     Disable,
     #[command(about = "Add target to Denylist")]
-    Add(AddRemoveArgs),
+    Add(DenylistAddArgs),
     #[command(about = "Remove target from Denylist")]
-    Rm(AddRemoveArgs),
+    Rm(DenylistRmArgs),
     #[command(about = "List targets in Denylist")]
     Ls,
 }
 
+#[derive(Args)]
+pub struct DenylistAddArgs {
+    #[arg(help = "Target package or package/process")]
+    pub target: String,
+    #[arg(short, long, help = "Add every process declared by the package")]
+    pub all_processes: bool,
+}
+
+#[derive(Args)]
+pub struct DenylistRmArgs {
+    #[arg(help = "Target package or package/process")]
+    pub target: String,
+}
+
 #[derive(Args)]
 pub struct AddRemoveArgs {
     #[arg(help = "Target package")]
@@ -137,6 +396,12 @@ pub enum ZygiskCommand {
 pub struct ModuleInstallArg {
     #[arg(help = "Path to target magisk module")]
     pub module: String,
+    #[arg(
+        short,
+        long,
+        help = "Install even if Zygisk libraries don't match the container ABI"
+    )]
+    pub force: bool,
 }
 
 #[derive(Args)]
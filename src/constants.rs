@@ -1,10 +1,36 @@
 pub const WAYDROID_DIR: &str = "/var/lib/waydroid";
-pub const WAYDROID_CONFIG: &str = "/var/lib/waydroid/waydroid.cfg";
+pub const WAYDROID_CONTAINER: &str = "waydroid";
+pub const WSU_CONFIG: &str = "/etc/wsu/wsu.conf";
+pub const WSU_TEMPLATES: &str = "/etc/wsu/templates";
+pub const WSU_STATE_DIR: &str = "/var/lib/wsu";
+pub const WSU_HISTORY: &str = "/var/log/wsu/history.jsonl";
+pub const SYSTEM_IMAGE_MIN_SIZE: u64 = 3221225472;
+pub const VENDOR_IMAGE_MIN_SIZE: u64 = 1073741824;
+// patched precompiled_sepolicy plus the backups of the originals
+pub const SEPOLICY_SPACE: u64 = 16777216;
+pub const ZYGOTE_RCS: [&str; 3] = [
+    "init.zygote32.rc",
+    "init.zygote64_32.rc",
+    "init.zygote64.rc",
+];
 pub const MAGISK_DIR: &str = "system/etc/init/magisk";
 pub const MAGISKTMP: &str = "debug_ramdisk";
+pub const INIT_DIR: &str = "system/etc/init";
 pub const BOOTANIM_RC_PATH: &str = "system/etc/init/bootanim.rc";
 pub const BOOTANIM_RC_GZ_PATH: &str = "system/etc/init/bootanim.rc.gz";
 pub const BOOTANIM_RC: &str = include_str!("res/bootanim.rc");
-pub const BOOTANIM_RC_MAGISK: &str = include_str!("res/bootanim_magisk.rc");
-pub const BOOTANIM_RC_MAGISK_NEW: &str = include_str!("res/bootanim_magisk_new.rc");
+pub const BOOTANIM_RC_Q: &str = include_str!("res/bootanim_q.rc");
+pub const KITSUNE_RC: &str = include_str!("res/kitsune.rc");
+pub const MAGISK_RC: &str = include_str!("res/magisk.rc");
 pub const LOADPOLICY: &str = include_str!("res/loadpolicy.sh");
+pub const ZYGOTE_RESTART_EXEC: &str =
+    "    exec u:r:magisk:s0 0 0 -- /debug_ramdisk/magisk --zygote-restart";
+pub const PRECOMPILED_SEPOLICY: &str = "vendor/etc/selinux/precompiled_sepolicy";
+pub const VENDOR_FILE_CONTEXTS: &str = "vendor/etc/selinux/vendor_file_contexts";
+pub const SEPOLICY_FILES: [&str; 2] = [PRECOMPILED_SEPOLICY, VENDOR_FILE_CONTEXTS];
+/// Custom sepolicy rules, relative to the Android data dir.
+pub const SEPOLICY_RULES: &str = "adb/wsu/sepolicy.rule";
+pub const MAGISK_FILE_CONTEXTS: [&str; 2] = [
+    "/debug_ramdisk(/.*)?    u:object_r:magisk_file:s0",
+    "/data/adb/magisk(/.*)?   u:object_r:magisk_file:s0",
+];
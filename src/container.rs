@@ -1,13 +1,7 @@
-use colored::*;
 use dbus::blocking::{Connection, Proxy};
-use ini::configparser::ini::Ini;
 use std::collections::HashMap;
-use std::thread::sleep;
 use std::time::Duration;
 
-use crate::constants::WAYDROID_CONFIG;
-use crate::print::{msg_err_str, msg_sub};
-
 pub struct WaydroidContainer {
     conn: Connection,
     session: HashMap<String, String>,
@@ -27,12 +21,11 @@ impl WaydroidContainer {
     }
 
     fn get_proxy(&self) -> Proxy<'_, &Connection> {
-        let proxy = self.conn.with_proxy(
+        self.conn.with_proxy(
             "id.waydro.Container",
             "/ContainerManager",
             Duration::from_millis(5000),
-        );
-        proxy
+        )
     }
 
     pub fn get_session(&self) -> HashMap<String, String> {
@@ -109,39 +102,14 @@ impl WaydroidContainer {
 
         Ok(())
     }
-
-    pub fn restart_countdown(&mut self) -> Result<(), dbus::Error> {
-        let secs = 5;
-        for i in 0..=(secs - 1) {
-            msg_sub(&format!(
-                "Restarting Waydroid in {}",
-                (secs - i).to_string().blue().bold()
-            ));
-            if i == (secs - 1) {
-                self.stop(false)?;
-                self.start()?;
-            }
-            sleep(Duration::from_secs(1));
-        }
-        Ok(())
-    }
 }
 
 impl Clone for WaydroidContainer {
     fn clone(&self) -> Self {
-        let conn = Connection::new_system().expect(&msg_err_str("Failed to get D-Bus connection"));
+        let conn = Connection::new_system().expect("Failed to get D-Bus connection");
         Self {
-            conn: conn,
+            conn,
             session: self.session.clone(),
         }
     }
 }
-
-pub fn has_overlay() -> anyhow::Result<bool, Box<dyn std::error::Error>> {
-    let mut conf = Ini::new();
-    conf.load(WAYDROID_CONFIG)?;
-    Ok(conf
-        .get("waydroid", "mount_overlays")
-        .expect(&msg_err_str("Failed to read the config file."))
-        == "True")
-}
@@ -0,0 +1,230 @@
+use crate::constants::{
+    SYSTEM_IMAGE_MIN_SIZE, VENDOR_IMAGE_MIN_SIZE, WAYDROID_CONTAINER, WAYDROID_DIR, WSU_CONFIG,
+    WSU_HISTORY, WSU_STATE_DIR, WSU_TEMPLATES,
+};
+use crate::error::WsuError;
+use crate::report::{Reporter, Silent};
+use crate::utils::{command_exists, get_data_home, parse_size, root};
+use anyhow::{Ok, anyhow};
+use ini::configparser::ini::Ini;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Overrides given on the command line, they take precedence over env and config.
+#[derive(Default)]
+pub struct ContextArgs {
+    pub waydroid_dir: Option<String>,
+    pub lxc_path: Option<String>,
+    pub container: Option<String>,
+    pub data_dir: Option<String>,
+    pub state_dir: Option<String>,
+    /// Nothing is reported when not given.
+    pub reporter: Option<Arc<dyn Reporter>>,
+}
+
+/// How Magisk's init actions get into the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    /// Merged into the image's bootanim.rc.
+    Bootanim,
+    /// A separate, randomly named rc file under /system/etc/init.
+    Rc,
+}
+
+impl Hook {
+    fn parse(hook: &str) -> anyhow::Result<Self> {
+        match hook.to_ascii_lowercase().as_str() {
+            "bootanim" => Ok(Hook::Bootanim),
+            "rc" => Ok(Hook::Rc),
+            _ => Err(anyhow!(
+                "Invalid hook \"{}\", expected \"bootanim\" or \"rc\"",
+                hook
+            )),
+        }
+    }
+}
+
+/// Where the Waydroid install wsu manages lives.
+#[derive(Clone)]
+pub struct Context {
+    pub waydroid_dir: PathBuf,
+    pub config: PathBuf,
+    pub lxc_path: PathBuf,
+    pub container: String,
+    /// Sizes the images are grown to when they are patched directly.
+    pub system_image_size: u64,
+    pub vendor_image_size: u64,
+    pub hook: Hook,
+    /// User templates that take precedence over the built-in ones.
+    pub templates_dir: PathBuf,
+    /// Where the install record and backups are kept.
+    pub state_dir: PathBuf,
+    /// The log of commands that changed the install.
+    pub history: PathBuf,
+    data_dir: Option<PathBuf>,
+    reporter: Arc<dyn Reporter>,
+}
+
+impl Context {
+    /// Resolves each setting from flag, then `WSU_*` env, then the wsu config file.
+    pub fn load(args: ContextArgs) -> anyhow::Result<Self> {
+        let config_path = env::var("WSU_CONFIG").unwrap_or_else(|_| WSU_CONFIG.to_string());
+        let mut conf = Ini::new();
+        if Path::new(&config_path).exists() {
+            conf.load(&config_path)
+                .map_err(|e| anyhow!("Couldn't load {}: {}", config_path, e))?;
+        }
+        Self::resolve(args, |section, var, key| {
+            env::var(var).ok().or_else(|| conf.get(section, key))
+        })
+    }
+
+    /// Resolves each setting from flag, then `lookup`, which is given the config
+    /// section, the env var and the config key of the setting.
+    pub(crate) fn resolve(
+        args: ContextArgs,
+        lookup: impl Fn(&str, &str, &str) -> Option<String>,
+    ) -> anyhow::Result<Self> {
+        let setting_in = |section: &str, arg: Option<String>, var: &str, key: &str| {
+            arg.or_else(|| lookup(section, var, key))
+                .filter(|value| !value.is_empty())
+        };
+        let setting = |arg, var, key| setting_in("waydroid", arg, var, key);
+        let image_size =
+            |var: &str, key: &str, default: u64| match setting_in("images", None, var, key) {
+                Some(size) => parse_size(&size).map_err(|e| anyhow!("Invalid {}: {}", key, e)),
+                None => Ok(default),
+            };
+
+        let waydroid_dir = setting(args.waydroid_dir, "WSU_WAYDROID_DIR", "dir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(WAYDROID_DIR));
+        let config = setting(None, "WSU_WAYDROID_CONFIG", "config")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| waydroid_dir.join("waydroid.cfg"));
+        let lxc_path = setting(args.lxc_path, "WSU_LXC_PATH", "lxc_path")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| waydroid_dir.join("lxc"));
+        let container = setting(args.container, "WSU_CONTAINER", "container")
+            .unwrap_or_else(|| WAYDROID_CONTAINER.to_string());
+        let data_dir = setting(args.data_dir, "WSU_DATA_DIR", "data_dir").map(PathBuf::from);
+        let system_image_size = image_size(
+            "WSU_SYSTEM_IMAGE_SIZE",
+            "system_size",
+            SYSTEM_IMAGE_MIN_SIZE,
+        )?;
+        let vendor_image_size = image_size(
+            "WSU_VENDOR_IMAGE_SIZE",
+            "vendor_size",
+            VENDOR_IMAGE_MIN_SIZE,
+        )?;
+        let hook = match setting_in("install", None, "WSU_HOOK", "hook") {
+            Some(hook) => Hook::parse(&hook)?,
+            None => Hook::Bootanim,
+        };
+        let templates_dir = setting_in("install", None, "WSU_TEMPLATES", "templates")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(WSU_TEMPLATES));
+        let state_dir = setting_in("state", args.state_dir, "WSU_STATE_DIR", "dir")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(WSU_STATE_DIR));
+        let history = setting_in("state", None, "WSU_HISTORY", "history")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(WSU_HISTORY));
+
+        Ok(Self {
+            waydroid_dir,
+            config,
+            lxc_path,
+            container,
+            system_image_size,
+            vendor_image_size,
+            hook,
+            templates_dir,
+            state_dir,
+            history,
+            data_dir,
+            reporter: args.reporter.unwrap_or_else(|| Arc::new(Silent)),
+        })
+    }
+
+    /// Where progress, commands and errors are reported.
+    pub fn report(&self) -> &dyn Reporter {
+        self.reporter.as_ref()
+    }
+
+    pub fn rootfs(&self) -> PathBuf {
+        self.waydroid_dir.join("rootfs")
+    }
+
+    pub fn overlay(&self) -> PathBuf {
+        self.waydroid_dir.join("overlay")
+    }
+
+    pub fn overlay_rw(&self) -> PathBuf {
+        self.waydroid_dir.join("overlay_rw")
+    }
+
+    /// Android's /data on the host, the session's data dir unless overridden.
+    pub fn data_dir(&self) -> anyhow::Result<PathBuf> {
+        match &self.data_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(PathBuf::from(get_data_home()?).join("waydroid/data")),
+        }
+    }
+
+    /// Props Waydroid generates for the container, on top of the image's build.prop.
+    pub fn base_prop(&self) -> PathBuf {
+        self.waydroid_dir.join("waydroid_base.prop")
+    }
+
+    fn waydroid_config(&self, key: &str) -> anyhow::Result<Option<String>> {
+        self.config_value("waydroid", key)
+    }
+
+    /// A prop set in the `[properties]` section of waydroid.cfg, which wins over every prop file.
+    pub fn waydroid_property(&self, key: &str) -> anyhow::Result<Option<String>> {
+        self.config_value("properties", key)
+    }
+
+    fn config_value(&self, section: &str, key: &str) -> anyhow::Result<Option<String>> {
+        let mut conf = Ini::new();
+        conf.load(self.config.to_string_lossy().as_ref())
+            .map_err(|e| anyhow!(e))?;
+        Ok(conf.get(section, key))
+    }
+
+    pub fn has_overlay(&self) -> anyhow::Result<bool> {
+        let overlay = self
+            .waydroid_config("mount_overlays")?
+            .ok_or_else(|| anyhow!("Couldn't reach the \"mount_overlays\" config."))?;
+        Ok(overlay == "True")
+    }
+
+    pub fn images_path(&self) -> anyhow::Result<PathBuf> {
+        let images = self
+            .waydroid_config("images_path")?
+            .ok_or_else(|| anyhow!("Couldn't get path for images!"))?;
+        Ok(PathBuf::from(images))
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.config.exists()
+    }
+
+    /// Fails unless Waydroid is installed and initialized and wsu runs as root,
+    /// which every command that touches the install needs.
+    pub fn preflight(&self) -> anyhow::Result<()> {
+        if !command_exists("waydroid") {
+            return Err(WsuError::WaydroidMissing.into());
+        }
+        if !self.is_initialized() {
+            return Err(WsuError::NotInitialized.into());
+        }
+        if !root() {
+            return Err(WsuError::NotRoot.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,507 @@
+use crate::constants::*;
+use crate::container::WaydroidContainer;
+use crate::context::Context;
+use crate::file_contexts::ContextsFile;
+use crate::initrc::RcFile;
+use crate::magisk_files::{find_magisk_rc, waydroid_su};
+use crate::record::{InstallRecord, image_fingerprints};
+use crate::selinux::{SELINUX_XATTR, audit_labels};
+use crate::templates::Templates;
+use crate::utils::*;
+use anyhow::Ok;
+use std::env::temp_dir;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::result::Result::Ok as OtherOk;
+use zip::read::ZipArchive;
+
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub hint: String,
+}
+
+impl Check {
+    fn new(name: &str, status: CheckStatus, detail: &str, hint: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            detail: detail.to_string(),
+            hint: hint.to_string(),
+        }
+    }
+
+    fn pass(name: &str, detail: &str) -> Self {
+        Self::new(name, CheckStatus::Pass, detail, "")
+    }
+
+    fn fail(name: &str, detail: &str, hint: &str) -> Self {
+        Self::new(name, CheckStatus::Fail, detail, hint)
+    }
+
+    fn skip(name: &str, detail: &str) -> Self {
+        Self::new(name, CheckStatus::Skip, detail, "")
+    }
+}
+
+const HINT_REINSTALL: &str = "Reinstall Magisk with 'sudo wsu remove' and 'sudo wsu install'";
+const HINT_SETUP: &str = "Run 'sudo wsu setup' while Waydroid is running";
+const HINT_START: &str = "Start the Waydroid session with 'waydroid session start'";
+const MIN_FREE_SPACE: u64 = 1073741824;
+
+pub fn run_doctor(ctx: &Context) -> anyhow::Result<Vec<Check>> {
+    let mut checks = Vec::new();
+
+    checks.push(if command_exists("waydroid") {
+        Check::pass("waydroid", "installed")
+    } else {
+        Check::fail("waydroid", "not found in PATH", "Install Waydroid")
+    });
+
+    if !ctx.is_initialized() {
+        checks.push(Check::fail(
+            "waydroid config",
+            &format!("{} is missing", ctx.config.to_string_lossy()),
+            "Run 'sudo waydroid init'",
+        ));
+        return Ok(checks);
+    }
+
+    let waydroid = WaydroidContainer::new();
+    checks.push(match &waydroid {
+        OtherOk(_) => Check::pass("D-Bus", "id.waydro.Container reachable"),
+        Err(e) => Check::fail(
+            "D-Bus",
+            &e.to_string(),
+            "Start the Waydroid container service with 'sudo systemctl start waydroid-container'",
+        ),
+    });
+
+    let lxc = ctx.lxc_path.join(&ctx.container).join("config");
+    checks.push(if !command_exists("lxc-attach") {
+        Check::fail("lxc", "lxc-attach not found", "Install lxc")
+    } else if !lxc.exists() {
+        Check::fail(
+            "lxc",
+            &format!("{} is missing", lxc.to_string_lossy()),
+            "Run 'sudo waydroid init'",
+        )
+    } else {
+        Check::pass("lxc", &lxc.to_string_lossy())
+    });
+
+    let has_overlay = match ctx.has_overlay() {
+        OtherOk(has_overlay) => {
+            checks.push(Check::pass(
+                "overlay",
+                if has_overlay {
+                    "mount_overlays enabled"
+                } else {
+                    "mount_overlays disabled, images are patched directly"
+                },
+            ));
+            has_overlay
+        }
+        Err(e) => {
+            checks.push(Check::fail(
+                "overlay",
+                &e.to_string(),
+                "Check mount_overlays in waydroid.cfg or run 'sudo waydroid init'",
+            ));
+            return Ok(checks);
+        }
+    };
+
+    for (name, image, min) in [
+        (
+            "system image",
+            get_systemimg_path(ctx)?,
+            ctx.system_image_size,
+        ),
+        (
+            "vendor image",
+            get_vendorimg_path(ctx)?,
+            ctx.vendor_image_size,
+        ),
+    ] {
+        checks.push(match get_image_size(image.clone()) {
+            OtherOk(size) if size < min && !has_overlay => Check::fail(
+                name,
+                &format!("{} MiB, needs at least {} MiB", size >> 20, min >> 20),
+                "Install resizes images automatically, rerun 'sudo wsu install'",
+            ),
+            OtherOk(size) => Check::pass(name, &format!("{} MiB", size >> 20)),
+            Err(e) => Check::fail(name, &e.to_string(), "Run 'sudo waydroid init'"),
+        });
+    }
+
+    for (name, path) in [
+        (
+            "free space (images)",
+            get_systemimg_path(ctx)?.with_file_name(""),
+        ),
+        ("free space (tmp)", temp_dir()),
+    ] {
+        checks.push(match free_space(&path) {
+            OtherOk(free) if free < MIN_FREE_SPACE => Check::new(
+                name,
+                CheckStatus::Warn,
+                &format!("{} MiB free on {}", free >> 20, path.to_string_lossy()),
+                "Free up some disk space",
+            ),
+            OtherOk(free) => Check::pass(name, &format!("{} MiB free", free >> 20)),
+            Err(e) => Check::fail(name, &e.to_string(), ""),
+        });
+    }
+
+    let Some(mut waydroid) = waydroid.ok() else {
+        checks.push(Check::skip("artifacts", "D-Bus unreachable"));
+        return Ok(checks);
+    };
+    let running = waydroid.is_container_running()?;
+
+    let mut mounted = false;
+    let rootfs = if running {
+        ctx.rootfs()
+    } else if has_overlay {
+        ctx.overlay()
+    } else {
+        create_tmpdir(ctx)?;
+        mount_system(ctx, waydroid.clone(), 0, true, true)?;
+        mounted = true;
+        temp_dir().join("waydroidsu/mnt")
+    };
+
+    let result = check_artifacts(ctx, &rootfs, &mut checks, running);
+    if mounted {
+        umount_system(ctx.report(), true)?;
+    }
+    result?;
+
+    Ok(checks)
+}
+
+fn check_artifacts(
+    ctx: &Context,
+    rootfs: &Path,
+    checks: &mut Vec<Check>,
+    running: bool,
+) -> anyhow::Result<()> {
+    let magisk_dir = rootfs.join(MAGISK_DIR);
+    let apk = magisk_dir.join("magisk.apk");
+    if !magisk_dir.exists() || !apk.exists() {
+        checks.push(Check::fail(
+            "magisk files",
+            &format!("{} is missing", MAGISK_DIR),
+            "Install Magisk with 'sudo wsu install'",
+        ));
+        return Ok(());
+    }
+
+    let mut archive = ZipArchive::new(BufReader::new(File::open(&apk)?))?;
+    let abi = match get_abi(rootfs, None).or_else(|_| get_abi(&ctx.rootfs(), None)) {
+        OtherOk(abi) => abi,
+        Err(e) => {
+            checks.push(Check::skip("magisk files", &format!("unknown ABI, {}", e)));
+            return Ok(());
+        }
+    };
+    let kitsune = archive
+        .by_name(&format!("lib/{}/libmagisk64.so", abi.abi))
+        .is_ok();
+    let sdk = match get_sdk(rootfs).or_else(|_| get_sdk(&ctx.rootfs())) {
+        OtherOk(sdk) => Some(sdk),
+        Err(e) => {
+            checks.push(Check::new(
+                "android version",
+                CheckStatus::Warn,
+                &e.to_string(),
+                "The files are compared with the templates for the newest Android version",
+            ));
+            None
+        }
+    };
+    let templates = match Templates::load(ctx, sdk, kitsune) {
+        OtherOk(templates) => templates,
+        Err(e) => {
+            checks.push(Check::fail(
+                "templates",
+                &e.to_string(),
+                &format!(
+                    "Fix or remove the override in {}",
+                    ctx.templates_dir.to_string_lossy()
+                ),
+            ));
+            return Ok(());
+        }
+    };
+    checks.push(compare_magisk_files(
+        &mut archive,
+        &magisk_dir,
+        &abi,
+        &templates,
+        kitsune,
+    ));
+
+    let template = &templates.magisk_rc;
+    checks.push(match find_magisk_rc(rootfs) {
+        OtherOk(Some(path)) => check_hook("init rc", &path, template),
+        _ => check_hook("bootanim.rc", &rootfs.join(BOOTANIM_RC_PATH), template),
+    });
+
+    if !kitsune {
+        for zygote in abi.zygote_rcs() {
+            let path = rootfs.join("system/etc/init/hw").join(zygote);
+            let content = fs::read_to_string(&path).unwrap_or_default();
+            checks.push(match RcFile::parse(&content) {
+                OtherOk(rc) if rc.service_has("zygote", ZYGOTE_RESTART_EXEC) => {
+                    Check::pass(zygote, "zygote restart injected")
+                }
+                OtherOk(_) => Check::fail(zygote, "zygote restart hook missing", HINT_REINSTALL),
+                Err(e) => Check::fail(zygote, &e.to_string(), HINT_REINSTALL),
+            });
+        }
+
+        let vendor_selinux = rootfs.join("vendor/etc/selinux");
+        let policy = fs::read(vendor_selinux.join("precompiled_sepolicy")).unwrap_or_default();
+        checks.push(if contains_bytes(&policy, b"magisk_file") {
+            Check::pass("precompiled_sepolicy", "contains Magisk rules")
+        } else {
+            Check::fail("precompiled_sepolicy", "not patched", HINT_REINSTALL)
+        });
+
+        let contexts =
+            fs::read_to_string(vendor_selinux.join("vendor_file_contexts")).unwrap_or_default();
+        checks.push(match ContextsFile::parse(&contexts) {
+            OtherOk(contexts) => {
+                let missing: Vec<&str> = MAGISK_FILE_CONTEXTS
+                    .iter()
+                    .filter(|entry| !contexts.contains(entry).unwrap_or(false))
+                    .copied()
+                    .collect();
+                if missing.is_empty() {
+                    Check::pass("vendor_file_contexts", "Magisk contexts present")
+                } else {
+                    Check::fail(
+                        "vendor_file_contexts",
+                        &format!("missing: {}", missing.join(", ")),
+                        HINT_REINSTALL,
+                    )
+                }
+            }
+            Err(e) => Check::fail("vendor_file_contexts", &e.to_string(), HINT_REINSTALL),
+        });
+    }
+
+    let labels = audit_labels(rootfs, &[rootfs, &ctx.rootfs()])?;
+    let wrong: Vec<&str> = labels
+        .iter()
+        .filter(|label| label.is_wrong())
+        .map(|label| label.path.as_str())
+        .collect();
+    checks.push(if wrong.is_empty() {
+        Check::pass(SELINUX_XATTR, "labels match")
+    } else {
+        Check::fail(
+            SELINUX_XATTR,
+            &format!("{} file(s) mislabeled, e.g. {}", wrong.len(), wrong[0]),
+            "Fix them with 'sudo wsu selinux relabel'",
+        )
+    });
+
+    let data_adb = ctx.data_dir().map(|data| data.join("adb/magisk")).ok();
+    checks.push(match data_adb {
+        Some(path) if path.exists() => Check::pass("data/adb/magisk", "present"),
+        Some(_) => Check::fail("data/adb/magisk", "missing", HINT_SETUP),
+        None => Check::skip("data/adb/magisk", "session isn't running"),
+    });
+
+    checks.push(
+        match (InstallRecord::load(ctx), image_fingerprints(rootfs)) {
+            (OtherOk(Some(record)), OtherOk(fingerprints)) if record.matches(&fingerprints) => {
+                Check::pass("fingerprint", "images match the installation")
+            }
+            (OtherOk(Some(record)), _) if !record.has_fingerprints() => Check::new(
+                "fingerprint",
+                CheckStatus::Warn,
+                "the installation recorded no fingerprints",
+                "Re-apply the patches with 'sudo wsu repair'",
+            ),
+            (OtherOk(Some(_)), OtherOk(_)) => Check::fail(
+                "fingerprint",
+                "images changed since Magisk was installed",
+                "Re-apply the patches with 'sudo wsu repair'",
+            ),
+            (OtherOk(None), _) => Check::skip("fingerprint", "no installation record"),
+            (Err(e), _) | (_, Err(e)) => {
+                Check::new("fingerprint", CheckStatus::Warn, &e.to_string(), "")
+            }
+        },
+    );
+
+    if !running {
+        checks.push(Check::skip("manager", "container isn't running"));
+        checks.push(Check::new(
+            "magiskd",
+            CheckStatus::Skip,
+            "container isn't running",
+            HINT_START,
+        ));
+        return Ok(());
+    }
+
+    let packages = waydroid_su(ctx, vec!["pm", "list", "packages"], true).unwrap_or_default();
+    checks.push(
+        if packages.contains("com.topjohnwu.magisk")
+            || packages.contains("io.github.huskydg.magisk")
+        {
+            Check::pass("manager", "installed")
+        } else {
+            Check::fail("manager", "not installed", HINT_SETUP)
+        },
+    );
+
+    checks.push(
+        if waydroid_su(ctx, vec!["pidof", "magiskd"], true).is_ok() {
+            Check::pass("magiskd", "running")
+        } else {
+            Check::fail(
+                "magiskd",
+                "not running",
+                "Restart Waydroid, if it persists reinstall Magisk",
+            )
+        },
+    );
+
+    Ok(())
+}
+
+fn compare_magisk_files(
+    archive: &mut ZipArchive<BufReader<File>>,
+    magisk_dir: &Path,
+    abi: &Abi,
+    templates: &Templates,
+    kitsune: bool,
+) -> Check {
+    let mut expected: Vec<(String, String)> = Vec::new();
+    for name in archive.file_names() {
+        let prefix = format!("lib/{}/lib", abi.abi);
+        if let Some(bin) = name
+            .strip_prefix(&prefix)
+            .and_then(|n| n.strip_suffix(".so"))
+        {
+            expected.push((name.to_string(), bin.to_string()));
+        } else if let Some(file) = name.strip_prefix("assets/chromeos/") {
+            expected.push((name.to_string(), format!("chromeos/{}", file)));
+        }
+    }
+    if let Some(abi32) = &abi.abi32 {
+        let lib32 = if kitsune {
+            "libmagisk32.so"
+        } else {
+            "libmagisk.so"
+        };
+        expected.push((format!("lib/{}/{}", abi32, lib32), "magisk32".to_string()));
+    }
+    expected.push(("assets/stub.apk".to_string(), "stub.apk".to_string()));
+    let mut scripts = vec!["boot_patch.sh", "util_functions.sh", "addon.d.sh"];
+    if !kitsune {
+        scripts.extend_from_slice(&["app_functions.sh", "module_installer.sh", "uninstaller.sh"]);
+    }
+    for script in scripts {
+        expected.push((format!("assets/{}", script), script.to_string()));
+    }
+
+    let mut mismatched = Vec::new();
+    for (entry, target) in expected {
+        let mut apk_data = Vec::new();
+        let read = archive
+            .by_name(&entry)
+            .map(|mut f| f.read_to_end(&mut apk_data).is_ok())
+            .unwrap_or(false);
+        if !read {
+            continue;
+        }
+        if fs::read(magisk_dir.join(&target)).ok().as_deref() != Some(apk_data.as_slice()) {
+            mismatched.push(target);
+        }
+    }
+    if !kitsune
+        && fs::read_to_string(magisk_dir.join("loadpolicy.sh"))
+            .ok()
+            .as_deref()
+            != Some(templates.loadpolicy.as_str())
+    {
+        mismatched.push("loadpolicy.sh".to_string());
+    }
+
+    if mismatched.is_empty() {
+        Check::pass("magisk files", "match magisk.apk")
+    } else {
+        Check::fail(
+            "magisk files",
+            &format!("differ from magisk.apk: {}", mismatched.join(", ")),
+            HINT_REINSTALL,
+        )
+    }
+}
+
+/// Checks the Magisk actions wsu added to the rc file at `path`.
+fn check_hook(name: &str, path: &Path, template: &str) -> Check {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    match RcFile::parse(&content) {
+        Err(e) => Check::fail(name, &e.to_string(), HINT_REINSTALL),
+        OtherOk(rc) if !rc.duplicate_services().is_empty() => Check::fail(
+            name,
+            &format!(
+                "service {} defined more than once",
+                rc.duplicate_services().join(", ")
+            ),
+            HINT_REINSTALL,
+        ),
+        OtherOk(rc) if !rc.is_patched() => {
+            Check::fail(name, "Magisk actions are missing", HINT_REINSTALL)
+        }
+        OtherOk(rc) if matches_template(rc.added().trim_start(), template) => {
+            Check::pass(name, "Magisk actions match template")
+        }
+        OtherOk(_) => Check::fail(name, "Magisk actions don't match template", HINT_REINSTALL),
+    }
+}
+
+/// Compares rc content with a template whose service names are randomized at install.
+pub fn matches_template(content: &str, template: &str) -> bool {
+    let content: Vec<&str> = content.lines().collect();
+    let template: Vec<&str> = template.lines().collect();
+    if content.len() != template.len() {
+        return false;
+    }
+    content.iter().zip(template.iter()).all(|(c, t)| {
+        if !t.contains("magisk_service_") {
+            return c == t;
+        }
+        let c_tokens: Vec<&str> = c.split_whitespace().collect();
+        let t_tokens: Vec<&str> = t.split_whitespace().collect();
+        c_tokens.len() == t_tokens.len()
+            && c_tokens.iter().zip(t_tokens.iter()).all(|(c, t)| {
+                if t.starts_with("magisk_service_") {
+                    c.len() == 15 && c.chars().all(|ch| ch.is_ascii_alphanumeric())
+                } else {
+                    c == t
+                }
+            })
+    })
+}
+
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
@@ -0,0 +1,98 @@
+use std::fmt;
+
+/// Failures scripts may want to tell apart, each with its own exit code.
+/// Anything else exits with 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WsuError {
+    InvalidInput(String),
+    NotRoot,
+    WaydroidMissing,
+    NotInitialized,
+    NotInstalled,
+    ContainerNotRunning,
+    SessionNotRunning,
+    /// A feature only the named Magisk flavor has.
+    Unsupported {
+        feature: String,
+        flavor: &'static str,
+    },
+    Network(String),
+    Mount(String),
+    Selinux(String),
+    /// Android didn't get as far as waited for in time.
+    Timeout(String),
+    /// `wsu doctor` found problems, with how many checks failed.
+    ChecksFailed(usize),
+}
+
+pub const EXIT_FAILURE: i32 = 1;
+
+impl WsuError {
+    pub fn code(&self) -> i32 {
+        match self {
+            WsuError::InvalidInput(_) => 2,
+            WsuError::NotRoot => 3,
+            WsuError::WaydroidMissing => 4,
+            WsuError::NotInitialized => 5,
+            WsuError::NotInstalled => 6,
+            WsuError::ContainerNotRunning => 7,
+            WsuError::SessionNotRunning => 8,
+            WsuError::Unsupported { .. } => 9,
+            WsuError::Network(_) => 10,
+            WsuError::Mount(_) => 11,
+            WsuError::Selinux(_) => 12,
+            WsuError::Timeout(_) => 13,
+            WsuError::ChecksFailed(_) => 14,
+        }
+    }
+
+    /// Stable identifier used in JSON output.
+    pub fn id(&self) -> &'static str {
+        match self {
+            WsuError::InvalidInput(_) => "invalid_input",
+            WsuError::NotRoot => "not_root",
+            WsuError::WaydroidMissing => "waydroid_missing",
+            WsuError::NotInitialized => "not_initialized",
+            WsuError::NotInstalled => "not_installed",
+            WsuError::ContainerNotRunning => "container_not_running",
+            WsuError::SessionNotRunning => "session_not_running",
+            WsuError::Unsupported { .. } => "unsupported",
+            WsuError::Network(_) => "network",
+            WsuError::Mount(_) => "mount",
+            WsuError::Selinux(_) => "selinux",
+            WsuError::Timeout(_) => "timeout",
+            WsuError::ChecksFailed(_) => "checks_failed",
+        }
+    }
+}
+
+impl fmt::Display for WsuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WsuError::InvalidInput(msg)
+            | WsuError::Network(msg)
+            | WsuError::Mount(msg)
+            | WsuError::Selinux(msg)
+            | WsuError::Timeout(msg) => write!(f, "{}", msg),
+            WsuError::NotRoot => write!(f, "Needs to be ran as sudo!"),
+            WsuError::WaydroidMissing => write!(f, "Waydroid is not installed on your system!"),
+            WsuError::NotInitialized => write!(f, "Your Waydroid is not initialized!"),
+            WsuError::NotInstalled => write!(f, "Magisk is not installed!"),
+            WsuError::ContainerNotRunning => write!(f, "Waydroid container isn't running!"),
+            WsuError::SessionNotRunning => write!(f, "Waydroid session isn't running!"),
+            WsuError::ChecksFailed(failed) => write!(f, "{} check(s) failed.", failed),
+            WsuError::Unsupported { feature, flavor } => {
+                write!(f, "{} - Is only available for {}", feature, flavor)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WsuError {}
+
+/// The identifier and exit code of `err`, from the first `WsuError` in its chain.
+pub fn classify(err: &anyhow::Error) -> (&'static str, i32) {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<WsuError>())
+        .map_or(("failed", EXIT_FAILURE), |e| (e.id(), e.code()))
+}
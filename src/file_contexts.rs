@@ -0,0 +1,300 @@
+use crate::initrc::{BEGIN_MARKER, END_MARKER};
+use anyhow::{Ok, anyhow};
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+
+/// Where an image keeps its file contexts, later files take precedence.
+const FILE_CONTEXTS: [&str; 2] = [
+    "system/etc/selinux/plat_file_contexts",
+    "vendor/etc/selinux/vendor_file_contexts",
+];
+
+/// A `path_regex [file_type] context` entry.
+#[derive(Debug, Clone)]
+struct ContextSpec {
+    pattern: String,
+    regex: Regex,
+    /// `-` for regular files, `d` for directories and so on, None matches any type.
+    file_type: Option<char>,
+    context: String,
+}
+
+impl ContextSpec {
+    /// Parses a line, None for blank lines and comments.
+    fn parse(line: &str) -> anyhow::Result<Option<Self>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (pattern, file_type, context) = match fields[..] {
+            [pattern, context] => (pattern, None, context),
+            [pattern, file_type, context] => {
+                let file_type = match file_type.as_bytes() {
+                    [b'-', t] if b"-dlcbsp".contains(t) => *t as char,
+                    _ => return Err(anyhow!("unknown file type {}", file_type)),
+                };
+                (pattern, Some(file_type), context)
+            }
+            _ => return Err(anyhow!("expected 2 or 3 fields")),
+        };
+        if context != "<<none>>" && context.split(':').count() < 4 {
+            return Err(anyhow!("invalid context {}", context));
+        }
+        let regex = Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| anyhow!(e))?;
+        Ok(Some(Self {
+            pattern: pattern.to_string(),
+            regex,
+            file_type,
+            context: context.to_string(),
+        }))
+    }
+
+    fn same_as(&self, other: &ContextSpec) -> bool {
+        self.pattern == other.pattern
+            && self.file_type == other.file_type
+            && self.context == other.context
+    }
+}
+
+/// The path to label mappings of file_contexts files.
+pub struct FileContexts {
+    specs: Vec<ContextSpec>,
+}
+
+impl FileContexts {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut specs = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if let Some(spec) =
+                ContextSpec::parse(line).map_err(|e| anyhow!("Line {}: {}", number + 1, e))?
+            {
+                specs.push(spec);
+            }
+        }
+        Ok(Self { specs })
+    }
+
+    /// Reads the file contexts of an image, taking each file from the first root that has it.
+    pub fn load(roots: &[&Path]) -> anyhow::Result<Self> {
+        let mut specs = Vec::new();
+        for name in FILE_CONTEXTS {
+            let Some(path) = roots
+                .iter()
+                .map(|root| root.join(name))
+                .find(|path| path.is_file())
+            else {
+                continue;
+            };
+            let parsed = Self::parse(&fs::read_to_string(&path)?)
+                .map_err(|e| anyhow!("{}: {}", path.to_string_lossy(), e))?;
+            specs.extend(parsed.specs);
+        }
+        Ok(Self { specs })
+    }
+
+    /// The context of `path` inside the image, the last matching entry wins.
+    pub fn lookup(&self, path: &str, file_type: char) -> Option<&str> {
+        self.specs
+            .iter()
+            .rev()
+            .find(|spec| spec.file_type.is_none_or(|t| t == file_type) && spec.regex.is_match(path))
+            .map(|spec| spec.context.as_str())
+    }
+
+    pub fn defines(&self, context: &str) -> bool {
+        self.specs.iter().any(|spec| spec.context == context)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    text: String,
+    spec: Option<ContextSpec>,
+    added: bool,
+}
+
+/// A file_contexts file that keeps every line as written, with the entries
+/// wsu added wrapped in markers.
+#[derive(Debug, Clone)]
+pub struct ContextsFile {
+    lines: Vec<Line>,
+}
+
+impl ContextsFile {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut lines = Vec::new();
+        let mut added = false;
+        for (number, text) in content.lines().enumerate() {
+            match text.trim() {
+                BEGIN_MARKER if added => {
+                    return Err(anyhow!("Line {}: nested {}", number + 1, BEGIN_MARKER));
+                }
+                BEGIN_MARKER => added = true,
+                END_MARKER if !added => {
+                    return Err(anyhow!("Line {}: stray {}", number + 1, END_MARKER));
+                }
+                END_MARKER => added = false,
+                _ => lines.push(Line {
+                    text: text.to_string(),
+                    spec: ContextSpec::parse(text)
+                        .map_err(|e| anyhow!("Line {}: {}", number + 1, e))?,
+                    added,
+                }),
+            }
+        }
+        if added {
+            return Err(anyhow!("{} is never closed", BEGIN_MARKER));
+        }
+        Ok(Self { lines })
+    }
+
+    /// Whether `entry` is present, added by wsu or not.
+    pub fn contains(&self, entry: &str) -> anyhow::Result<bool> {
+        let spec = parse_entry(entry)?;
+        Ok(self
+            .lines
+            .iter()
+            .any(|line| line.spec.as_ref().is_some_and(|s| s.same_as(&spec))))
+    }
+
+    /// Adds `entry` as owned by wsu, returns false when nothing was added.
+    /// The same entry without markers is left to the image, unless `adopt` says
+    /// it's a leftover of older wsu versions, which appended entries unmarked.
+    pub fn add(&mut self, entry: &str, adopt: bool) -> anyhow::Result<bool> {
+        let spec = parse_entry(entry)?;
+        let same = |line: &Line| line.spec.as_ref().is_some_and(|s| s.same_as(&spec));
+        if self
+            .lines
+            .iter()
+            .any(|line| same(line) && (line.added || !adopt))
+        {
+            return Ok(false);
+        }
+        self.lines.retain(|line| !same(line));
+        self.lines.push(Line {
+            text: entry.to_string(),
+            spec: Some(spec),
+            added: true,
+        });
+        Ok(true)
+    }
+
+    /// Drops every entry wsu added, returns false when there was nothing to remove.
+    pub fn remove_added(&mut self) -> bool {
+        let before = self.lines.len();
+        self.lines.retain(|line| !line.added);
+        self.lines.len() != before
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+        let mut in_block = false;
+        for line in &self.lines {
+            if line.added != in_block {
+                out.push_str(if line.added { BEGIN_MARKER } else { END_MARKER });
+                out.push('\n');
+                in_block = line.added;
+            }
+            out.push_str(&line.text);
+            out.push('\n');
+        }
+        if in_block {
+            out.push_str(END_MARKER);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn parse_entry(entry: &str) -> anyhow::Result<ContextSpec> {
+    ContextSpec::parse(entry)
+        .map_err(|e| anyhow!("Invalid file context '{}': {}", entry, e))?
+        .ok_or_else(|| anyhow!("Empty file context"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VENDOR: &str = "# vendor\n/vendor(/.*)?    u:object_r:vendor_file:s0\n/vendor/bin/sh  --  u:object_r:vendor_shell_exec:s0\n";
+    const MAGISK: &str = "/debug_ramdisk(/.*)?    u:object_r:magisk_file:s0";
+
+    #[test]
+    fn add_and_remove_added_round_trip() {
+        let mut contexts = ContextsFile::parse(VENDOR).unwrap();
+        assert!(contexts.add(MAGISK, false).unwrap());
+        assert!(!contexts.add(MAGISK, false).unwrap());
+        assert!(contexts.contains(MAGISK).unwrap());
+
+        let serialized = contexts.serialize();
+        assert_eq!(
+            serialized,
+            format!("{}{}\n{}\n{}\n", VENDOR, BEGIN_MARKER, MAGISK, END_MARKER)
+        );
+        let mut restored = ContextsFile::parse(&serialized).unwrap();
+        assert!(restored.remove_added());
+        assert_eq!(restored.serialize(), VENDOR);
+        assert!(!restored.remove_added());
+    }
+
+    #[test]
+    fn add_adopts_unmarked_entries_only_when_asked() {
+        let image = format!("{}{}\n", VENDOR, MAGISK);
+
+        let mut contexts = ContextsFile::parse(&image).unwrap();
+        assert!(!contexts.add(MAGISK, false).unwrap());
+        assert_eq!(contexts.serialize(), image);
+        assert!(!contexts.remove_added());
+
+        let mut contexts = ContextsFile::parse(&image).unwrap();
+        assert!(contexts.add(MAGISK, true).unwrap());
+        assert_eq!(
+            contexts.serialize(),
+            format!("{}{}\n{}\n{}\n", VENDOR, BEGIN_MARKER, MAGISK, END_MARKER)
+        );
+        assert!(contexts.remove_added());
+        assert_eq!(contexts.serialize(), VENDOR);
+    }
+
+    #[test]
+    fn contains_compares_fields_not_whitespace() {
+        let contexts = ContextsFile::parse(VENDOR).unwrap();
+        assert!(
+            contexts
+                .contains("/vendor(/.*)? u:object_r:vendor_file:s0")
+                .unwrap()
+        );
+        assert!(
+            !contexts
+                .contains("/vendor(/.*)? u:object_r:system_file:s0")
+                .unwrap()
+        );
+        assert!(contexts.contains("").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_entries() {
+        assert!(ContextsFile::parse("/a  -x  u:object_r:a:s0\n").is_err());
+        assert!(ContextsFile::parse("/a  u:object_r\n").is_err());
+        assert!(ContextsFile::parse("/a(  u:object_r:a:s0\n").is_err());
+        assert!(ContextsFile::parse(&format!("{}\n", END_MARKER)).is_err());
+        assert!(ContextsFile::parse(&format!("{}\n", BEGIN_MARKER)).is_err());
+    }
+
+    #[test]
+    fn lookup_prefers_later_entries_of_matching_type() {
+        let contexts = FileContexts::parse(VENDOR).unwrap();
+        assert_eq!(
+            contexts.lookup("/vendor/bin/sh", '-'),
+            Some("u:object_r:vendor_shell_exec:s0")
+        );
+        assert_eq!(
+            contexts.lookup("/vendor/bin/sh", 'd'),
+            Some("u:object_r:vendor_file:s0")
+        );
+        assert_eq!(contexts.lookup("/system/bin/sh", '-'), None);
+        assert!(contexts.defines("u:object_r:vendor_file:s0"));
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{Ok, anyhow};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// One command that changed the install, as kept in the history log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Unix time the command started at.
+    pub time: u64,
+    /// The user behind sudo, or the one running wsu.
+    pub user: String,
+    /// Subcommand path, such as "module install".
+    pub command: String,
+    /// Everything after the program name.
+    pub args: Vec<String>,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        command: &str,
+        args: Vec<String>,
+        started: SystemTime,
+        duration: Duration,
+        error: Option<String>,
+    ) -> Self {
+        let user = env::var("SUDO_USER")
+            .or_else(|_| env::var("USER"))
+            .unwrap_or_else(|_| "unknown".to_string());
+        Self {
+            time: started
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            user,
+            command: command.to_string(),
+            args,
+            outcome: if error.is_some() {
+                Outcome::Failure
+            } else {
+                Outcome::Success
+            },
+            error,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+
+    /// Whether the entry is of `command` or one of its subcommands.
+    pub fn is_command(&self, command: &str) -> bool {
+        let words = command.split_whitespace();
+        let mut own = self.command.split_whitespace();
+        words.into_iter().all(|word| own.next() == Some(word))
+    }
+}
+
+/// Appends `entry` to the history log, one line per entry.
+pub fn append(path: &Path, entry: &HistoryEntry) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o644)
+        .open(path)
+        .map_err(|e| anyhow!("Couldn't open {}: {}", path.to_string_lossy(), e))?;
+    // a single write keeps concurrent entries from interleaving
+    file.write_all((serde_json::to_string(entry)? + "\n").as_bytes())?;
+    Ok(())
+}
+
+/// Every entry of the history log, oldest first. Lines that don't parse,
+/// like one cut short by a crash, are skipped.
+pub fn read(path: &Path) -> anyhow::Result<Vec<HistoryEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Couldn't read {}: {}", path.to_string_lossy(), e))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
@@ -0,0 +1,173 @@
+use crate::backup::{BackupStore, image_scope};
+use crate::constants::*;
+use crate::context::Context;
+use crate::error::WsuError;
+use crate::install::{
+    MagiskPayload, PatchPlan, apk_is_kitsune, patch_rootfs, prepare_payload, unpatch_rootfs,
+};
+use crate::magisk_files::resolve_hook;
+use crate::selinux::patch_sepolicy_prepare_host;
+use crate::templates::Templates;
+use crate::utils::*;
+use anyhow::Ok;
+use std::env::temp_dir;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result::Result::Ok as OtherOk;
+
+fn image_tempdir() -> PathBuf {
+    temp_dir().join("waydroidsu-image")
+}
+
+/// Mounts standalone images, runs `f` on the mounted rootfs and always unmounts afterwards.
+fn with_images<F>(
+    ctx: &Context,
+    system: &str,
+    vendor: &str,
+    needed: u64,
+    f: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(&Path, &Path) -> anyhow::Result<()>,
+{
+    let tempdir = image_tempdir();
+    let mnt = tempdir.join("mnt");
+    if tempdir.exists() {
+        if is_mounted_at(&mnt.to_string_lossy())? {
+            return Err(WsuError::Mount(format!(
+                "{} is still mounted, unmount it first",
+                mnt.to_string_lossy()
+            ))
+            .into());
+        }
+        fs::remove_dir_all(&tempdir)?;
+    }
+    fs::create_dir_all(&tempdir)?;
+
+    let guard = mount_images(
+        ctx,
+        Path::new(system),
+        Path::new(vendor),
+        &mnt,
+        needed,
+        false,
+        false,
+    )?;
+    let result = f(&mnt, &tempdir);
+    // the guard only steps in when unmounting here doesn't get to run or fails
+    let unmounted = umount_images(ctx.report(), &mnt, false);
+    drop(guard);
+    // never clean up underneath an image that is still mounted
+    let cleaned = match is_mounted_at(&mnt.to_string_lossy()) {
+        OtherOk(false) => fs::remove_dir_all(&tempdir).map_err(anyhow::Error::from),
+        OtherOk(true) => Ok(()),
+        Err(e) => Err(e),
+    };
+    result.and(unmounted).and(cleaned)
+}
+
+pub fn patch_image(
+    ctx: &Context,
+    system: &str,
+    vendor: &str,
+    apk: &str,
+    abi: Option<&str>,
+) -> anyhow::Result<()> {
+    let apk = PathBuf::from(apk);
+    if !apk.is_file() {
+        return Err(WsuError::InvalidInput(
+            "Magisk apk doesn't exist or isn't a valid file.".to_string(),
+        )
+        .into());
+    }
+
+    ctx.report().begin("Patching images...");
+    let needed = apk_payload_size(&apk)?;
+    with_images(ctx, system, vendor, needed, |rootfs, tempdir| {
+        let abi = get_abi(rootfs, abi)?;
+        let magisk_tmp = tempdir.join("magisk");
+        let staged = tempdir.join("staged");
+        fs::create_dir_all(&magisk_tmp)?;
+        fs::create_dir_all(&staged)?;
+
+        let image_apk = tempdir.join("magisk.apk");
+        fs::copy(&apk, &image_apk)?;
+        ctx.report().step("Extracting Magisk");
+        unzip_file(&image_apk.to_string_lossy(), &magisk_tmp.to_string_lossy())?;
+        let kitsune = apk_is_kitsune(&image_apk, &abi.abi)?;
+        let payload = prepare_payload(magisk_tmp.clone(), image_apk, &abi, kitsune)?;
+
+        let mut backups = BackupStore::open(ctx, &image_scope(Path::new(system)))?;
+        if rootfs.join(MAGISK_DIR).exists() {
+            unpatch_rootfs(
+                ctx,
+                rootfs,
+                false,
+                PathBuf::new(),
+                PathBuf::new(),
+                &mut backups,
+            )?;
+        }
+        if !kitsune {
+            stage_sepolicy(ctx, rootfs, &magisk_tmp, &staged, &payload)?;
+        }
+        let mut plan = PatchPlan {
+            hook: resolve_hook(ctx.report(), ctx.hook, rootfs)?,
+            templates: Templates::load(ctx, Some(get_sdk(rootfs)?), kitsune)?,
+            backups,
+        };
+        patch_rootfs(
+            ctx.report(),
+            rootfs,
+            &payload,
+            &abi,
+            &staged,
+            false,
+            &mut plan,
+        )?;
+        Ok(())
+    })?;
+    ctx.report().finish("Images patched");
+    Ok(())
+}
+
+/// magiskpolicy is a static binary, the copy built for the host's ABI runs outside the container.
+fn stage_sepolicy(
+    ctx: &Context,
+    rootfs: &Path,
+    magisk_tmp: &Path,
+    staged: &Path,
+    payload: &MagiskPayload,
+) -> anyhow::Result<()> {
+    let host_policy = magisk_tmp
+        .join("lib")
+        .join(host_abi()?)
+        .join("libmagiskpolicy.so");
+    let magiskpolicy = if host_policy.exists() {
+        host_policy
+    } else {
+        payload.libs.join("libmagiskpolicy.so")
+    };
+    ctx.report().step("Extracting sepolicy");
+    patch_sepolicy_prepare_host(ctx.report(), rootfs, staged, magiskpolicy)
+}
+
+pub fn unpatch_image(ctx: &Context, system: &str, vendor: &str) -> anyhow::Result<()> {
+    ctx.report().begin("Unpatching images...");
+    with_images(ctx, system, vendor, 0, |rootfs, _| {
+        if !rootfs.join(MAGISK_DIR).exists() {
+            return Err(WsuError::NotInstalled.into());
+        }
+        let mut backups = BackupStore::open(ctx, &image_scope(Path::new(system)))?;
+        unpatch_rootfs(
+            ctx,
+            rootfs,
+            false,
+            PathBuf::new(),
+            PathBuf::new(),
+            &mut backups,
+        )
+    })?;
+    ctx.report().finish("Images unpatched");
+    Ok(())
+}
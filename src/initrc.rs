@@ -0,0 +1,375 @@
+use anyhow::{Ok, anyhow};
+
+/// Lines between these markers were added by wsu and get removed on uninstall.
+pub const BEGIN_MARKER: &str = "# >>> wsu";
+pub const END_MARKER: &str = "# <<< wsu";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SectionKind {
+    Service { name: String, args: Vec<String> },
+    Action { trigger: String },
+    Import { path: String },
+}
+
+/// A section of an init rc file, `start` is its header line and `end` is exclusive.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub kind: SectionKind,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    text: String,
+    added: bool,
+}
+
+/// An Android init rc file that keeps every line as written, so patching it
+/// doesn't touch vendor customizations.
+#[derive(Debug, Clone, Default)]
+pub struct RcFile {
+    lines: Vec<Line>,
+    sections: Vec<Section>,
+}
+
+impl RcFile {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut lines = Vec::new();
+        let mut added = false;
+        for (number, text) in content.lines().enumerate() {
+            match text.trim() {
+                BEGIN_MARKER if added => {
+                    return Err(anyhow!("Line {}: nested {}", number + 1, BEGIN_MARKER));
+                }
+                BEGIN_MARKER => added = true,
+                END_MARKER if !added => {
+                    return Err(anyhow!("Line {}: stray {}", number + 1, END_MARKER));
+                }
+                END_MARKER => added = false,
+                _ => lines.push(Line {
+                    text: text.to_string(),
+                    added,
+                }),
+            }
+        }
+        if added {
+            return Err(anyhow!("{} is never closed", BEGIN_MARKER));
+        }
+
+        let mut rc = Self {
+            lines,
+            sections: Vec::new(),
+        };
+        rc.index()?;
+        Ok(rc)
+    }
+
+    /// Rebuilds the section list, continuation lines belong to the line they continue.
+    fn index(&mut self) -> anyhow::Result<()> {
+        self.sections.clear();
+        let mut continued = false;
+        for i in 0..self.lines.len() {
+            let text = self.lines[i].text.trim();
+            let is_continuation = continued;
+            continued = text.ends_with('\\');
+            if is_continuation {
+                continue;
+            }
+            let Some(kind) = parse_header(text).map_err(|e| anyhow!("Line {}: {}", i + 1, e))?
+            else {
+                continue;
+            };
+            if let Some(last) = self.sections.last_mut() {
+                last.end = i;
+            }
+            self.sections.push(Section {
+                kind,
+                start: i,
+                end: self.lines.len(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn service_names(&self) -> Vec<&str> {
+        self.sections
+            .iter()
+            .filter_map(|section| match &section.kind {
+                SectionKind::Service { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether `service` has `option`, whatever the whitespace around and inside it.
+    pub fn service_has(&self, service: &str, option: &str) -> bool {
+        let option: Vec<&str> = option.split_whitespace().collect();
+        self.sections
+            .iter()
+            .filter(|section| {
+                matches!(&section.kind, SectionKind::Service { name, .. } if name == service)
+            })
+            .flat_map(|section| &self.lines[section.start + 1..section.end])
+            .any(|line| line.text.split_whitespace().eq(option.iter().copied()))
+    }
+
+    /// Service names defined more than once, which init refuses to start.
+    pub fn duplicate_services(&self) -> Vec<&str> {
+        let names = self.service_names();
+        let mut duplicates: Vec<&str> = names
+            .iter()
+            .enumerate()
+            .filter(|(i, name)| names[..*i].contains(name))
+            .map(|(_, name)| *name)
+            .collect();
+        duplicates.dedup();
+        duplicates
+    }
+
+    pub fn imports(&self) -> Vec<&str> {
+        self.sections
+            .iter()
+            .filter_map(|section| match &section.kind {
+                SectionKind::Import { path } => Some(path.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// True for files wsu wrote in full.
+    pub fn is_added_only(&self) -> bool {
+        !self.lines.is_empty() && self.lines.iter().all(|line| line.added)
+    }
+
+    pub fn is_patched(&self) -> bool {
+        self.lines.iter().any(|line| line.added)
+    }
+
+    /// Appends the sections of `other` as added by wsu.
+    pub fn merge(&mut self, other: &RcFile) -> anyhow::Result<()> {
+        let existing = self.service_names();
+        if let Some(name) = other
+            .service_names()
+            .into_iter()
+            .find(|name| existing.contains(name))
+        {
+            return Err(anyhow!("Service {} is already defined", name));
+        }
+
+        if self.lines.last().is_some_and(|line| !line.text.is_empty()) {
+            self.lines.push(Line {
+                text: String::new(),
+                added: true,
+            });
+        }
+        let body = other.lines.iter().skip_while(|line| line.text.is_empty());
+        self.lines.extend(body.map(|line| Line {
+            text: line.text.clone(),
+            added: true,
+        }));
+        while self
+            .lines
+            .last()
+            .is_some_and(|line| line.added && line.text.is_empty())
+        {
+            self.lines.pop();
+        }
+        self.index()
+    }
+
+    /// Adds `option` right below the header of `service`, indented like its other options.
+    pub fn add_to_service(&mut self, service: &str, option: &str) -> anyhow::Result<()> {
+        let section = self
+            .sections
+            .iter()
+            .find(|section| {
+                matches!(&section.kind, SectionKind::Service { name, .. } if name == service)
+            })
+            .ok_or_else(|| anyhow!("No service {} defined", service))?;
+        let indent = self.lines[section.start + 1..section.end]
+            .iter()
+            .find(|line| !line.text.trim().is_empty())
+            .map(|line| indent_of(&line.text).to_string())
+            .unwrap_or_else(|| "    ".to_string());
+
+        self.lines.insert(
+            section.start + 1,
+            Line {
+                text: format!("{}{}", indent, option.trim()),
+                added: true,
+            },
+        );
+        self.index()
+    }
+
+    /// Drops every line wsu added, returns false when there was nothing to remove.
+    pub fn remove_added(&mut self) -> anyhow::Result<bool> {
+        let before = self.lines.len();
+        self.lines.retain(|line| !line.added);
+        self.index()?;
+        Ok(self.lines.len() != before)
+    }
+
+    /// The lines wsu added, without markers.
+    pub fn added(&self) -> String {
+        self.lines
+            .iter()
+            .filter(|line| line.added)
+            .map(|line| line.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+        // markers are indented like the first line of the block they wrap
+        let mut block: Option<&str> = None;
+        for line in &self.lines {
+            match (block, line.added) {
+                (None, true) => {
+                    let indent = indent_of(&line.text);
+                    out.push_str(&format!("{}{}\n", indent, BEGIN_MARKER));
+                    block = Some(indent);
+                }
+                (Some(indent), false) => {
+                    out.push_str(&format!("{}{}\n", indent, END_MARKER));
+                    block = None;
+                }
+                _ => {}
+            }
+            out.push_str(&line.text);
+            out.push('\n');
+        }
+        if let Some(indent) = block {
+            out.push_str(&format!("{}{}\n", indent, END_MARKER));
+        }
+        out
+    }
+}
+
+fn indent_of(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+fn parse_header(line: &str) -> anyhow::Result<Option<SectionKind>> {
+    let mut tokens = line.split_whitespace();
+    let kind = match tokens.next() {
+        Some("service") => {
+            let name = tokens
+                .next()
+                .ok_or_else(|| anyhow!("service without a name"))?;
+            let args: Vec<String> = tokens.map(str::to_string).collect();
+            if args.is_empty() {
+                return Err(anyhow!("service {} has no executable", name));
+            }
+            SectionKind::Service {
+                name: name.to_string(),
+                args,
+            }
+        }
+        Some("on") => {
+            let trigger = tokens.collect::<Vec<_>>().join(" ");
+            if trigger.is_empty() {
+                return Err(anyhow!("action without a trigger"));
+            }
+            SectionKind::Action { trigger }
+        }
+        Some("import") => SectionKind::Import {
+            path: tokens
+                .next()
+                .ok_or_else(|| anyhow!("import without a path"))?
+                .to_string(),
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZYGOTE: &str = "service zygote /system/bin/app_process64 -Xzygote /system/bin --zygote\n\tclass main\n\tpriority -20\n\tuser root\n\tonrestart restart audioserver\n";
+
+    #[test]
+    fn serialize_keeps_unpatched_files_as_written() {
+        let content = "import /init.${ro.hardware}.rc\n\non boot\n    chmod 0755 /data \\\n        /cache\n\n# comment\n";
+        assert_eq!(RcFile::parse(content).unwrap().serialize(), content);
+    }
+
+    #[test]
+    fn parse_finds_sections() {
+        let rc = RcFile::parse(
+            "import /a.rc\non early-init\n    start x\nservice x /bin/x\n    oneshot\n",
+        )
+        .unwrap();
+        assert_eq!(rc.service_names(), vec!["x"]);
+        assert_eq!(rc.imports(), vec!["/a.rc"]);
+        assert!(!rc.is_patched());
+    }
+
+    #[test]
+    fn parse_rejects_broken_markers() {
+        assert!(RcFile::parse(&format!("{}\n{}\n", BEGIN_MARKER, BEGIN_MARKER)).is_err());
+        assert!(RcFile::parse(&format!("{}\n", END_MARKER)).is_err());
+        assert!(RcFile::parse(&format!("{}\non boot\n", BEGIN_MARKER)).is_err());
+        assert!(RcFile::parse("service\n").is_err());
+        assert!(RcFile::parse("service x\n").is_err());
+    }
+
+    #[test]
+    fn merge_and_remove_added_round_trip() {
+        let original = "service bootanim /system/bin/bootanimation\n\tclass core\n";
+        let mut rc = RcFile::parse(original).unwrap();
+        rc.merge(&RcFile::parse("on post-fs-data\n    start magisk\n").unwrap())
+            .unwrap();
+
+        let patched = RcFile::parse(&rc.serialize()).unwrap();
+        assert!(patched.is_patched());
+        assert!(!patched.is_added_only());
+        assert_eq!(patched.added(), "\non post-fs-data\n    start magisk");
+
+        let mut restored = patched;
+        assert!(restored.remove_added().unwrap());
+        assert_eq!(restored.serialize(), original);
+        assert!(!restored.remove_added().unwrap());
+    }
+
+    #[test]
+    fn merge_refuses_defined_services() {
+        let mut rc = RcFile::parse("service x /bin/x\n").unwrap();
+        assert!(
+            rc.merge(&RcFile::parse("service x /bin/y\n").unwrap())
+                .is_err()
+        );
+        assert!(!rc.is_patched());
+    }
+
+    #[test]
+    fn add_to_service_follows_tab_indentation() {
+        let mut rc = RcFile::parse(ZYGOTE).unwrap();
+        rc.add_to_service("zygote", "    exec /bin/restart")
+            .unwrap();
+        let serialized = rc.serialize();
+        assert!(serialized.contains(&format!("\t{}\n\texec /bin/restart\n", BEGIN_MARKER)));
+
+        let parsed = RcFile::parse(&serialized).unwrap();
+        assert!(parsed.service_has("zygote", "exec /bin/restart"));
+        assert!(rc.add_to_service("missing", "oneshot").is_err());
+    }
+
+    #[test]
+    fn service_has_ignores_whitespace_and_other_sections() {
+        let rc = RcFile::parse(&format!("{}on boot\n    exec /bin/restart\n", ZYGOTE)).unwrap();
+        assert!(rc.service_has("zygote", "onrestart  restart audioserver"));
+        assert!(!rc.service_has("zygote", "exec /bin/restart"));
+        assert!(!rc.service_has("zygote", "onrestart restart"));
+    }
+
+    #[test]
+    fn duplicate_services_are_listed_once() {
+        let rc = RcFile::parse("service a /a\nservice b /b\nservice a /a\nservice a /a\n").unwrap();
+        assert_eq!(rc.duplicate_services(), vec!["a"]);
+    }
+}
@@ -1,221 +1,336 @@
+use crate::backup::{BackupStore, install_backups};
+use crate::boot::{BOOT_TIMEOUT, BootStage, restart_and_wait, wait_for};
 use crate::constants::*;
-use crate::container::{WaydroidContainer, has_overlay};
+use crate::container::WaydroidContainer;
+use crate::context::{Context, Hook};
+use crate::error::WsuError;
 use crate::magisk::Magisk;
 use crate::magisk_files::{
     check_uninstall_manager, clean_up, magisk_is_installed, magisk_is_set_up, patch_bootanim,
-    patch_init_zygote, restore_bootanim, restore_init_zygote, waydroid_su,
+    patch_init_zygote, patch_magisk_rc, remove_magisk_rc, resolve_hook, restore_bootanim,
+    restore_init_zygote, waydroid_su,
 };
-use crate::print::{msg_end, msg_err, msg_err_str, msg_main, msg_regular, msg_sub};
+use crate::record::{InstallRecord, running_fingerprints_or_unknown};
+use crate::report::Reporter;
 use crate::selinux::*;
+use crate::templates::Templates;
 use crate::utils::*;
 use anyhow::{Ok, anyhow};
-use colored::*;
 use std::env::temp_dir;
-use std::fs;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 use std::result::Result::Ok as OtherOk;
+use zip::read::ZipArchive;
 
-pub fn install(arch: &str, custom_apk: &str, update: bool, kitsune: bool) -> anyhow::Result<()> {
-    if !magisk_is_installed()? || update {
+pub use crate::magisk_files::get_status;
+pub use crate::record::images_changed;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum InstallMode {
+    Install,
+    Update,
+    Repair,
+}
+
+pub fn install(
+    ctx: &Context,
+    abi: &Abi,
+    custom_apk: &str,
+    mode: InstallMode,
+    kitsune: bool,
+) -> anyhow::Result<()> {
+    let update = mode == InstallMode::Update;
+    if !magisk_is_installed(ctx)? || mode != InstallMode::Install {
         let mut waydroid = WaydroidContainer::new()?;
-        let has_overlay = has_overlay().expect(&msg_err_str(
-            "Couldn't reach the \"mount_overlays\" config.",
-        ));
+        let has_overlay = ctx.has_overlay()?;
 
         if !waydroid.is_container_running()? {
-            msg_err("Waydroid container isn't running!");
-            return Ok(());
+            return Err(WsuError::ContainerNotRunning.into());
         }
 
-        if !has_overlay {
-            print!(
-                "[{}] {} {} ",
-                "WSU".blue().bold(),
-                "Your setup has \"mount_overlays\" disabled, do you wish to modify system and vendor images?"
-                    .bold(),
-                "[Y/n]".blue().bold()
-            );
-            io::stdout().flush()?; // Ensure the prompt is displayed immediately
-
-            let mut input = String::new();
-            io::stdin().read_line(&mut input)?; // Read user input
-            println!();
-
-            let input = input.trim(); // Remove trailing newline and spaces
-            if !input.eq_ignore_ascii_case("y") {
-                return Ok(());
-            }
+        if !has_overlay
+            && !ctx.report().confirm(
+                "Your setup has \"mount_overlays\" disabled, do you wish to modify system and vendor images?",
+            )
+        {
+            return Err(WsuError::InvalidInput(
+                "Modifying the system and vendor images wasn't confirmed, pass --yes to allow it".to_string(),
+            )
+            .into());
         }
 
-        if !update {
-            msg_main("Installing Magisk...");
+        if mode == InstallMode::Install {
+            ctx.report().begin("Installing Magisk...");
         }
 
-        let waydroid_data = PathBuf::from(get_data_home()?).join("waydroid/data");
+        let waydroid_data = ctx.data_dir()?;
+        let fingerprints = running_fingerprints_or_unknown(ctx);
+        let mut plan = PatchPlan {
+            hook: resolve_hook(ctx.report(), ctx.hook, &ctx.rootfs())?,
+            templates: Templates::load(ctx, Some(get_sdk(&ctx.rootfs())?), kitsune)?,
+            backups: install_backups(ctx)?,
+        };
 
         let tempdir = temp_dir().join("waydroidsu");
         let magisk_tmp = tempdir.join("magisk");
-        create_tmpdir()?;
+        create_tmpdir(ctx)?;
 
-        let apk = resolve_apk(custom_apk, kitsune, tempdir.clone())?;
+        let apk = resolve_apk(ctx.report(), custom_apk, kitsune, tempdir.clone())?;
 
         if !magisk_tmp.exists() {
             fs::create_dir(magisk_tmp.clone())?;
         }
-        msg_sub("Extracting Magisk");
+        ctx.report().step("Extracting Magisk");
         unzip_file(&apk.to_string_lossy(), &magisk_tmp.to_string_lossy())?;
-        let (libs, libs32, assets) = prepare_libs(magisk_tmp.clone(), arch, kitsune)?;
+        let payload = prepare_payload(magisk_tmp.clone(), apk, abi, kitsune)?;
 
         let rootfs = if has_overlay {
-            PathBuf::from(WAYDROID_DIR).join("overlay")
+            ctx.overlay()
         } else {
             tempdir.join("mnt")
         };
-        let overlay_rw = PathBuf::from(WAYDROID_DIR).join("overlay_rw/system");
+        let overlay_rw = ctx.overlay_rw().join("system");
         let magisk_dir = rootfs.join(MAGISK_DIR);
-        let bootanim_rc_path = rootfs.join(BOOTANIM_RC_PATH);
-        let bootanim_rc_gz_path = rootfs.join(BOOTANIM_RC_GZ_PATH);
 
         if !kitsune {
-            patch_sepolicy_prepare(waydroid_data.clone(), libs.join("libmagiskpolicy.so"))?;
+            patch_sepolicy_prepare(
+                ctx,
+                waydroid_data.clone(),
+                payload.libs.join("libmagiskpolicy.so"),
+            )?;
             waydroid_su(
+                ctx,
                 vec!["cp", "/system/etc/init/hw/init.zygote*", "/data/local/tmp/"],
                 true,
             )?;
         }
+        // the overlay starts out without bootanim.rc, patch the image's one
+        let lower_bootanim = ctx.rootfs().join(BOOTANIM_RC_PATH);
+        if has_overlay && plan.hook == Hook::Bootanim && lower_bootanim.exists() {
+            fs::copy(&lower_bootanim, waydroid_data.join("local/tmp/bootanim.rc"))?;
+        }
 
-        msg_sub("Stopping Waydroid session");
+        ctx.report().step("Stopping Waydroid session");
         waydroid.stop(true)?;
 
         if !has_overlay && !is_mounted_at(&tempdir.join("mnt").to_string_lossy())? {
-            mount_system(waydroid.clone(), false)?;
+            mount_system(ctx, waydroid.clone(), payload.size()?, false, false)?;
         }
 
         clean_up(
+            ctx,
             rootfs.clone(),
             has_overlay,
             overlay_rw,
-            waydroid_data.clone(),
+            if mode == InstallMode::Repair {
+                PathBuf::new()
+            } else {
+                waydroid_data.clone()
+            },
         )?;
 
-        create_dir_check(magisk_dir.clone(), true)?;
-        create_dir_check(rootfs.join("system/addon.d"), has_overlay)?;
-        create_dir_check(rootfs.join(MAGISKTMP), true)?;
-
-        msg_sub("Copying scripts and binaries");
-        for entry in fs::read_dir(libs)? {
-            let path = entry?.path();
+        patch_rootfs(
+            ctx.report(),
+            &rootfs,
+            &payload,
+            abi,
+            &waydroid_data.join("local/tmp"),
+            has_overlay,
+            &mut plan,
+        )?;
 
-            let Some(file_name) = path.file_name().and_then(|s| s.to_str()) else {
-                continue;
-            };
+        ctx.report().step("Finishing installation");
 
-            if file_name.starts_with("lib") && file_name.ends_with(".so") {
-                let target_name = file_name.trim_start_matches("lib").trim_end_matches(".so");
-                fs::copy(&path, magisk_dir.join(target_name))?;
+        if update {
+            let data_adb = waydroid_data.join("adb");
+            let magisk_path = data_adb.join("magisk");
+            if magisk_path.exists() {
+                fs::remove_dir_all(&magisk_path)?;
             }
+            cp_dir(ctx.report(), magisk_dir, data_adb)?;
         }
 
-        let lib_name = if !kitsune {
-            "libmagisk.so"
-        } else {
-            "libmagisk32.so"
-        };
-        fs::copy(libs32.join(lib_name), magisk_dir.join("magisk32"))?;
+        if !has_overlay {
+            umount_system(ctx.report(), false)?;
+        }
 
-        let mut required_files = vec!["boot_patch.sh", "util_functions.sh", "addon.d.sh"];
+        fs::remove_dir_all(tempdir)?;
 
-        if !kitsune {
-            required_files.extend_from_slice(&[
-                "app_functions.sh",
-                "module_installer.sh",
-                "uninstaller.sh",
-            ]);
+        InstallRecord {
+            system_fingerprint: fingerprints.0,
+            vendor_fingerprint: fingerprints.1,
+            kitsune,
         }
+        .save(ctx)?;
 
-        for entry in fs::read_dir(&assets)? {
-            let path = entry?.path();
-            let Some(file_name) = path.file_name().and_then(|f| f.to_str()) else {
-                continue;
-            };
-
-            if required_files.contains(&file_name) {
-                fs::copy(&path, magisk_dir.join(file_name))?;
+        let done = if mode == InstallMode::Repair {
+            "Repair completed"
+        } else {
+            "Installation completed"
+        };
+        let mut booted = false;
+        if !waydroid.get_session().is_empty() && !update {
+            ctx.report().step("Starting Waydroid");
+            if let Err(e) = waydroid.start() {
+                ctx.report()
+                    .warn(&format!("Couldn't start Waydroid container.\n{}", e));
+                ctx.report()
+                    .finish(&format!("{}, start Waydroid manually", done));
+            } else if let Err(e) = wait_for(ctx, BootStage::Magiskd, BOOT_TIMEOUT) {
+                ctx.report().warn(&e.to_string());
+                ctx.report()
+                    .finish(&format!("{}, but Waydroid didn\'t finish booting", done));
+            } else {
+                ctx.report().finish(done);
+                booted = true;
             }
+        } else if !update {
+            ctx.report()
+                .finish(&format!("{}, start Waydroid manually", done));
         }
+        if mode == InstallMode::Install {
+            ctx.report().info(if booted {
+                "Run \'sudo wsu setup\' or install the manager."
+            } else {
+                "Run \'sudo wsu setup\' after Waydroid starts or install the manager."
+            });
+        }
+    } else {
+        ctx.report().info("Magisk is already installed!");
+    }
+    Ok(())
+}
 
-        chmod_755_recursive(magisk_dir.clone())?;
-        cp_dir(assets.join("chromeos"), magisk_dir.clone())?;
-        chmod_755_recursive(magisk_dir.join("chromeos"))?;
-        fs::copy(assets.join("stub.apk"), magisk_dir.join("stub.apk"))?;
+/// What to write into an image besides the Magisk files themselves.
+pub struct PatchPlan {
+    pub hook: Hook,
+    pub templates: Templates,
+    /// Where the files the patch replaces are backed up.
+    pub backups: BackupStore,
+}
 
-        let apk_name = apk
-            .file_name()
-            .ok_or_else(|| anyhow!(msg_err_str("Couldn't get a filename.")))?;
-        fs::copy(&apk, magisk_dir.join(apk_name))?;
+/// Copies Magisk into `rootfs` and patches sepolicy, zygote and the init hook.
+/// `staged` holds the sepolicy and zygote files extracted beforehand.
+pub fn patch_rootfs(
+    report: &dyn Reporter,
+    rootfs: &Path,
+    payload: &MagiskPayload,
+    abi: &Abi,
+    staged: &Path,
+    has_overlay: bool,
+    plan: &mut PatchPlan,
+) -> anyhow::Result<()> {
+    let magisk_dir = rootfs.join(MAGISK_DIR);
+    create_dir_check(magisk_dir.clone(), true)?;
+    create_dir_check(rootfs.join("system/addon.d"), has_overlay)?;
+    create_dir_check(rootfs.join(MAGISKTMP), true)?;
+
+    report.step("Copying scripts and binaries");
+    for entry in fs::read_dir(&payload.libs)? {
+        let path = entry?.path();
+
+        let Some(file_name) = path.file_name().and_then(|s| s.to_str()) else {
+            continue;
+        };
 
-        if !kitsune {
-            patch_sepolicy(magisk_dir.clone(), rootfs.clone(), waydroid_data.clone())?;
-            patch_init_zygote(rootfs.clone(), waydroid_data.clone())?;
-            create_dir_check(rootfs.join("system/etc/init"), false)?;
+        if file_name.starts_with("lib") && file_name.ends_with(".so") {
+            let target_name = file_name.trim_start_matches("lib").trim_end_matches(".so");
+            fs::copy(&path, magisk_dir.join(target_name))?;
         }
+    }
 
-        patch_bootanim(bootanim_rc_path, bootanim_rc_gz_path, has_overlay, kitsune)?;
+    if let Some(libs32) = &payload.libs32 {
+        let lib_name = if !payload.kitsune {
+            "libmagisk.so"
+        } else {
+            "libmagisk32.so"
+        };
+        fs::copy(libs32.join(lib_name), magisk_dir.join("magisk32"))?;
+    }
 
-        msg_sub("Finishing installation");
+    let mut required_files = vec!["boot_patch.sh", "util_functions.sh", "addon.d.sh"];
 
-        if update {
-            let data_adb = waydroid_data.join("adb");
-            let magisk_path = data_adb.join("magisk");
-            if magisk_path.exists() {
-                fs::remove_dir_all(&magisk_path)?;
-            }
-            cp_dir(magisk_dir, data_adb)?;
-        }
+    if !payload.kitsune {
+        required_files.extend_from_slice(&[
+            "app_functions.sh",
+            "module_installer.sh",
+            "uninstaller.sh",
+        ]);
+    }
 
-        if !has_overlay {
-            umount_system(false)?;
+    for entry in fs::read_dir(&payload.assets)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+
+        if required_files.contains(&file_name) {
+            fs::copy(&path, magisk_dir.join(file_name))?;
         }
+    }
 
-        fs::remove_dir_all(tempdir)?;
+    chmod_755_recursive(magisk_dir.clone())?;
+    cp_dir(report, payload.assets.join("chromeos"), magisk_dir.clone())?;
+    chmod_755_recursive(magisk_dir.join("chromeos"))?;
+    fs::copy(payload.assets.join("stub.apk"), magisk_dir.join("stub.apk"))?;
+
+    let apk_name = payload
+        .apk
+        .file_name()
+        .ok_or_else(|| anyhow!("Couldn't get a filename."))?;
+    fs::copy(&payload.apk, magisk_dir.join(apk_name))?;
+
+    if !payload.kitsune {
+        patch_sepolicy(
+            report,
+            magisk_dir.clone(),
+            rootfs.to_path_buf(),
+            staged,
+            &plan.templates.loadpolicy,
+            &mut plan.backups,
+        )?;
+        patch_init_zygote(
+            report,
+            rootfs.to_path_buf(),
+            staged,
+            abi.zygote_rcs(),
+            &mut plan.backups,
+        )?;
+        create_dir_check(rootfs.join("system/etc/init"), false)?;
+    }
 
-        if !waydroid.get_session().is_empty() && !update {
-            msg_sub("Starting Waydroid");
-            if let Err(e) = waydroid.start() {
-                msg_err(&format!("Couldn't start Waydroid container.\n{}", e));
-                msg_end("Installation completed, start Waydroid manually");
-            } else {
-                msg_end("Installation completed");
-            }
-            msg_regular("Run \'sudo wsu setup\' after Waydroid starts or install the manager.");
-        } else if !update {
-            msg_end("Installation completed, start Waydroid manually");
-            msg_regular("Run \'sudo wsu setup\' after Waydroid starts or install the manager.");
+    match plan.hook {
+        Hook::Rc => {
+            restore_bootanim(report, rootfs, has_overlay, &mut plan.backups)?;
+            patch_magisk_rc(report, rootfs, &plan.templates)?;
+        }
+        Hook::Bootanim => {
+            remove_magisk_rc(report, rootfs)?;
+            patch_bootanim(report, rootfs, staged, &plan.templates, &mut plan.backups)?;
         }
-    } else {
-        msg_regular("Magisk is already installed!");
     }
     Ok(())
 }
 
-pub fn update(arch: &str) -> anyhow::Result<()> {
+pub fn update(ctx: &Context, abi: &Abi) -> anyhow::Result<()> {
     let mut waydroid = WaydroidContainer::new()?;
     if !waydroid.is_container_running()? {
-        return Err(anyhow!("Waydroid container isn't running!"));
+        return Err(WsuError::ContainerNotRunning.into());
     }
-    if !magisk_is_installed()? || !magisk_is_set_up()? {
-        return Err(anyhow!("Magisk is not installed."));
+    if !magisk_is_installed(ctx)? || !magisk_is_set_up(ctx)? {
+        return Err(WsuError::NotInstalled.into());
     }
     let tempdir = temp_dir().join("waydroidsu");
-    create_tmpdir()?;
+    create_tmpdir(ctx)?;
 
-    let magisk = Magisk::new()?;
+    let magisk = Magisk::new(ctx)?;
     let local_version = magisk.version();
     let new = !local_version.contains("v27.2");
 
     let json_file = tempdir.join("canary.json");
     download_file(
+        ctx.report(),
         if new {
             "https://raw.githubusercontent.com/mistrmochov/MagiskForWaydroid/refs/heads/master/stable.json"
         } else {
@@ -232,135 +347,269 @@ pub fn update(arch: &str) -> anyhow::Result<()> {
                 "Local version of Magisk not recognized, please reinstall!"
             ));
         }
-        msg_main("Updating Magisk...");
-        remove(false, true)?;
-        if let Err(e) = install(arch, "", true, false) {
-            msg_err(&e.to_string());
-            remove(true, false)?;
-            msg_err("Update has failed, reinstall Magisk");
-            return Ok(());
+        ctx.report().begin("Updating Magisk...");
+        remove(ctx, false, true)?;
+        if let Err(e) = install(ctx, abi, "", InstallMode::Update, false) {
+            ctx.report().error(&e.to_string());
+            remove(ctx, true, false)?;
+            return Err(e.context("Update has failed, reinstall Magisk"));
         }
-        waydroid_su(vec!["chmod", "-R", "755", "/data/adb/magisk/"], false)?;
-        waydroid_su(vec!["chown", "-R", "0:0", "/data/adb/magisk"], false)?;
+        waydroid_su(ctx, vec!["chmod", "-R", "755", "/data/adb/magisk/"], false)?;
+        waydroid_su(ctx, vec!["chown", "-R", "0:0", "/data/adb/magisk"], false)?;
         waydroid_su(
+            ctx,
             vec!["cp", "/data/adb/magisk/magisk.apk", "/data/local/tmp"],
             false,
         )?;
-        check_uninstall_manager()?;
-        waydroid_su(vec!["pm", "install", "/data/local/tmp/magisk.apk"], false)?;
-        waydroid_su(vec!["rm", "/data/local/tmp/magisk.apk"], false)?;
+        check_uninstall_manager(ctx)?;
+        waydroid_su(
+            ctx,
+            vec!["pm", "install", "/data/local/tmp/magisk.apk"],
+            false,
+        )?;
+        waydroid_su(ctx, vec!["rm", "/data/local/tmp/magisk.apk"], false)?;
 
-        msg_end("Update completed, restarting Waydroid.");
-        waydroid.restart_countdown()?;
+        restart_and_wait(ctx, &mut waydroid)?;
+        ctx.report().finish("Update completed");
     } else {
-        msg_regular("Magisk is up to date");
+        ctx.report().info("Magisk is up to date");
     }
 
     Ok(())
 }
 
-pub fn remove(recover: bool, update: bool) -> anyhow::Result<()> {
-    if !magisk_is_installed()? && !recover {
-        return Err(anyhow!("Magisk is not installed!"));
+pub fn remove(ctx: &Context, recover: bool, update: bool) -> anyhow::Result<()> {
+    let result = remove_magisk(ctx, recover, update);
+    // don't leave the images mounted when removing failed halfway
+    if result.is_err() && is_mounted_at(&temp_dir().join("waydroidsu/mnt").to_string_lossy())? {
+        umount_system(ctx.report(), false)?;
+    }
+    result
+}
+
+fn remove_magisk(ctx: &Context, recover: bool, update: bool) -> anyhow::Result<()> {
+    if !magisk_is_installed(ctx)? && !recover {
+        return Err(WsuError::NotInstalled.into());
     }
     if recover {
-        msg_end(&"Aborting...".red());
+        ctx.report().finish("Aborting...");
     } else if !update {
-        msg_main(&format!("Removing Magisk..."));
+        ctx.report().begin("Removing Magisk...");
     }
 
     let mut waydroid = WaydroidContainer::new()?;
+    // opened while the images below an overlay are still mounted
+    let mut backups = install_backups(ctx)?;
 
     let waydroid_data = if waydroid.is_session_running(true, true)? {
-        PathBuf::from(get_data_home()?).join("waydroid/data")
+        ctx.data_dir()?
     } else {
         PathBuf::new()
     };
     if waydroid.is_session_running(true, true)? && !update {
-        msg_sub("Stopping Waydroid");
+        ctx.report().step("Stopping Waydroid");
         waydroid.stop(true)?;
     }
-    let has_overlay = has_overlay().expect(&msg_err_str(
-        "Couldn't reach the \"mount_overlays\" config.",
-    ));
+    let has_overlay = ctx.has_overlay()?;
     let tempdir = temp_dir().join("waydroidsu");
-    create_tmpdir()?;
+    create_tmpdir(ctx)?;
 
-    let overlay_rw = PathBuf::from(WAYDROID_DIR).join("overlay_rw/system");
+    let overlay_rw = ctx.overlay_rw().join("system");
     let rootfs = if has_overlay {
-        PathBuf::from(WAYDROID_DIR).join("overlay")
+        ctx.overlay()
     } else {
         if !is_mounted_at(&tempdir.join("mnt").to_string_lossy())? {
-            if let OtherOk(mount) = mount_system(waydroid.clone(), false) {
+            if let OtherOk(mount) = mount_system(ctx, waydroid.clone(), 0, false, false) {
                 if !mount {
-                    return Err(anyhow!("Failed to mount system."));
+                    return Err(WsuError::Mount("Failed to mount system.".to_string()).into());
                 }
             } else {
-                return Err(anyhow!("Failed to mount system."));
+                return Err(WsuError::Mount("Failed to mount system.".to_string()).into());
             }
         }
         tempdir.join("mnt")
     };
-    let bootanim_rc_path = rootfs.join(BOOTANIM_RC_PATH);
-    let bootanim_rc_gz_path = rootfs.join(BOOTANIM_RC_GZ_PATH);
 
-    msg_sub("Removing files");
-    clean_up(rootfs.clone(), has_overlay, overlay_rw, waydroid_data)?;
-    restore_sepolicy(rootfs.clone(), has_overlay)?;
-    restore_init_zygote(rootfs, has_overlay)?;
-    restore_bootanim(bootanim_rc_path, bootanim_rc_gz_path, has_overlay)?;
+    unpatch_rootfs(
+        ctx,
+        &rootfs,
+        has_overlay,
+        overlay_rw,
+        waydroid_data,
+        &mut backups,
+    )?;
 
     if !has_overlay {
-        if let OtherOk(mount) = umount_system(false) {
+        if let OtherOk(mount) = umount_system(ctx.report(), false) {
             if !mount {
-                return Err(anyhow!("Failed to umount system."));
+                return Err(WsuError::Mount("Failed to umount system.".to_string()).into());
             }
         } else {
-            return Err(anyhow!("Failed to umount system."));
+            return Err(WsuError::Mount("Failed to umount system.".to_string()).into());
         }
     }
 
     if tempdir.exists() {
         fs::remove_dir_all(tempdir)?;
     }
+    if !update {
+        InstallRecord::delete(ctx)?;
+    }
 
     if !waydroid.get_session().is_empty() && !update {
-        msg_sub("Starting Waydroid");
+        ctx.report().step("Starting Waydroid");
         if let Err(e) = waydroid.start() {
-            msg_err(&format!("Couldn't start Waydroid container.\n{}", e));
-            msg_end("Removal completed.");
-            msg_regular("Start Waydroid manually.");
+            ctx.report()
+                .warn(&format!("Couldn't start Waydroid container.\n{}", e));
+            ctx.report().finish("Removal completed.");
+            ctx.report().info("Start Waydroid manually.");
         } else {
-            msg_end("Removal completed.");
+            ctx.report().finish("Removal completed.");
         }
     } else if !update {
-        msg_end("Removal completed.");
-        msg_regular("Start Waydroid manually.");
+        ctx.report().finish("Removal completed.");
+        ctx.report().info("Start Waydroid manually.");
+    }
+    Ok(())
+}
+
+/// Runs `f` on the overlay, or on the mounted images when overlays are disabled.
+/// A running session is stopped while the images are mounted and started again afterwards.
+pub fn with_rootfs<T>(
+    ctx: &Context,
+    quiet: bool,
+    f: impl FnOnce(&Path) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    on_rootfs(ctx, quiet, false, f)
+}
+
+/// Like [`with_rootfs`] for `f` that only reads, the images are mounted
+/// read-only and neither repaired nor resized.
+pub fn inspect_rootfs<T>(
+    ctx: &Context,
+    quiet: bool,
+    f: impl FnOnce(&Path) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    on_rootfs(ctx, quiet, true, f)
+}
+
+fn on_rootfs<T>(
+    ctx: &Context,
+    quiet: bool,
+    read_only: bool,
+    f: impl FnOnce(&Path) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    if ctx.has_overlay()? {
+        return f(&ctx.overlay());
+    }
+
+    let mut waydroid = WaydroidContainer::new()?;
+    let running = waydroid.is_session_running(true, true)?;
+    if running && !quiet {
+        ctx.report().step("Stopping Waydroid");
+    }
+    create_tmpdir(ctx)?;
+    mount_system(ctx, waydroid.clone(), 0, quiet, read_only)?;
+    let result = f(&temp_dir().join("waydroidsu/mnt"));
+    umount_system(ctx.report(), quiet)?;
+    let result = result?;
+
+    if running {
+        if !quiet {
+            ctx.report().step("Starting Waydroid");
+        }
+        waydroid.start()?;
+    }
+    Ok(result)
+}
+
+/// Puts a single backed up file back into the Waydroid image or overlay.
+pub fn restore_backup(ctx: &Context, path: &str) -> anyhow::Result<()> {
+    let mut backups = install_backups(ctx)?;
+    if backups.get(path).is_none() {
+        return Err(anyhow!("No backup of {} for {}", path, backups.scope()));
+    }
+    ctx.report().begin(&format!("Restoring {}...", path));
+    if !with_rootfs(ctx, false, |rootfs| backups.restore(rootfs, path))? {
+        return Err(anyhow!(
+            "The backup of {} was taken from an image that has been replaced since, dropped it",
+            path
+        ));
+    }
+
+    ctx.report().finish("Backup restored.");
+    if ctx.has_overlay()? {
+        ctx.report().info("Restart Waydroid to apply the change.");
+    }
+    Ok(())
+}
+
+/// Fixes the SELinux labels of the files wsu manages.
+pub fn relabel_selinux(ctx: &Context) -> anyhow::Result<()> {
+    ctx.report().begin("Relabeling Magisk files...");
+    let fixed = with_rootfs(ctx, false, |rootfs| {
+        relabel(&audit_labels(rootfs, &[rootfs, &ctx.rootfs()])?)
+    })?;
+
+    if fixed == 0 {
+        ctx.report().finish("All labels are correct.");
+    } else {
+        ctx.report()
+            .finish(&format!("Relabeled {} file(s).", fixed));
+        if ctx.has_overlay()? {
+            ctx.report().info("Restart Waydroid to apply the change.");
+        }
     }
     Ok(())
 }
 
-pub fn setup() -> anyhow::Result<()> {
+/// Removes Magisk files from `rootfs` and restores the backed up originals.
+pub fn unpatch_rootfs(
+    ctx: &Context,
+    rootfs: &Path,
+    has_overlay: bool,
+    overlay_rw: PathBuf,
+    waydroid_data: PathBuf,
+    backups: &mut BackupStore,
+) -> anyhow::Result<()> {
+    ctx.report().step("Removing files");
+    clean_up(
+        ctx,
+        rootfs.to_path_buf(),
+        has_overlay,
+        overlay_rw,
+        waydroid_data,
+    )?;
+    restore_sepolicy(ctx.report(), rootfs.to_path_buf(), has_overlay, backups)?;
+    restore_init_zygote(ctx.report(), rootfs.to_path_buf(), has_overlay, backups)?;
+    remove_magisk_rc(ctx.report(), rootfs)?;
+    restore_bootanim(ctx.report(), rootfs, has_overlay, backups)?;
+    Ok(())
+}
+
+pub fn setup(ctx: &Context) -> anyhow::Result<()> {
     let mut waydroid = WaydroidContainer::new()?;
     if !waydroid.is_container_running()? {
-        return Err(anyhow!("Waydroid container isn't running!"));
+        return Err(WsuError::ContainerNotRunning.into());
     }
 
-    if !magisk_is_installed()? {
-        return Err(anyhow!("Magisk ins't installed!"));
+    if !magisk_is_installed(ctx)? {
+        return Err(WsuError::NotInstalled.into());
     }
 
-    msg_main("Running additional setup...");
-    waydroid_su(vec!["rm", "-rf", "/data/adb/magisk"], false)?;
-    waydroid_su(vec!["mkdir", "-p", "/data/adb/magisk"], false)?;
-    waydroid_su(vec!["chmod", "700", "/data/adb"], false)?;
+    ctx.report().begin("Running additional setup...");
+    waydroid_su(ctx, vec!["rm", "-rf", "/data/adb/magisk"], false)?;
+    waydroid_su(ctx, vec!["mkdir", "-p", "/data/adb/magisk"], false)?;
+    waydroid_su(ctx, vec!["chmod", "700", "/data/adb"], false)?;
     waydroid_su(
+        ctx,
         vec!["cp", "-r", "/system/etc/init/magisk/*", "/data/adb/magisk"],
         false,
     )?;
-    waydroid_su(vec!["chmod", "-R", "755", "/data/adb/magisk/"], false)?;
-    waydroid_su(vec!["chown", "-R", "0:0", "/data/adb/magisk"], false)?;
+    waydroid_su(ctx, vec!["chmod", "-R", "755", "/data/adb/magisk/"], false)?;
+    waydroid_su(ctx, vec!["chown", "-R", "0:0", "/data/adb/magisk"], false)?;
     waydroid_su(
+        ctx,
         vec![
             "cp",
             "/system/etc/init/magisk/magisk.apk",
@@ -368,19 +617,92 @@ pub fn setup() -> anyhow::Result<()> {
         ],
         false,
     )?;
-    check_uninstall_manager()?;
-    waydroid_su(vec!["pm", "install", "/data/local/tmp/magisk.apk"], false)?;
-    waydroid_su(vec!["rm", "/data/local/tmp/magisk.apk"], false)?;
+    check_uninstall_manager(ctx)?;
+    waydroid_su(
+        ctx,
+        vec!["pm", "install", "/data/local/tmp/magisk.apk"],
+        false,
+    )?;
+    waydroid_su(ctx, vec!["rm", "/data/local/tmp/magisk.apk"], false)?;
 
-    waydroid.restart_countdown()?;
+    restart_and_wait(ctx, &mut waydroid)?;
+    ctx.report().finish("Setup completed");
     Ok(())
 }
 
-fn resolve_apk(custom_apk: &str, kitsune: bool, tempdir: PathBuf) -> anyhow::Result<PathBuf> {
+pub fn repair(ctx: &Context, abi: &Abi, force: bool) -> anyhow::Result<()> {
+    let mut waydroid = WaydroidContainer::new()?;
+    if !waydroid.is_container_running()? {
+        return Err(WsuError::ContainerNotRunning.into());
+    }
+    let record = InstallRecord::load(ctx)?;
+    if record.is_none() && !magisk_is_installed(ctx)? {
+        return Err(WsuError::NotInstalled.into());
+    }
+    let fingerprints = running_fingerprints_or_unknown(ctx);
+    if !force && record.as_ref().is_some_and(|r| r.matches(&fingerprints)) {
+        ctx.report()
+            .info("Magisk patches match the current Waydroid images");
+        return Ok(());
+    }
+
+    let installed_apk = [
+        ctx.rootfs().join(MAGISK_DIR).join("magisk.apk"),
+        ctx.data_dir()?.join("adb/magisk/magisk.apk"),
+    ]
+    .into_iter()
+    .find(|apk| apk.exists())
+    .ok_or_else(|| anyhow!("Couldn't find the installed Magisk apk, reinstall Magisk"))?;
+
+    let apk = temp_dir().join("wsu-repair.apk");
+    fs::copy(&installed_apk, &apk)?;
+    let kitsune = match &record {
+        Some(record) => record.kitsune,
+        None => apk_is_kitsune(&apk, &abi.abi)?,
+    };
+
+    ctx.report().begin("Repairing Magisk...");
+    if ctx.has_overlay()? {
+        let overlay = ctx.overlay();
+        let mut backups = install_backups(ctx)?;
+        restore_sepolicy(ctx.report(), overlay.clone(), true, &mut backups)?;
+        restore_init_zygote(ctx.report(), overlay.clone(), true, &mut backups)?;
+        remove_magisk_rc(ctx.report(), &overlay)?;
+        restore_bootanim(ctx.report(), &overlay, true, &mut backups)?;
+        ctx.report().step("Restarting Waydroid container");
+        waydroid.stop(false)?;
+        waydroid.start()?;
+    }
+
+    let result = install(
+        ctx,
+        abi,
+        &apk.to_string_lossy(),
+        InstallMode::Repair,
+        kitsune,
+    );
+    remove_check(apk)?;
+    result
+}
+
+pub fn apk_is_kitsune(apk: &Path, arch: &str) -> anyhow::Result<bool> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(apk)?))?;
+    Ok(archive
+        .by_name(&format!("lib/{}/libmagisk64.so", arch))
+        .is_ok())
+}
+
+fn resolve_apk(
+    report: &dyn Reporter,
+    custom_apk: &str,
+    kitsune: bool,
+    tempdir: PathBuf,
+) -> anyhow::Result<PathBuf> {
     let apk = tempdir.join("magisk.apk");
     if custom_apk.is_empty() {
         let json_file = tempdir.join("channel.json");
         download_file(
+            report,
             if !kitsune {
                 "https://raw.githubusercontent.com/mistrmochov/MagiskForWaydroid/refs/heads/master/stable.json"
             } else {
@@ -391,8 +713,8 @@ fn resolve_apk(custom_apk: &str, kitsune: bool, tempdir: PathBuf) -> anyhow::Res
         )?;
         let json_data = fs::read_to_string(json_file)?;
         let (version, link) = get_magisk_info(&json_data)?;
-        msg_sub(&format!("Downloading Magisk: {}", version.blue().bold()));
-        download_file(&link, &apk.to_string_lossy(), false)?;
+        report.step(&format!("Downloading Magisk: {}", version));
+        download_file(report, &link, &apk.to_string_lossy(), false)?;
     } else {
         let path = PathBuf::from(custom_apk);
         if !path.exists() || !path.is_file() {
@@ -405,20 +727,41 @@ fn resolve_apk(custom_apk: &str, kitsune: bool, tempdir: PathBuf) -> anyhow::Res
     Ok(apk)
 }
 
-fn prepare_libs(
+/// Extracted Magisk apk, with the libraries matching the target ABI.
+pub struct MagiskPayload {
+    pub apk: PathBuf,
+    pub libs: PathBuf,
+    pub libs32: Option<PathBuf>,
+    pub assets: PathBuf,
+    pub kitsune: bool,
+}
+
+impl MagiskPayload {
+    /// Upper bound of what patching copies into the system image.
+    pub fn size(&self) -> anyhow::Result<u64> {
+        let mut size = fs::metadata(&self.apk)?.len();
+        size += dir_size(&self.libs)? + dir_size(&self.assets)?;
+        if let Some(libs32) = &self.libs32 {
+            size += dir_size(libs32)?;
+        }
+        Ok(size)
+    }
+}
+
+pub fn prepare_payload(
     magisk_tmp: PathBuf,
-    arch: &str,
+    apk: PathBuf,
+    abi: &Abi,
     kitsune: bool,
-) -> anyhow::Result<(PathBuf, PathBuf, PathBuf)> {
-    let libs = magisk_tmp.join("lib").join(arch);
-    let libs32 = if arch == "x86_64" {
-        magisk_tmp.join("lib/x86")
-    } else {
-        magisk_tmp.join("lib/armeabi-v7a")
-    };
+) -> anyhow::Result<MagiskPayload> {
+    let libs = magisk_tmp.join("lib").join(&abi.abi);
+    let libs32 = abi
+        .abi32
+        .as_ref()
+        .map(|abi32| magisk_tmp.join("lib").join(abi32));
     let assets = magisk_tmp.join("assets");
 
-    if !libs.exists() || !libs32.exists() || !assets.exists() {
+    if !libs.exists() || libs32.as_ref().is_some_and(|l| !l.exists()) || !assets.exists() {
         return Err(anyhow!("Structure of apk file hasn't been recognized."));
     }
     if libs.join("libmagisk.so").exists() && kitsune {
@@ -431,5 +774,184 @@ fn prepare_libs(
         ));
     }
 
-    Ok((libs, libs32, assets))
+    Ok(MagiskPayload {
+        apk,
+        libs,
+        libs32,
+        assets,
+        kitsune,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextArgs;
+    use crate::magisk_files::find_magisk_rc;
+    use std::collections::BTreeMap;
+    use std::process;
+
+    /// A scratch directory removed again when dropped.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = temp_dir().join(format!("wsu-test-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, path: &str, content: &str) {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Every regular file below `dir` with its content, directories left
+    /// empty by an uninstall don't count.
+    fn snapshot(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(current).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let relative = path.strip_prefix(dir).unwrap();
+                    files.insert(
+                        relative.to_string_lossy().to_string(),
+                        fs::read(&path).unwrap(),
+                    );
+                }
+            }
+        }
+        files
+    }
+
+    /// A Kitsune apk unpacked the way `prepare_payload` expects it.
+    fn kitsune_payload(fixture: &Fixture) -> MagiskPayload {
+        for lib in ["libmagisk64.so", "libmagiskinit.so", "libmagiskpolicy.so"] {
+            fixture.write(&format!("apk/lib/x86_64/{}", lib), lib);
+        }
+        fixture.write("apk/lib/x86/libmagisk32.so", "libmagisk32.so");
+        for asset in [
+            "boot_patch.sh",
+            "util_functions.sh",
+            "addon.d.sh",
+            "stub.apk",
+        ] {
+            fixture.write(&format!("apk/assets/{}", asset), asset);
+        }
+        fixture.write("apk/assets/chromeos/futility", "futility");
+        fixture.write("magisk.apk", "apk");
+        MagiskPayload {
+            apk: fixture.0.join("magisk.apk"),
+            libs: fixture.0.join("apk/lib/x86_64"),
+            libs32: Some(fixture.0.join("apk/lib/x86")),
+            assets: fixture.0.join("apk/assets"),
+            kitsune: true,
+        }
+    }
+
+    fn round_trip(name: &str, hook: Hook) {
+        let fixture = Fixture::new(name);
+        // tab indented like the bootanim.rc LineageOS images ship
+        fixture.write(
+            "rootfs/system/etc/init/bootanim.rc",
+            "service bootanim /system/bin/bootanimation\n\tclass core animation\n\tuser graphics\n\tdisabled\n\toneshot\n",
+        );
+        fixture.write("rootfs/system/build.prop", "ro.build.version.sdk=30\n");
+        fixture.write(
+            "rootfs/vendor/etc/selinux/vendor_file_contexts",
+            "/vendor(/.*)?    u:object_r:vendor_file:s0\n",
+        );
+        let rootfs = fixture.0.join("rootfs");
+        let before = snapshot(&rootfs);
+
+        let dir = |name: &str| Some(fixture.0.join(name).to_string_lossy().to_string());
+        let ctx = Context::resolve(
+            ContextArgs {
+                waydroid_dir: dir("waydroid"),
+                data_dir: dir("data"),
+                state_dir: dir("state"),
+                ..Default::default()
+            },
+            |_, _, _| None,
+        )
+        .unwrap();
+        let abi = Abi {
+            abi: "x86_64".to_string(),
+            abi32: Some("x86".to_string()),
+            native_bridge: None,
+        };
+        let payload = kitsune_payload(&fixture);
+        let staged = fixture.0.join("staged");
+        fs::create_dir_all(&staged).unwrap();
+        let mut plan = PatchPlan {
+            hook,
+            templates: Templates {
+                bootanim_rc: BOOTANIM_RC.to_string(),
+                magisk_rc: KITSUNE_RC.to_string(),
+                loadpolicy: LOADPOLICY.to_string(),
+            },
+            backups: BackupStore::open(&ctx, "test").unwrap(),
+        };
+
+        patch_rootfs(
+            ctx.report(),
+            &rootfs,
+            &payload,
+            &abi,
+            &staged,
+            false,
+            &mut plan,
+        )
+        .unwrap();
+        assert!(rootfs.join(MAGISK_DIR).join("magisk64").exists());
+        assert!(rootfs.join(MAGISK_DIR).join("magisk32").exists());
+        assert!(rootfs.join(MAGISK_DIR).join("magisk.apk").exists());
+        let patched = snapshot(&rootfs);
+        match hook {
+            Hook::Bootanim => assert_ne!(
+                patched[BOOTANIM_RC_PATH], before[BOOTANIM_RC_PATH],
+                "bootanim.rc isn't patched"
+            ),
+            Hook::Rc => assert!(find_magisk_rc(&rootfs).unwrap().is_some()),
+        }
+        assert_eq!(
+            plan.backups.get(BOOTANIM_RC_PATH).is_some(),
+            hook == Hook::Bootanim
+        );
+
+        unpatch_rootfs(
+            &ctx,
+            &rootfs,
+            false,
+            PathBuf::new(),
+            PathBuf::new(),
+            &mut plan.backups,
+        )
+        .unwrap();
+        assert_eq!(snapshot(&rootfs), before);
+        assert!(plan.backups.all().is_empty());
+    }
+
+    #[test]
+    fn bootanim_hook_round_trip() {
+        round_trip("bootanim-hook", Hook::Bootanim);
+    }
+
+    #[test]
+    fn rc_hook_round_trip() {
+        round_trip("rc-hook", Hook::Rc);
+    }
 }
@@ -0,0 +1,48 @@
+//! Magisk installer and manager for Waydroid.
+//!
+//! Everything starts from a [`Context`](context::Context), which locates the
+//! Waydroid install and carries the [`Reporter`](report::Reporter) progress,
+//! warnings and prompts go to. The library never writes to the terminal on
+//! its own, without a reporter it works silently.
+//!
+//! ```no_run
+//! use wsu::context::{Context, ContextArgs};
+//! use wsu::install::{InstallMode, install};
+//!
+//! let ctx = Context::load(ContextArgs::default())?;
+//! ctx.preflight()?;
+//! let abi = wsu::get_running_abi(&ctx, None)?;
+//! install(&ctx, &abi, "", InstallMode::Install, false)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Failures scripts and frontends may want to tell apart are
+//! [`WsuError`](error::WsuError)s somewhere in the error's chain.
+
+pub mod backup;
+pub mod boot;
+pub mod container;
+pub mod context;
+pub mod doctor;
+pub mod error;
+pub mod history;
+pub mod image;
+pub mod install;
+pub mod magisk;
+pub mod packages;
+pub mod report;
+pub mod selinux;
+pub mod sepolicy;
+pub mod state;
+
+mod constants;
+mod file_contexts;
+mod initrc;
+mod loopdev;
+mod magisk_files;
+mod manifest;
+mod record;
+mod templates;
+mod utils;
+
+pub use crate::utils::{Abi, get_running_abi, root};
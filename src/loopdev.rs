@@ -0,0 +1,286 @@
+use crate::error::WsuError;
+use anyhow::{Ok, anyhow};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+const LOOP_SET_FD: libc::c_ulong = 0x4C00;
+const LOOP_CLR_FD: libc::c_ulong = 0x4C01;
+const LOOP_SET_STATUS64: libc::c_ulong = 0x4C04;
+const LOOP_CONFIGURE: libc::c_ulong = 0x4C0A;
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4C82;
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+const LO_NAME_SIZE: usize = 64;
+const LO_KEY_SIZE: usize = 32;
+// another process may grab the free device between GET_FREE and CONFIGURE
+const CONFIGURE_ATTEMPTS: usize = 8;
+
+const EXT4_MAGIC_OFFSET: u64 = 1080;
+const EXT4_MAGIC: [u8; 2] = [0x53, 0xEF];
+const EROFS_MAGIC_OFFSET: u64 = 1024;
+const EROFS_MAGIC: [u8; 4] = [0xE2, 0xE1, 0xF5, 0xE0];
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; LO_KEY_SIZE],
+    lo_init: [u64; 2],
+}
+
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+fn loop_info(image: &Path, read_only: bool) -> LoopInfo64 {
+    // SAFETY: LoopInfo64 is plain old data, all zeroes is a valid value
+    let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+    info.lo_flags = LO_FLAGS_AUTOCLEAR;
+    if read_only {
+        info.lo_flags |= LO_FLAGS_READ_ONLY;
+    }
+    let name = image.to_string_lossy();
+    let len = name.len().min(LO_NAME_SIZE - 1);
+    info.lo_file_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    info
+}
+
+/// A loop device bound to an image file, detached when dropped.
+///
+/// The device is set up with autoclear, so once a filesystem is mounted from it
+/// the kernel keeps it alive until the last unmount.
+pub struct LoopDevice {
+    pub path: PathBuf,
+    device: File,
+}
+
+impl LoopDevice {
+    /// Binds `image` to a free loop device. A `read_only` device is backed by a
+    /// read-only file, so nothing mounted from it can modify the image.
+    pub fn attach(image: &Path, read_only: bool) -> anyhow::Result<Self> {
+        let backing = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(image)
+            .map_err(|e| anyhow!("Couldn't open {}: {}", image.to_string_lossy(), e))?;
+        let control = File::open(LOOP_CONTROL)
+            .map_err(|e| anyhow!("Couldn't open {}: {}", LOOP_CONTROL, e))?;
+
+        let mut last_err = io::Error::from_raw_os_error(libc::EBUSY);
+        for _ in 0..CONFIGURE_ATTEMPTS {
+            // SAFETY: LOOP_CTL_GET_FREE takes no argument and returns a device number
+            let number = unsafe { libc::ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE) };
+            if number < 0 {
+                return Err(anyhow!(
+                    "Couldn't get a free loop device: {}",
+                    io::Error::last_os_error()
+                ));
+            }
+
+            let path = PathBuf::from(format!("/dev/loop{}", number));
+            let device = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .map_err(|e| anyhow!("Couldn't open {}: {}", path.to_string_lossy(), e))?;
+
+            match configure(&device, &backing, image, read_only) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => last_err = e,
+                Err(e) => {
+                    return Err(anyhow!(
+                        "Couldn't attach {} to {}: {}",
+                        image.to_string_lossy(),
+                        path.to_string_lossy(),
+                        e
+                    ));
+                }
+                _ => return Ok(Self { path, device }),
+            }
+        }
+        Err(anyhow!(
+            "Couldn't attach {} to a loop device: {}",
+            image.to_string_lossy(),
+            last_err
+        ))
+    }
+}
+
+fn configure(device: &File, backing: &File, image: &Path, read_only: bool) -> io::Result<()> {
+    let config = LoopConfig {
+        fd: backing.as_raw_fd() as u32,
+        block_size: 0,
+        info: loop_info(image, read_only),
+        reserved: [0; 8],
+    };
+    // SAFETY: config outlives the call and matches the kernel's struct loop_config
+    if unsafe { libc::ioctl(device.as_raw_fd(), LOOP_CONFIGURE, &config) } == 0 {
+        return io::Result::Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if !matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOTTY)) {
+        return Err(err);
+    }
+
+    // kernels before 5.8 have no LOOP_CONFIGURE
+    // SAFETY: LOOP_SET_FD takes the backing file descriptor by value
+    if unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_FD, backing.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let info = loop_info(image, read_only);
+    // SAFETY: info outlives the call and matches the kernel's struct loop_info64
+    if unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_STATUS64, &info) } != 0 {
+        let err = io::Error::last_os_error();
+        // SAFETY: LOOP_CLR_FD takes no argument
+        unsafe { libc::ioctl(device.as_raw_fd(), LOOP_CLR_FD) };
+        return Err(err);
+    }
+    io::Result::Ok(())
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        // With autoclear a mounted device only gets detached after the last unmount
+        // SAFETY: LOOP_CLR_FD takes no argument
+        unsafe { libc::ioctl(self.device.as_raw_fd(), LOOP_CLR_FD) };
+    }
+}
+
+/// A mounted filesystem, unmounted along with anything mounted below it when
+/// dropped unless kept.
+pub struct MountGuard {
+    pub target: PathBuf,
+    active: bool,
+}
+
+impl MountGuard {
+    /// Leaves the filesystem mounted after the guard goes away.
+    pub fn keep(mut self) {
+        self.active = false;
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        if self.active {
+            let _ = umount_recursive(&self.target);
+        }
+    }
+}
+
+fn detect_fstype(image: &Path) -> anyhow::Result<&'static str> {
+    let mut file = File::open(image)?;
+    let mut ext4 = [0u8; 2];
+    file.seek(SeekFrom::Start(EXT4_MAGIC_OFFSET))?;
+    file.read_exact(&mut ext4)?;
+    if ext4 == EXT4_MAGIC {
+        return Ok("ext4");
+    }
+    let mut erofs = [0u8; 4];
+    file.seek(SeekFrom::Start(EROFS_MAGIC_OFFSET))?;
+    file.read_exact(&mut erofs)?;
+    if erofs == EROFS_MAGIC {
+        return Ok("erofs");
+    }
+    Err(anyhow!(
+        "{} isn't an ext4 or erofs image",
+        image.to_string_lossy()
+    ))
+}
+
+/// Attaches `image` to a loop device and mounts it at `target`, read-write
+/// unless `read_only` is set or the filesystem is erofs.
+pub fn mount_loop(image: &Path, target: &Path, read_only: bool) -> anyhow::Result<MountGuard> {
+    let fstype = detect_fstype(image).map_err(|e| WsuError::Mount(e.to_string()))?;
+    let read_only = read_only || fstype == "erofs";
+    let flags = if read_only { libc::MS_RDONLY } else { 0 };
+    // a read-only ext4 mount would still replay the journal
+    let data = if read_only && fstype == "ext4" {
+        Some(CString::new("noload")?)
+    } else {
+        None
+    };
+    let device =
+        LoopDevice::attach(image, read_only).map_err(|e| WsuError::Mount(e.to_string()))?;
+
+    let source = CString::new(device.path.to_string_lossy().as_bytes())?;
+    let c_target = CString::new(target.to_string_lossy().as_bytes())?;
+    let c_fstype = CString::new(fstype)?;
+    // SAFETY: all pointers are valid NUL terminated strings for the duration of the call
+    let ret = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            c_target.as_ptr(),
+            c_fstype.as_ptr(),
+            flags,
+            data.as_ref()
+                .map_or(ptr::null(), |data| data.as_ptr() as *const libc::c_void),
+        )
+    };
+    if ret != 0 {
+        return Err(WsuError::Mount(format!(
+            "Couldn't mount {} at {}: {}",
+            image.to_string_lossy(),
+            target.to_string_lossy(),
+            io::Error::last_os_error()
+        ))
+        .into());
+    }
+
+    Ok(MountGuard {
+        target: target.to_path_buf(),
+        active: true,
+    })
+}
+
+pub fn umount(target: &Path) -> anyhow::Result<()> {
+    let c_target = CString::new(target.to_string_lossy().as_bytes())?;
+    // SAFETY: c_target is a valid NUL terminated string
+    if unsafe { libc::umount2(c_target.as_ptr(), 0) } != 0 {
+        return Err(WsuError::Mount(format!(
+            "Couldn't unmount {}: {}",
+            target.to_string_lossy(),
+            io::Error::last_os_error()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Mount points at or below `target`, deepest first.
+fn mounts_below(target: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts")?;
+    let mut found: Vec<PathBuf> = mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|mount| PathBuf::from(mount.replace("\\040", " ")))
+        .filter(|mount| mount.starts_with(target))
+        .collect();
+    found.sort_by_key(|mount| std::cmp::Reverse(mount.components().count()));
+    Ok(found)
+}
+
+/// Unmounts `target` and everything mounted below it.
+pub fn umount_recursive(target: &Path) -> anyhow::Result<()> {
+    for mount in mounts_below(target)? {
+        umount(&mount)?;
+    }
+    Ok(())
+}
@@ -1,32 +1,40 @@
 use crate::container::WaydroidContainer;
+use crate::context::Context;
+use crate::error::WsuError;
 use crate::magisk_files::{magisk_is_installed, magisk_is_set_up, waydroid_su};
-use crate::print::{msg_end, msg_err, msg_err_str, msg_main, msg_regular, msg_sub};
-use crate::selinux::getenforce;
-use crate::utils::{create_tmpdir, get_data_home, unzip_file};
+use crate::packages::PackageIndex;
+use crate::report::Reporter;
+use crate::selinux::host_selinux;
+use crate::utils::{Abi, create_tmpdir, get_running_abi, read_prop_file, unzip_file};
 use anyhow::{Ok, anyhow};
-use colored::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env::temp_dir;
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result::Result::Ok as OtherOk;
+use zip::read::ZipArchive;
+
+// Magisk keeps isolated service processes under this pseudo package.
+const ISOLATED_MAGIC: &str = "isolated";
 
 pub struct Magisk {
+    ctx: Context,
     waydroid: WaydroidContainer,
     installed: bool,
     version: String,
+    packages: Option<PackageIndex>,
+    abi: Option<Abi>,
     pub modules_path: PathBuf,
 }
 
 impl Magisk {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(ctx: &Context) -> anyhow::Result<Self> {
         let waydroid = WaydroidContainer::new()?;
-        let installed = magisk_is_installed()? && magisk_is_set_up()?;
+        let installed = magisk_is_installed(ctx)? && magisk_is_set_up(ctx)?;
         let version_full = if installed {
-            waydroid_su(vec!["magisk", "-v"], false)?
+            waydroid_su(ctx, vec!["magisk", "-v"], false)?
         } else {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         };
 
         let version = if version_full.trim().contains(":MAGISK:R") {
@@ -42,7 +50,10 @@ impl Magisk {
             waydroid,
             installed,
             version,
-            modules_path: PathBuf::from(get_data_home()?).join("waydroid/data/adb/modules"),
+            packages: None,
+            abi: None,
+            modules_path: ctx.data_dir()?.join("adb/modules"),
+            ctx: ctx.clone(),
         })
     }
 
@@ -50,13 +61,29 @@ impl Magisk {
         &self.version
     }
 
+    pub fn report(&self) -> &dyn Reporter {
+        self.ctx.report()
+    }
+
+    pub fn is_kitsune(&self) -> bool {
+        self.version.contains("kitsune") || self.version.contains("v27.2-Waydroid")
+    }
+
+    fn hide_applet(&self) -> &'static str {
+        if self.is_kitsune() {
+            "magiskhide"
+        } else {
+            "--denylist"
+        }
+    }
+
     pub fn get_list_modules(&mut self) -> anyhow::Result<Vec<String>> {
         if !self.waydroid.is_session_running(true, true)? {
-            return Err(anyhow!("Waydroid session isn't running!"));
+            return Err(WsuError::SessionNotRunning.into());
         }
 
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
 
         let path = &self.modules_path;
@@ -90,15 +117,15 @@ impl Magisk {
 
     fn is_module_disabled(&mut self, module: &str) -> anyhow::Result<bool> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
 
         let module_dir = self.modules_path.join(module);
         if !module_dir.exists() || !module_dir.is_dir() {
-            return Err(anyhow!(format!("\'{}\' not found", module)));
+            return Err(WsuError::InvalidInput(format!("\'{}\' not found", module)).into());
         }
 
         Ok(module_dir.join("disable").exists() && module_dir.join("disable").is_file())
@@ -106,122 +133,154 @@ impl Magisk {
 
     pub fn disable_module(&mut self, module: &str) -> anyhow::Result<()> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
 
         let module_dir = self.modules_path.join(module);
         if !module_dir.exists() || !module_dir.is_dir() {
-            return Err(anyhow!(format!("\'{}\' not found", module)));
+            return Err(WsuError::InvalidInput(format!("\'{}\' not found", module)).into());
         }
 
         if !self.is_module_disabled(module)? {
             File::create(module_dir.join("disable"))?;
-            msg_regular(&format!("Disabled: \'{}\'", module));
+            self.report().info(&format!("Disabled: \'{}\'", module));
         } else {
-            return Err(anyhow!(format!("\'{}\' already disabled", module)));
+            return Err(WsuError::InvalidInput(format!("\'{}\' already disabled", module)).into());
         }
         Ok(())
     }
 
     pub fn enable_module(&mut self, module: &str) -> anyhow::Result<()> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
 
         let module_dir = self.modules_path.join(module);
         if !module_dir.exists() || !module_dir.is_dir() {
-            return Err(anyhow!(format!("\'{}\' not found", module)));
+            return Err(WsuError::InvalidInput(format!("\'{}\' not found", module)).into());
         }
 
         if self.is_module_disabled(module)? {
             fs::remove_file(module_dir.join("disable"))?;
-            msg_regular(&format!("Enabled: \'{}\'", module));
+            self.report().info(&format!("Enabled: \'{}\'", module));
         } else {
-            return Err(anyhow!(format!("\'{}\' already enabled", module)));
+            return Err(WsuError::InvalidInput(format!("\'{}\' already enabled", module)).into());
         }
         Ok(())
     }
 
     pub fn remove_module(&mut self, module: &str) -> anyhow::Result<()> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
 
         let module_dir = self.modules_path.join(module);
         if !module_dir.exists() || !module_dir.is_dir() {
-            return Err(anyhow!(format!("\'{}\' not found", module)));
+            return Err(WsuError::InvalidInput(format!("\'{}\' not found", module)).into());
         }
         fs::remove_dir_all(module_dir)?;
-        msg_regular(&format!("Removed: \'{}\'", module));
-        msg_regular("Reboot Waydroid to apply changes.");
+        self.report().info(&format!("Removed: \'{}\'", module));
+        self.report().info("Reboot Waydroid to apply changes.");
         Ok(())
     }
 
     fn get_module_name(&self, path: &str) -> anyhow::Result<String> {
         let tmp = temp_dir().join("waydroidsu");
-        create_tmpdir()?;
+        create_tmpdir(&self.ctx)?;
         let tmpdir = tmp.join("module_name");
         if !tmpdir.exists() {
             fs::create_dir_all(tmpdir.clone())?;
         }
         unzip_file(path, &tmpdir.to_string_lossy())?;
 
-        let prop = tmpdir.join("module.prop");
+        let props = read_prop_file(&tmpdir.join("module.prop"))?;
 
-        let file = fs::File::open(prop)?;
-        let reader = BufReader::new(file);
-        let mut props = HashMap::new();
+        if tmp.exists() {
+            fs::remove_dir_all(tmp)?;
+        }
+        props
+            .get("id")
+            .cloned()
+            .ok_or_else(|| anyhow!("Couldn't get name of the module!"))
+    }
 
-        for line in reader.lines() {
-            let line = line?;
-            let line = line.trim();
+    pub fn get_module_info(&mut self, module: &str) -> anyhow::Result<(String, bool)> {
+        let module_dir = self.modules_path.join(module);
+        let props = read_prop_file(&module_dir.join("module.prop")).unwrap_or_default();
+        let version = props.get("version").cloned().unwrap_or_default();
+        Ok((version, !self.is_module_disabled(module)?))
+    }
 
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
+    /// ABIs of the container's zygotes that a module's `zygisk/*.so` libraries don't cover.
+    pub fn module_missing_zygisk_abis(&mut self, module: &str) -> anyhow::Result<Vec<String>> {
+        let zygisk_dir = self.modules_path.join(module).join("zygisk");
+        let Some(entries) = fs::read_dir(zygisk_dir).ok() else {
+            return Ok(Vec::new());
+        };
+        let libs: Vec<String> = entries
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+        Ok(missing_zygisk_abis(&libs, self.abi()?))
+    }
 
-            if let Some((key, value)) = line.split_once('=') {
-                props.insert(key.trim().to_string(), value.trim().to_string());
-                if key == "id" {
-                    if tmp.exists() {
-                        fs::remove_dir_all(tmp)?;
-                    }
-                    return Ok(value.to_string());
-                }
-            }
+    fn abi(&mut self) -> anyhow::Result<&Abi> {
+        if self.abi.is_none() {
+            self.abi = Some(get_running_abi(&self.ctx, None)?);
         }
+        self.abi
+            .as_ref()
+            .ok_or_else(|| anyhow!("Couldn't detect the container ABI!"))
+    }
 
-        if tmp.exists() {
-            fs::remove_dir_all(tmp)?;
-        }
-        Err(anyhow!("Couldn't get name of the module!"))
+    fn check_zygisk_module(&mut self, module_path: &Path) -> anyhow::Result<()> {
+        let archive = ZipArchive::new(File::open(module_path)?)?;
+        let libs: Vec<String> = archive
+            .file_names()
+            .filter_map(|name| name.strip_prefix("zygisk/"))
+            .map(|name| name.to_string())
+            .collect();
+        let abi = self.abi()?;
+        let missing = missing_zygisk_abis(&libs, abi);
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let mut msg = format!("Zygisk module has no libraries for {}", missing.join(", "));
+        if let Some(bridge) = &abi.native_bridge {
+            msg.push_str(&format!(
+                ", {} translation doesn\'t apply to zygote",
+                bridge
+            ));
+        }
+        Err(anyhow!(msg))
     }
 
-    pub fn install_module(&mut self, module: &str) -> anyhow::Result<()> {
+    pub fn install_module(&mut self, module: &str, force: bool) -> anyhow::Result<()> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
         let module_path = PathBuf::from(module);
-        let tmp_dir = PathBuf::from(get_data_home()?).join("waydroid/data/local/tmp");
+        let tmp_dir = self.ctx.data_dir()?.join("local/tmp");
 
         if !module_path.exists() {
-            return Err(anyhow!("No such file or directory."));
+            return Err(WsuError::InvalidInput("No such file or directory.".to_string()).into());
         }
         let filename = module_path
             .file_name()
-            .expect(&msg_err_str("Failed to get file name."))
+            .ok_or_else(|| anyhow!("Failed to get file name."))?
             .to_string_lossy();
         if !module_path
             .to_string_lossy()
@@ -229,33 +288,46 @@ impl Magisk {
             .to_ascii_lowercase()
             .ends_with(".zip")
         {
-            return Err(anyhow!(format!("{} isn't a zip file", filename)));
+            return Err(WsuError::InvalidInput(format!("{} isn't a zip file", filename)).into());
+        }
+        if let Err(e) = self.check_zygisk_module(&module_path) {
+            if !force {
+                return Err(anyhow!("{}, use \'--force\' to install anyway", e));
+            }
+            self.report().warn(&e.to_string());
         }
 
         let waydroid_module_path = PathBuf::from("/data/local/tmp").join(filename.to_string());
         let waydroid_module_path_string = waydroid_module_path.to_string_lossy().to_string();
-        msg_main(&format!("Installing magisk module"));
-        msg_sub("Copying to temporary directory");
+        self.report().begin("Installing magisk module");
+        self.report().step("Copying to temporary directory");
         fs::copy(module_path.clone(), tmp_dir.join(filename.to_string()))?;
-        msg_sub("Installing");
+        self.report().step("Installing");
         if let Err(e) = waydroid_su(
+            &self.ctx,
             vec!["magisk", "--install-module", &waydroid_module_path_string],
             false,
         ) {
             return Err(anyhow!("Installation failed! - {}", e));
         }
-        waydroid_su(vec!["rm", &waydroid_module_path.to_string_lossy()], false)?;
+        waydroid_su(
+            &self.ctx,
+            vec!["rm", &waydroid_module_path.to_string_lossy()],
+            false,
+        )?;
         let name = match self.get_module_name(&module_path.to_string_lossy()) {
             OtherOk(n) => n,
             Err(e) => {
-                msg_err(&e.to_string());
-                msg_end("Installation completed.");
-                msg_regular("Reboot Waydroid to apply changes.");
+                self.report().warn(&e.to_string());
+                self.report().finish("Installation completed.");
+                self.report().info("Reboot Waydroid to apply changes.");
                 return Ok(());
             }
         };
-        msg_end(&format!("module: \'{}\' has been installed", name));
-        msg_regular("Reboot Waydroid to apply changes.");
+        self.ctx
+            .report()
+            .finish(&format!("module: \'{}\' has been installed", name));
+        self.report().info("Reboot Waydroid to apply changes.");
 
         Ok(())
     }
@@ -265,50 +337,53 @@ impl Magisk {
         applet: &str,
         args: Vec<&str>,
         force_no_su: bool,
+    ) -> anyhow::Result<String> {
+        self.applet(applet, args, force_no_su)
+    }
+
+    fn applet(
+        &mut self,
+        applet: &str,
+        args: Vec<&str>,
+        force_no_su: bool,
     ) -> anyhow::Result<String> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
         let mut args_new: Vec<&str> = Vec::with_capacity(args.len() + 1);
         args_new.push("magisk");
-        args_new.push(&applet);
-        args_new.extend(args.iter().map(|s| s));
-        let out = waydroid_su(args_new, force_no_su)?;
-        if applet != "--sqlite" {
-            if !out.is_empty() {
-                println!("{}", out.bold());
-            }
-        }
-        Ok(out)
+        args_new.push(applet);
+        args_new.extend(args.iter());
+        waydroid_su(&self.ctx, args_new, force_no_su)
     }
 
     pub fn sqlite(&mut self, arg: &str, force_no_su: bool) -> anyhow::Result<String> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
         Ok(self.cmd("--sqlite", vec![arg], force_no_su)?)
     }
 
     pub fn get_zygisk(&mut self) -> anyhow::Result<bool> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
-        let getenforce = getenforce()?;
+        let enforcing = host_selinux().is_enforcing();
         let zygisk_str = self.sqlite(
             "\"SELECT value FROM settings WHERE key == 'zygisk'\"",
-            getenforce,
+            enforcing,
         )?;
 
-        if let Some(zygisk) = zygisk_str.trim().split('=').last() {
+        if let Some(zygisk) = zygisk_str.trim().split('=').next_back() {
             return Ok(zygisk == "1");
         }
         Err(anyhow!("Couldn't get the value of zygisk!"))
@@ -316,21 +391,21 @@ impl Magisk {
 
     pub fn set_zygisk(&mut self, enabled: bool) -> anyhow::Result<()> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
-        let getenforce = getenforce()?;
+        let enforcing = host_selinux().is_enforcing();
         if enabled {
             self.sqlite(
                 "\"REPLACE INTO settings (key,value) VALUES('zygisk',1)\"",
-                getenforce,
+                enforcing,
             )?;
         } else {
             self.sqlite(
                 "\"REPLACE INTO settings (key,value) VALUES('zygisk',0)\"",
-                getenforce,
+                enforcing,
             )?;
         }
         Ok(())
@@ -338,15 +413,14 @@ impl Magisk {
 
     pub fn get_superuser_list(&mut self) -> anyhow::Result<Vec<(String, &'static str)>> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
-        let getenforce = getenforce()?;
-        let kitsune =
-            self.version().contains("kitsune") || self.version().contains("v27.2-Waydroid");
-        let result = self.sqlite("\"SELECT uid,policy FROM policies\"", getenforce)?;
+        let enforcing = host_selinux().is_enforcing();
+        let kitsune = self.is_kitsune();
+        let result = self.sqlite("\"SELECT uid,policy FROM policies\"", enforcing)?;
 
         let mut superuser_list = Vec::new();
         for line in result.lines() {
@@ -376,7 +450,7 @@ impl Magisk {
 
             let policy_val: i32 = match policy_field
                 .split('=')
-                .last()
+                .next_back()
                 .and_then(|s| s.trim().parse().ok())
             {
                 Some(v) => v,
@@ -385,7 +459,7 @@ impl Magisk {
 
             let uid_num: i32 = match uid_field
                 .split('=')
-                .last()
+                .next_back()
                 .and_then(|s| s.trim().parse().ok())
             {
                 Some(v) => v,
@@ -404,52 +478,176 @@ impl Magisk {
 
     pub fn superuser_manage(&mut self, pkg: &str, allow: bool) -> anyhow::Result<()> {
         if !self.waydroid.is_container_running()? {
-            return Err(anyhow!("Waydroid container isn't running!"));
+            return Err(WsuError::ContainerNotRunning.into());
         }
         if !self.installed {
-            return Err(anyhow!("Magisk isn't installed!"));
+            return Err(WsuError::NotInstalled.into());
         }
-        let getenforce = getenforce()?;
+        let enforcing = host_selinux().is_enforcing();
 
         let policy = if allow { "2" } else { "1" };
-        //let (pkgs, app_id) = self.get_package(query)
-        let app_id = self.get_app_id(pkg);
-        if app_id.is_empty() {
-            return Err(anyhow!("Invalid package name!"));
-        }
+        // a root grant has to name its package, fuzzy matches are confirmed
+        let pkg = self.resolve_package(pkg, true)?;
+        let app_id = self.get_app_id(&pkg)?;
 
         let arg = format!(
             "\"REPLACE INTO policies VALUES({},{},0,1,1)\"",
             app_id, policy
         );
-        self.sqlite(&arg, getenforce)?;
+        self.sqlite(&arg, enforcing)?;
+        self.report().info(&format!(
+            "{}: \'{}\'",
+            if allow { "Allowed" } else { "Denied" },
+            pkg
+        ));
         Ok(())
     }
 
-    fn get_package(&mut self, query: i32) -> Vec<String> {
-        let mut names = Vec::new();
-
-        if let OtherOk(result) = waydroid_su(
-            vec![
-                "pm",
-                "list",
-                "packages",
-                "-U",
-                "|",
-                "grep",
-                &query.to_string(),
-            ],
-            false,
-        ) {
-            for line in result.lines() {
-                let mut parts = line.split_whitespace();
-                if let Some(name_part) = parts.next() {
-                    if let Some(name) = name_part.split(':').last() {
-                        names.push(name.trim().to_string());
-                    }
-                }
+    pub fn superuser_remove(&mut self, pkg: &str) -> anyhow::Result<()> {
+        let enforcing = host_selinux().is_enforcing();
+        let app_id = self.get_app_id(pkg)?;
+        let arg = format!("\"DELETE FROM policies WHERE uid == {}\"", app_id);
+        self.sqlite(&arg, enforcing)?;
+        Ok(())
+    }
+
+    pub fn get_settings(&mut self) -> anyhow::Result<BTreeMap<String, i64>> {
+        let enforcing = host_selinux().is_enforcing();
+        let result = self.sqlite("\"SELECT key,value FROM settings\"", enforcing)?;
+
+        let mut settings = BTreeMap::new();
+        for line in result.lines() {
+            let fields: HashMap<&str, &str> = line
+                .split('|')
+                .filter_map(|field| field.trim().split_once('='))
+                .collect();
+            if let (Some(key), Some(value)) = (fields.get("key"), fields.get("value"))
+                && let OtherOk(value) = value.parse()
+            {
+                settings.insert(key.to_string(), value);
             }
         }
+        Ok(settings)
+    }
+
+    pub fn set_setting(&mut self, key: &str, value: i64) -> anyhow::Result<()> {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(WsuError::InvalidInput(format!("Invalid setting name \'{}\'", key)).into());
+        }
+        let enforcing = host_selinux().is_enforcing();
+        let arg = format!(
+            "\"REPLACE INTO settings (key,value) VALUES('{}',{})\"",
+            key, value
+        );
+        self.sqlite(&arg, enforcing)?;
+        Ok(())
+    }
+
+    pub fn get_denylist(&mut self) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+        let applet = self.hide_applet();
+        let out = self.applet(applet, vec!["ls"], false)?;
+        let mut denylist: Vec<(String, Vec<String>)> = Vec::new();
+        for line in out.lines() {
+            let Some((pkg, process)) = line.trim().split_once('|') else {
+                continue;
+            };
+            match denylist.iter_mut().find(|(p, _)| p == pkg) {
+                Some((_, processes)) => processes.push(process.to_string()),
+                None => denylist.push((pkg.to_string(), vec![process.to_string()])),
+            }
+        }
+        denylist.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(denylist)
+    }
+
+    pub fn denylist_add(&mut self, target: &str, all_processes: bool) -> anyhow::Result<()> {
+        let (pkg, process) = split_target(target);
+        let pkg = &self.resolve_hide_target(pkg)?;
+        if process.is_some() && all_processes {
+            return Err(WsuError::InvalidInput(
+                "\'--all-processes\' can't be combined with a process name".to_string(),
+            )
+            .into());
+        }
+        if pkg == ISOLATED_MAGIC && process.is_none() {
+            return Err(WsuError::InvalidInput(
+                "Isolated targets require a process name".to_string(),
+            )
+            .into());
+        }
+
+        let targets = if all_processes {
+            self.get_package_processes(pkg)?
+        } else {
+            vec![(pkg.to_string(), process.unwrap_or(pkg).to_string())]
+        };
+
+        for (pkg, process) in targets {
+            let applet = self.hide_applet();
+            self.applet(applet, vec!["add", &pkg, &process], false)?;
+            self.ctx
+                .report()
+                .step(&format!("Added: {}/{}", pkg, process));
+        }
+        Ok(())
+    }
+
+    pub fn denylist_rm(&mut self, target: &str) -> anyhow::Result<()> {
+        // entries of uninstalled packages are removed too, so match the list as given
+        let (pkg, process) = split_target(target);
+        let listed = self
+            .get_denylist()?
+            .into_iter()
+            .find(|(listed, _)| listed == pkg)
+            .is_some_and(|(_, processes)| process.is_none_or(|p| processes.iter().any(|l| l == p)));
+        if !listed {
+            return Err(
+                WsuError::InvalidInput(format!("\'{}\' isn't on the denylist", target)).into(),
+            );
+        }
+        let mut args = vec!["rm", pkg];
+        if let Some(process) = process {
+            args.push(process);
+        }
+        let applet = self.hide_applet();
+        self.applet(applet, args, false)?;
+        self.report().step(&format!("Removed: {}", target));
+        Ok(())
+    }
+
+    fn resolve_hide_target(&mut self, pkg: &str) -> anyhow::Result<String> {
+        if pkg == ISOLATED_MAGIC {
+            return Ok(pkg.to_string());
+        }
+        self.resolve_package(pkg, false)
+    }
+
+    /// Every process `pkg` declares, as (denylist package, process) pairs.
+    fn get_package_processes(&mut self, pkg: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let out = waydroid_su(&self.ctx, vec!["dumpsys", "package", pkg], false)
+            .map_err(|e| anyhow!("Couldn't dump package {}: {}", pkg, e))?;
+        Ok(declared_processes(pkg, &out))
+    }
+
+    pub fn package_index(&mut self) -> anyhow::Result<&PackageIndex> {
+        if self.packages.is_none() {
+            self.packages = Some(PackageIndex::fetch(&self.ctx)?);
+        }
+        self.packages
+            .as_ref()
+            .ok_or_else(|| anyhow!("Couldn't get the list of installed packages!"))
+    }
+
+    fn get_package(&mut self, query: i32) -> Vec<String> {
+        let mut names: Vec<String> = match self.package_index() {
+            OtherOk(index) => index
+                .by_uid(query as u32)
+                .iter()
+                .map(|p| p.name.clone())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        names.dedup();
 
         if names.is_empty() {
             names.push(format!("uid:{}", query));
@@ -458,43 +656,119 @@ impl Magisk {
         names
     }
 
-    fn get_app_id(&mut self, query: &str) -> String {
-        let mut app_id = String::new();
+    fn resolve_package(&mut self, query: &str, confirm: bool) -> anyhow::Result<String> {
+        let ctx = self.ctx.clone();
+        Ok(self
+            .package_index()?
+            .resolve(ctx.report(), query, confirm)?
+            .name
+            .clone())
+    }
 
-        if let OtherOk(result) = waydroid_su(
-            vec![
-                "pm",
-                "list",
-                "packages",
-                "-U",
-                "|",
-                "grep",
-                &query.to_string(),
-            ],
-            false,
-        ) {
-            let mut contains = false;
-            for line in result.lines() {
-                let mut parts = line.split_whitespace();
-                if let Some(name_part) = parts.next() {
-                    if let Some(name) = name_part.split(':').last() {
-                        if query == name {
-                            contains = true;
-                        }
-                    }
-                }
-            }
+    fn get_app_id(&mut self, query: &str) -> anyhow::Result<u32> {
+        let ctx = self.ctx.clone();
+        Ok(self
+            .package_index()?
+            .resolve(ctx.report(), query, true)?
+            .app_id())
+    }
+}
 
-            let mut parts = result.split_whitespace();
-            parts.next();
-            if let Some(app_part) = parts.next() {
-                if let Some(uid) = app_part.split(':').last() {
-                    if contains {
-                        app_id = uid.to_string();
-                    }
-                }
+/// The main process of `pkg` and the ones listed under `processes:` in its
+/// `dumpsys package` entry.
+fn declared_processes(pkg: &str, dump: &str) -> Vec<(String, String)> {
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let header = format!("Package [{}]", pkg);
+    let mut processes = vec![(pkg.to_string(), pkg.to_string())];
+    let mut lines = dump
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with(&header));
+    let Some(package_line) = lines.next() else {
+        return processes;
+    };
+    let package_indent = indent(package_line);
+    let mut list_indent = None;
+    let mut entry_indent = None;
+    for line in lines {
+        let name = line.trim();
+        if name.is_empty() {
+            continue;
+        }
+        let depth = indent(line);
+        if depth <= package_indent {
+            break;
+        }
+        match list_indent {
+            None if name == "processes:" => list_indent = Some(depth),
+            None => {}
+            Some(list) if depth <= list => break,
+            // deeper lines are the permissions denied to a process
+            Some(_)
+                if *entry_indent.get_or_insert(depth) == depth
+                    && !processes.iter().any(|(_, process)| process == name) =>
+            {
+                processes.push((pkg.to_string(), name.to_string()));
             }
+            Some(_) => {}
         }
-        app_id
+    }
+    processes
+}
+
+fn split_target(target: &str) -> (&str, Option<&str>) {
+    match target.split_once('/') {
+        Some((pkg, process)) if !process.is_empty() => (pkg, Some(process)),
+        Some((pkg, _)) => (pkg, None),
+        None => (target, None),
+    }
+}
+
+/// Zygote ABIs with no matching `<abi>.so` in a Zygisk module's library list.
+/// Modules without any Zygisk libraries aren't Zygisk modules and miss nothing.
+fn missing_zygisk_abis(libs: &[String], abi: &Abi) -> Vec<String> {
+    if !libs.iter().any(|lib| lib.ends_with(".so")) {
+        return Vec::new();
+    }
+    abi.zygote_abis()
+        .into_iter()
+        .filter(|zygote_abi| !libs.contains(&format!("{}.so", zygote_abi)))
+        .map(|zygote_abi| zygote_abi.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = "Packages:
+  Package [org.example.app] (5c1a2e7):
+    userId=10071
+    pkg=Package{4d3c8a1 org.example.app}
+    processes:
+      org.example.app:remote
+      org.example.app:push
+        deny:
+          android.permission.INTERNET
+    timeStamp=2024-01-01 00:00:00
+  Package [org.example.other] (9ab31f0):
+    processes:
+      org.example.other:sync
+";
+
+    #[test]
+    fn declared_processes_reads_the_package_block() {
+        let app = "org.example.app";
+        assert_eq!(
+            declared_processes(app, DUMP),
+            [app, "org.example.app:remote", "org.example.app:push"]
+                .map(|process| (app.to_string(), process.to_string()))
+        );
+        assert_eq!(
+            declared_processes("org.example.none", DUMP),
+            [(
+                "org.example.none".to_string(),
+                "org.example.none".to_string()
+            )]
+        );
     }
 }
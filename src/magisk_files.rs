@@ -1,92 +1,66 @@
+use crate::backup::BackupStore;
 use crate::constants::*;
-use crate::container::{WaydroidContainer, has_overlay};
+use crate::container::WaydroidContainer;
+use crate::context::{Context, Hook};
+use crate::error::WsuError;
+use crate::initrc::RcFile;
 use crate::magisk::Magisk;
-use crate::print::{msg_err_str, msg_sub};
+use crate::report::{Reporter, Target};
 use crate::selinux::*;
+use crate::templates::Templates;
 use crate::utils::*;
 use anyhow::{Ok, anyhow};
 use std::env;
-use std::fs::{self, File};
-use std::io::{BufRead, BufReader};
+use std::env::temp_dir;
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::result::Result::Ok as OtherOk;
 
-pub fn magisk_is_installed() -> anyhow::Result<bool> {
+pub fn magisk_is_installed(ctx: &Context) -> anyhow::Result<bool> {
     let mut waydroid = WaydroidContainer::new()?;
     let magisk_dir;
     if waydroid.is_session_running(true, true)? {
-        magisk_dir = PathBuf::from(WAYDROID_DIR).join("rootfs/system/etc/init/magisk");
-    } else if has_overlay().expect(&msg_err_str("Failed to get overlay result!")) {
-        magisk_dir = PathBuf::from(WAYDROID_DIR).join("overlay/system/etc/init/magisk");
+        magisk_dir = ctx.rootfs().join(MAGISK_DIR);
+    } else if ctx.has_overlay()? {
+        magisk_dir = ctx.overlay().join(MAGISK_DIR);
     } else {
-        mount_system(waydroid, true)?;
-        magisk_dir = PathBuf::from("/mnt/waydroid").join("system/etc/init/magisk");
+        mount_system(ctx, waydroid, 0, true, true)?;
+        magisk_dir = temp_dir().join("waydroidsu/mnt").join(MAGISK_DIR);
         let magisk_dir_result = magisk_dir.exists();
-        umount_system(true)?;
+        umount_system(ctx.report(), true)?;
         return Ok(magisk_dir_result);
     }
     Ok(magisk_dir.exists())
 }
 
-pub fn magisk_is_set_up() -> anyhow::Result<bool> {
+pub fn magisk_is_set_up(ctx: &Context) -> anyhow::Result<bool> {
     let mut waydroid = WaydroidContainer::new()?;
-    let magisk_init =
-        if has_overlay().expect(&msg_err_str("Failed to get \"mount_overlays\" config,")) {
-            PathBuf::from(WAYDROID_DIR)
-                .join("overlay")
-                .join(MAGISK_DIR)
-                .join("magisk")
-        } else {
-            if !waydroid.is_session_running(true, true)? {
-                return Err(anyhow!("Waydroid session isn't running!"));
-            }
-            PathBuf::from(WAYDROID_DIR)
-                .join("rootfs")
-                .join(MAGISK_DIR)
-                .join("magisk")
-        };
-    let magisk_data = PathBuf::from(get_data_home()?).join("waydroid/data/adb/magisk/magisk");
-    let magisk_init64 =
-        if has_overlay().expect(&msg_err_str("Failed to get \"mount_overlays\" config,")) {
-            PathBuf::from(WAYDROID_DIR)
-                .join("overlay")
-                .join(MAGISK_DIR)
-                .join("magisk64")
-        } else {
-            if !waydroid.is_session_running(true, true)? {
-                return Err(anyhow!("Waydroid session isn't running!"));
-            }
-            PathBuf::from(WAYDROID_DIR)
-                .join("rootfs")
-                .join(MAGISK_DIR)
-                .join("magisk64")
-        };
-    let magisk_data64 = PathBuf::from(get_data_home()?).join("waydroid/data/adb/magisk/magisk64");
-    Ok((magisk_init.exists() || magisk_init64.exists())
-        && (magisk_data.exists() || magisk_data64.exists()))
+    let magisk_dir = if ctx.has_overlay()? {
+        ctx.overlay().join(MAGISK_DIR)
+    } else {
+        if !waydroid.is_session_running(true, true)? {
+            return Err(WsuError::SessionNotRunning.into());
+        }
+        ctx.rootfs().join(MAGISK_DIR)
+    };
+    let data_magisk = ctx.data_dir()?.join("adb/magisk");
+    Ok(
+        (magisk_dir.join("magisk").exists() || magisk_dir.join("magisk64").exists())
+            && (data_magisk.join("magisk").exists() || data_magisk.join("magisk64").exists()),
+    )
 }
 
-pub fn get_status() -> anyhow::Result<(bool, String, bool)> {
+pub fn get_status(ctx: &Context) -> anyhow::Result<(bool, String, bool)> {
     let mut waydroid = WaydroidContainer::new()?;
     if !waydroid.is_container_running()? {
-        return Err(anyhow!("Waydroid container isn't running!"));
+        return Err(WsuError::ContainerNotRunning.into());
     }
-    let installed = magisk_is_installed()? && magisk_is_set_up()?;
-
-    let mut args = Vec::new();
-    args.push("pidof");
-    args.push("magiskd");
-    let daemon_running = if installed {
-        if let Err(_) = waydroid_su(args, false) {
-            false
-        } else {
-            true
-        }
-    } else {
-        false
-    };
+    let installed = magisk_is_installed(ctx)? && magisk_is_set_up(ctx)?;
+
+    let daemon_running = installed && waydroid_su(ctx, vec!["pidof", "magiskd"], false).is_ok();
     let (zygisk, version) = if daemon_running {
-        let mut magisk = Magisk::new()?;
+        let mut magisk = Magisk::new(ctx)?;
         (magisk.get_zygisk()?, magisk.version().to_string())
     } else {
         (false, String::new())
@@ -94,21 +68,20 @@ pub fn get_status() -> anyhow::Result<(bool, String, bool)> {
     Ok((daemon_running, version, zygisk))
 }
 
-pub fn waydroid_su(args: Vec<&str>, force_no_su: bool) -> anyhow::Result<String> {
+pub fn waydroid_su(ctx: &Context, args: Vec<&str>, force_no_su: bool) -> anyhow::Result<String> {
     let mut waydroid = WaydroidContainer::new()?;
-    let selinux_enforcing = getenforce()?;
+    let selinux_enforcing = host_selinux().is_enforcing();
     if !waydroid.is_container_running()? {
-        return Err(anyhow!("Waydroid container isn't running!"));
+        return Err(WsuError::ContainerNotRunning.into());
     }
 
-    if !magisk_is_installed()? && !force_no_su {
-        return Err(anyhow!("Magisk is not installed!"));
+    if !magisk_is_installed(ctx)? && !force_no_su {
+        return Err(WsuError::NotInstalled.into());
     }
 
     if args.is_empty() {
         return Err(anyhow!("su arguments are empty"));
     }
-    let lxc = PathBuf::from(WAYDROID_DIR).join("lxc");
     let path_var = env::var("PATH")?;
 
     let args_string = args.join(" ");
@@ -119,23 +92,30 @@ pub fn waydroid_su(args: Vec<&str>, force_no_su: bool) -> anyhow::Result<String>
     };
 
     let mut cmd = Command::new("lxc-attach");
-    cmd.args(["-P", lxc.to_string_lossy().trim(), "-n", "waydroid", "--"]);
-
-    if selinux_enforcing {
-        cmd.args(["sh", "-c", &full_command]);
+    cmd.args([
+        "-P",
+        ctx.lxc_path.to_string_lossy().trim(),
+        "-n",
+        &ctx.container,
+        "--",
+    ]);
+
+    let inner: Vec<String> = if selinux_enforcing || force_no_su {
+        vec!["sh".to_string(), "-c".to_string(), full_command]
     } else {
-        if force_no_su {
-            cmd.args(["sh", "-c", &full_command]);
-        } else {
-            cmd.args([&format!("/{}/su", MAGISKTMP), "-c"]).args(args);
-        }
-    }
+        [format!("/{}/su", MAGISKTMP), "-c".to_string()]
+            .into_iter()
+            .chain(args.iter().map(|arg| arg.to_string()))
+            .collect()
+    };
+    cmd.args(&inner);
 
     cmd.env(
         "PATH",
         format!("{path_var}:/system/bin:/vendor/bin:/product/bin"),
     );
 
+    ctx.report().command(Target::Container, &inner);
     let out = cmd.output()?;
 
     if !out.status.success() {
@@ -164,6 +144,7 @@ fn move_from_overlay_rw(overlay_rw: PathBuf, overlay: PathBuf) -> anyhow::Result
 }
 
 pub fn clean_up(
+    ctx: &Context,
     rootfs: PathBuf,
     has_overlay: bool,
     overlay_rw: PathBuf,
@@ -172,12 +153,15 @@ pub fn clean_up(
     let rm_adb = !waydroid_data.to_string_lossy().is_empty();
     let adb_magisk = waydroid_data.join("adb/magisk");
 
-    let common_paths = [
-        overlay_rw.join(MAGISK_DIR),
+    let mut common_paths = vec![
         rootfs.join(MAGISK_DIR),
-        overlay_rw.join("system/addon.d/99-magisk.sh"),
         rootfs.join("system/addon.d/99-magisk.sh"),
     ];
+    // offline images have no overlay_rw
+    if !overlay_rw.to_string_lossy().is_empty() {
+        common_paths.push(overlay_rw.join(MAGISK_DIR));
+        common_paths.push(overlay_rw.join("system/addon.d/99-magisk.sh"));
+    }
 
     for path in common_paths {
         remove_check(path)?;
@@ -189,9 +173,9 @@ pub fn clean_up(
 
     if has_overlay {
         let vendor_selinux = rootfs.join("vendor/etc/selinux");
-        let vendor_selinux_rw = PathBuf::from(WAYDROID_DIR).join("overlay_rw/vendor/etc/selinux");
+        let vendor_selinux_rw = ctx.overlay_rw().join("vendor/etc/selinux");
 
-        let move_pairs = vec![
+        let mut move_pairs = vec![
             (
                 vendor_selinux_rw.join("vendor_file_contexts"),
                 vendor_selinux.join("vendor_file_contexts"),
@@ -200,22 +184,6 @@ pub fn clean_up(
                 vendor_selinux_rw.join("vendor_file_contexts.gz"),
                 vendor_selinux.join("vendor_file_contexts.gz"),
             ),
-            (
-                overlay_rw.join("system/etc/init/hw/init.zygote32.rc"),
-                rootfs.join("system/etc/init/hw/init.zygote32.rc"),
-            ),
-            (
-                overlay_rw.join("system/etc/init/hw/init.zygote64_32.rc"),
-                rootfs.join("system/etc/init/hw/init.zygote64_32.rc"),
-            ),
-            (
-                overlay_rw.join("system/etc/init/hw/init.zygote32.rc.gz"),
-                rootfs.join("system/etc/init/hw/init.zygote32.rc.gz"),
-            ),
-            (
-                overlay_rw.join("system/etc/init/hw/init.zygote64_32.rc.gz"),
-                rootfs.join("system/etc/init/hw/init.zygote64_32.rc.gz"),
-            ),
             (
                 overlay_rw.join(BOOTANIM_RC_PATH),
                 rootfs.join(BOOTANIM_RC_PATH),
@@ -238,6 +206,13 @@ pub fn clean_up(
             remove_check(path)?;
         }
 
+        for zygote in ZYGOTE_RCS {
+            for name in [zygote.to_string(), format!("{}.gz", zygote)] {
+                let rel = PathBuf::from("system/etc/init/hw").join(name);
+                move_pairs.push((overlay_rw.join(&rel), rootfs.join(rel)));
+            }
+        }
+
         for (src, dest) in move_pairs {
             move_from_overlay_rw(src, dest)?;
         }
@@ -245,166 +220,284 @@ pub fn clean_up(
     Ok(())
 }
 
-fn backup_bootanim(bootanim_rc_path: PathBuf, bootanim_rc_gz_path: PathBuf) -> anyhow::Result<()> {
-    msg_sub("Backing up bootanim.rc");
-    gzip_compress(
-        &bootanim_rc_path.to_string_lossy(),
-        &bootanim_rc_gz_path.to_string_lossy(),
+fn read_rc(path: &Path) -> anyhow::Result<RcFile> {
+    RcFile::parse(&fs::read_to_string(path)?)
+        .map_err(|e| anyhow!("Couldn't parse {}: {}", path.to_string_lossy(), e))
+}
+
+/// Magisk's init actions and services, with randomly named services.
+fn magisk_rc_template(templates: &Templates) -> String {
+    let x = generate_random_string(15);
+    let y = generate_random_string(15);
+    templates
+        .magisk_rc
+        .replace("magisk_service_x", &x)
+        .replace("magisk_service_y", &y)
+}
+
+/// Picks the hook method for the image at `rootfs`, falling back to bootanim.rc
+/// when its init won't load a separate rc file.
+pub fn resolve_hook(report: &dyn Reporter, hook: Hook, rootfs: &Path) -> anyhow::Result<Hook> {
+    if hook == Hook::Bootanim {
+        return Ok(hook);
+    }
+    match rc_dir_imported(rootfs) {
+        OtherOk(true) => Ok(Hook::Rc),
+        OtherOk(false) => {
+            report.warn("This image's init.rc doesn't import /system/etc/init, using bootanim.rc");
+            Ok(Hook::Bootanim)
+        }
+        Err(e) => {
+            report.warn(&format!(
+                "Couldn't check init imports ({}), using bootanim.rc",
+                e
+            ));
+            Ok(Hook::Bootanim)
+        }
+    }
+}
+
+/// Whether one of the image's init.rc files imports /system/etc/init.
+fn rc_dir_imported(rootfs: &Path) -> anyhow::Result<bool> {
+    for init_rc in ["init.rc", "system/etc/init/hw/init.rc"] {
+        let path = rootfs.join(init_rc);
+        if path.exists()
+            && read_rc(&path)?
+                .imports()
+                .iter()
+                .any(|import| import.trim_end_matches('/') == "/system/etc/init")
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The rc file wsu installed under /system/etc/init, if any.
+pub fn find_magisk_rc(rootfs: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let init_dir = rootfs.join(INIT_DIR);
+    if !init_dir.exists() {
+        return Ok(None);
+    }
+    for entry in fs::read_dir(init_dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "rc") {
+            continue;
+        }
+        if read_rc(&path).is_ok_and(|rc| rc.is_added_only()) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Installs Magisk's init actions as their own randomly named rc file.
+pub fn patch_magisk_rc(
+    report: &dyn Reporter,
+    rootfs: &Path,
+    templates: &Templates,
+) -> anyhow::Result<()> {
+    remove_magisk_rc(report, rootfs)?;
+
+    report.step("Installing Magisk init rc");
+    let mut rc = RcFile::default();
+    rc.merge(&RcFile::parse(&magisk_rc_template(templates))?)?;
+    let init_dir = rootfs.join(INIT_DIR);
+    create_dir_check(init_dir.clone(), false)?;
+    let path = init_dir.join(format!("{}.rc", generate_random_string(15)));
+    fs::write(&path, rc.serialize())?;
+    set_selinux_attr(
+        &path.to_string_lossy(),
+        SELINUX_XATTR,
+        "u:object_r:system_file:s0",
     )?;
     Ok(())
 }
 
+pub fn remove_magisk_rc(report: &dyn Reporter, rootfs: &Path) -> anyhow::Result<()> {
+    while let Some(path) = find_magisk_rc(rootfs)? {
+        report.step("Removing Magisk init rc");
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Merges Magisk's actions and services into bootanim.rc, leaving the rest of it untouched.
+/// Without one in `rootfs` the copy staged from the image below the overlay is
+/// patched, the template only stands in when the image has none either.
 pub fn patch_bootanim(
-    bootanim_rc_path: PathBuf,
-    bootanim_rc_gz_path: PathBuf,
-    has_overlay: bool,
-    kitsune: bool,
+    report: &dyn Reporter,
+    rootfs: &Path,
+    staged: &Path,
+    templates: &Templates,
+    backups: &mut BackupStore,
 ) -> anyhow::Result<()> {
-    if bootanim_rc_path.exists() {
-        if !bootanim_rc_gz_path.exists() {
-            backup_bootanim(bootanim_rc_path.clone(), bootanim_rc_gz_path.clone())?;
-        }
-    } else if !bootanim_rc_gz_path.exists() && !has_overlay {
-        File::create(bootanim_rc_path.clone())?;
-        fs::write(bootanim_rc_path.clone(), BOOTANIM_RC)?;
-        backup_bootanim(bootanim_rc_path.clone(), bootanim_rc_gz_path.clone())?;
-    } else {
-        File::create(bootanim_rc_path.clone())?;
+    let bootanim_rc_path = rootfs.join(BOOTANIM_RC_PATH);
+    let bootanim_rc_gz_path = rootfs.join(BOOTANIM_RC_GZ_PATH);
+    let staged = staged.join("bootanim.rc");
+    if bootanim_rc_gz_path.exists() {
+        // backup left by wsu versions that replaced the whole file
+        gzip_decompress(
+            &bootanim_rc_gz_path.to_string_lossy(),
+            &bootanim_rc_path.to_string_lossy(),
+        )?;
+        fs::remove_file(&bootanim_rc_gz_path)?;
     }
-
-    msg_sub("Patching bootanim.rc");
-    let x = generate_random_string(15);
-    let y = generate_random_string(15);
-    let mut bootanim_rc_magisk = if !kitsune {
-        BOOTANIM_RC_MAGISK_NEW.replace("magisk_service_x", &x)
+    let mut rc = if bootanim_rc_path.exists() {
+        backups.backup(rootfs, BOOTANIM_RC_PATH)?;
+        let mut rc = read_rc(&bootanim_rc_path)?;
+        rc.remove_added()?;
+        rc
+    } else if staged.exists() {
+        let mut rc = read_rc(&staged)?;
+        rc.remove_added()?;
+        rc
     } else {
-        BOOTANIM_RC_MAGISK.replace("magisk_service_x", &x)
+        RcFile::parse(&templates.bootanim_rc)?
     };
-    bootanim_rc_magisk = bootanim_rc_magisk.replace("magisk_service_y", &y);
-    fs::write(bootanim_rc_path, bootanim_rc_magisk)?;
+    remove_check(staged)?;
+
+    report.step("Patching bootanim.rc");
+    rc.merge(&RcFile::parse(&magisk_rc_template(templates))?)
+        .map_err(|e| anyhow!("Couldn't patch bootanim.rc: {}", e))?;
+    fs::write(bootanim_rc_path, rc.serialize())?;
     Ok(())
 }
 
 pub fn restore_bootanim(
-    bootanim_rc_path: PathBuf,
-    bootanim_rc_gz_path: PathBuf,
+    report: &dyn Reporter,
+    rootfs: &Path,
     has_overlay: bool,
+    backups: &mut BackupStore,
 ) -> anyhow::Result<()> {
+    let bootanim_rc_path = rootfs.join(BOOTANIM_RC_PATH);
+    let bootanim_rc_gz_path = rootfs.join(BOOTANIM_RC_GZ_PATH);
     if bootanim_rc_gz_path.exists() {
         remove_check(bootanim_rc_path.clone())?;
-        msg_sub("Restoring bootanim.rc");
+        report.step("Restoring bootanim.rc");
         gzip_decompress(
             &bootanim_rc_gz_path.to_string_lossy(),
             &bootanim_rc_path.to_string_lossy(),
         )?;
         fs::remove_file(bootanim_rc_gz_path)?;
+    } else if backups.restore(rootfs, BOOTANIM_RC_PATH)? {
+        report.step("Restoring bootanim.rc");
     } else if bootanim_rc_path.exists() {
         if has_overlay {
             fs::remove_file(bootanim_rc_path.clone())?;
         } else {
-            msg_sub("Restoring bootanim.rc");
-            fs::write(bootanim_rc_path.clone(), BOOTANIM_RC)?;
+            let mut rc = read_rc(&bootanim_rc_path)?;
+            if rc.remove_added()? {
+                report.step("Restoring bootanim.rc");
+                fs::write(&bootanim_rc_path, rc.serialize())?;
+            }
         }
     }
     Ok(())
 }
 
 fn inject_zygote_restart<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
-    let input_string = fs::read_to_string(&path)?;
-    if !input_string
-        .contains("    exec u:r:magisk:s0 0 0 -- /debug_ramdisk/magisk --zygote-restart")
-    {
-        let input = fs::File::open(&path)?;
-        let reader = BufReader::new(input);
-        let mut output = Vec::new();
-        for line in reader.lines() {
-            let line = line?;
-            output.push(line.clone());
-
-            if line.contains("service zygote ") {
-                output.push(
-                    "    exec u:r:magisk:s0 0 0 -- /debug_ramdisk/magisk --zygote-restart"
-                        .to_string(),
-                );
-            }
-        }
-
-        fs::write(path, output.join("\n"))?;
-    }
-
+    let path = path.as_ref();
+    let mut rc = read_rc(path)?;
+    rc.remove_added()?;
+    rc.add_to_service("zygote", ZYGOTE_RESTART_EXEC)
+        .map_err(|e| anyhow!("Couldn't patch {}: {}", path.to_string_lossy(), e))?;
+    fs::write(path, rc.serialize())?;
     Ok(())
 }
 
-pub fn restore_init_zygote(rootfs: PathBuf, has_overlay: bool) -> anyhow::Result<()> {
+pub fn restore_init_zygote(
+    report: &dyn Reporter,
+    rootfs: PathBuf,
+    has_overlay: bool,
+    backups: &mut BackupStore,
+) -> anyhow::Result<()> {
     let mut msg = false;
-    let zygotes = [
-        ("init.zygote32.rc", "init.zygote32.rc.gz"),
-        ("init.zygote64_32.rc", "init.zygote64_32.rc.gz"),
-    ];
 
-    for (plain, gz) in zygotes {
-        let plain_path = rootfs.join(format!("system/etc/init/hw/{}", plain));
-        let gz_path = rootfs.join(format!("system/etc/init/hw/{}", gz));
+    for plain in ZYGOTE_RCS {
+        let path = format!("system/etc/init/hw/{}", plain);
+        let plain_path = rootfs.join(&path);
+        let gz_path = rootfs.join(format!("{}.gz", path));
+        if !gz_path.exists() && backups.restore(&rootfs, &path)? {
+            msg = true;
+            continue;
+        }
 
-        if has_overlay || gz_path.exists() {
-            if remove_check(plain_path.clone())? {
-                msg = true;
-            }
+        if (has_overlay || gz_path.exists()) && remove_check(plain_path.clone())? {
+            msg = true;
         }
 
         if gz_path.exists() {
             msg = true;
             gzip_decompress(&gz_path.to_string_lossy(), &plain_path.to_string_lossy())?;
             remove_check(gz_path)?;
+        } else if plain_path.exists() {
+            let mut rc = read_rc(&plain_path)?;
+            if rc.remove_added()? {
+                msg = true;
+                fs::write(&plain_path, rc.serialize())?;
+            }
         }
     }
 
     if msg {
-        msg_sub("Restoring init zygote");
+        report.step("Restoring init zygote");
     }
 
     Ok(())
 }
 
-pub fn patch_init_zygote(rootfs: PathBuf, waydroid_data: PathBuf) -> anyhow::Result<()> {
+pub fn patch_init_zygote(
+    report: &dyn Reporter,
+    rootfs: PathBuf,
+    staged: &Path,
+    zygotes: &[&str],
+    backups: &mut BackupStore,
+) -> anyhow::Result<()> {
     create_dir_check(rootfs.clone().join("system/etc/init/hw"), false)?;
 
-    let zygotes = [
-        ("init.zygote32.rc", "init.zygote32.rc.gz"),
-        ("init.zygote64_32.rc", "init.zygote64_32.rc.gz"),
-    ];
-
-    msg_sub("Injecting zygote restart");
-    for (plain, gz) in zygotes {
-        let plain_path = rootfs.join(format!("system/etc/init/hw/{}", plain));
-        let plain_path_data = waydroid_data.join("local/tmp").join(plain);
-        let gz_path = rootfs.join(format!("system/etc/init/hw/{}", gz));
-        if !plain_path.exists() {
+    report.step("Injecting zygote restart");
+    for plain in zygotes {
+        let path = format!("system/etc/init/hw/{}", plain);
+        let plain_path = rootfs.join(&path);
+        let plain_path_data = staged.join(plain);
+        let gz_path = rootfs.join(format!("{}.gz", path));
+        if gz_path.exists() {
+            // backup left by wsu versions that didn't mark what they added
+            gzip_decompress(&gz_path.to_string_lossy(), &plain_path.to_string_lossy())?;
+            remove_check(gz_path)?;
+        }
+        if plain_path.exists() {
+            backups.backup(&rootfs, &path)?;
+        } else {
             fs::copy(&plain_path_data, plain_path.clone())?;
-        } else if !gz_path.exists() {
-            msg_sub(&format!("Backing up \'{}\'", plain));
-            gzip_compress(&plain_path.to_string_lossy(), &gz_path.to_string_lossy())?;
         }
 
         set_selinux_attr(
             &plain_path.to_string_lossy(),
-            "security.selinux",
+            SELINUX_XATTR,
             "u:object_r:system_file:s0",
         )?;
         inject_zygote_restart(&plain_path)?;
+    }
 
-        fs::remove_file(plain_path_data)?;
+    for plain in ZYGOTE_RCS {
+        remove_check(staged.join(plain))?;
     }
     Ok(())
 }
 
-pub fn check_uninstall_manager() -> anyhow::Result<()> {
-    let packages = waydroid_su(vec!["pm", "list", "packages"], false)?;
+pub fn check_uninstall_manager(ctx: &Context) -> anyhow::Result<()> {
+    let packages = waydroid_su(ctx, vec!["pm", "list", "packages"], false)?;
     if packages.contains("com.topjohnwu.magisk") {
-        waydroid_su(vec!["pm", "uninstall", "com.topjohnwu.magisk"], false)?;
+        waydroid_su(ctx, vec!["pm", "uninstall", "com.topjohnwu.magisk"], false)?;
     }
     if packages.contains("io.github.huskydg.magisk") {
-        waydroid_su(vec!["pm", "uninstall", "io.github.huskydg.magisk;"], false)?;
+        waydroid_su(
+            ctx,
+            vec!["pm", "uninstall", "io.github.huskydg.magisk;"],
+            false,
+        )?;
     }
     Ok(())
 }
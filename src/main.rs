@@ -1,31 +1,55 @@
 use crate::cli::*;
-use crate::install::{install, remove, setup, update};
-use crate::magisk::Magisk;
-use crate::print::{msg_err, msg_regular, print_modules, print_status, print_superuser};
-use crate::utils::{
-    command_exists, get_arch, is_mounted_at, is_waydroid_initialized, root, umount_system,
+use crate::output::{Recorder, ReportMode, reporter};
+use crate::print::{
+    msg_regular, print_applet, print_backups, print_changes, print_denylist, print_doctor,
+    print_history, print_labels, print_modules, print_packages, print_result, print_sepolicy_diff,
+    print_sepolicy_rules, print_status, print_superuser,
 };
-use anyhow::{Ok, anyhow};
-use clap::Parser;
-use std::env::temp_dir;
+use anyhow::Ok;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use serde_json::json;
+use std::env;
+use std::process;
 use std::result::Result::Ok as OtherOk;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use wsu::backup::{BackupStore, install_scope};
+use wsu::boot::{BootStage, wait_for};
+use wsu::context::{Context, ContextArgs};
+use wsu::error::WsuError;
+use wsu::history::{self, HistoryEntry};
+use wsu::image::{patch_image, unpatch_image};
+use wsu::install::{
+    InstallMode, install, relabel_selinux, remove, repair, restore_backup, setup, update,
+};
+use wsu::magisk::Magisk;
+use wsu::report::Reporter;
+use wsu::sepolicy::{add_rule, remove_rule};
+use wsu::state::{apply, export};
+use wsu::{get_running_abi, root};
 
 mod cli;
-mod constants;
-mod container;
-mod install;
-mod magisk;
-mod magisk_files;
+mod output;
 mod print;
-mod selinux;
-mod utils;
 
 macro_rules! magisk_or_exit {
-    () => {
-        match Magisk::new() {
+    ($ctx:expr) => {
+        match Magisk::new($ctx) {
             OtherOk(m) => m,
             Err(e) => {
-                msg_err(&e.to_string());
+                $ctx.report().fail(&e);
+                return Ok(());
+            }
+        }
+    };
+}
+
+macro_rules! abi_or_exit {
+    ($ctx:expr, $abi:expr) => {
+        match get_running_abi($ctx, $abi.as_deref()) {
+            OtherOk(abi) => abi,
+            Err(e) => {
+                $ctx.report().fail(&e);
                 return Ok(());
             }
         }
@@ -33,192 +57,359 @@ macro_rules! magisk_or_exit {
 }
 
 macro_rules! try_run {
-    ($expr:expr) => {
+    ($ctx:expr, $expr:expr) => {
         if let Err(e) = $expr {
-            msg_err(&e.to_string());
+            $ctx.report().fail(&e);
         }
     };
 }
 
 macro_rules! try_run_or_exit {
-    ($expr:expr) => {
+    ($ctx:expr, $expr:expr) => {
         if let Err(e) = $expr {
-            msg_err(&e.to_string());
+            $ctx.report().fail(&e);
             return Ok(());
         }
     };
 }
 
-fn preflight() -> anyhow::Result<()> {
-    if !command_exists("waydroid") {
-        return Err(anyhow!("Waydroid is not installed on your system!"));
-    }
-    if !is_waydroid_initialized() {
-        return Err(anyhow!("Your Waydroid is not initialized!"));
-    }
-    if !root() {
-        return Err(anyhow!("Needs to be ran as sudo!"));
-    }
-    Ok(())
-}
-
 fn kitsune_or_err(magisk: &Magisk, applet: &str) -> anyhow::Result<()> {
-    if !magisk.version().contains("kitsune") && !magisk.version().contains("v27.2-Waydroid") {
-        return Err(anyhow!(format!(
-            "{} - Is only available for Kitsune",
-            applet
-        )));
+    if !magisk.is_kitsune() {
+        return Err(WsuError::Unsupported {
+            feature: applet.to_string(),
+            flavor: "Kitsune",
+        }
+        .into());
     }
     Ok(())
 }
 
 fn magisk_or_err(magisk: &Magisk, applet: &str) -> anyhow::Result<()> {
-    if magisk.version().contains("kitsune") || magisk.version().contains("v27.2-Waydroid") {
-        return Err(anyhow!(format!(
-            "{} - Is only available for Magisk",
-            applet
-        )));
+    if magisk.is_kitsune() {
+        return Err(WsuError::Unsupported {
+            feature: applet.to_string(),
+            flavor: "Magisk",
+        }
+        .into());
     }
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+/// "module install" for `wsu module install foo.zip`.
+fn command_path(matches: &ArgMatches) -> String {
+    let mut path = Vec::new();
+    let mut matches = matches;
+    while let Some((name, sub)) = matches.subcommand() {
+        path.push(name);
+        matches = sub;
+    }
+    path.join(" ")
+}
 
-    try_run_or_exit!(preflight());
+fn run(command: Commands, ctx: &Context) -> anyhow::Result<()> {
+    match &command {
+        // the log is readable by everyone
+        Commands::History(_) => {}
+        Commands::Doctor
+        | Commands::Backup { .. }
+        | Commands::PatchImage(_)
+        | Commands::UnpatchImage(_) => {
+            if !root() {
+                ctx.report().fail(&WsuError::NotRoot.into());
+                return Ok(());
+            }
+        }
+        _ => try_run_or_exit!(ctx, ctx.preflight()),
+    }
 
-    match cli.command {
+    match command {
         Commands::Status => {
-            try_run!(print_status());
+            try_run!(ctx, print_status(ctx));
         }
         Commands::Install(args) => {
-            let (arch, arch_supported) = get_arch();
-            if arch_supported {
-                let apk_path = args.apk.unwrap_or_else(|| "".to_string());
-                if let Err(e) = install(arch, &apk_path, false, args.kitsune) {
-                    msg_err(&e.to_string());
-                    try_run!(remove(true, false));
-                }
+            let abi = abi_or_exit!(ctx, args.abi);
+            let apk_path = args.apk.unwrap_or_else(|| "".to_string());
+            if let Err(e) = install(ctx, &abi, &apk_path, InstallMode::Install, args.kitsune) {
+                ctx.report().fail(&e);
+                try_run!(ctx, remove(ctx, true, false));
             }
         }
-        Commands::Setup => try_run!(setup()),
+        Commands::Setup => try_run!(ctx, setup(ctx)),
         Commands::Remove => {
-            if let Err(e) = remove(false, false) {
-                msg_err(&e.to_string());
-                let mnt = temp_dir().join("waydroidsu/mnt");
-                let mounted = match is_mounted_at(&mnt.to_string_lossy()) {
-                    OtherOk(m) => m,
-                    Err(e) => {
-                        msg_err(&e.to_string());
-                        return Ok(());
-                    }
-                };
-                if mounted {
-                    try_run!(umount_system(false));
-                }
-            }
+            try_run!(ctx, remove(ctx, false, false));
         }
-        Commands::Update => {
-            let (arch, arch_supported) = get_arch();
-            if arch_supported {
-                try_run!(update(arch));
-            }
+        Commands::Update(args) => {
+            let abi = abi_or_exit!(ctx, args.abi);
+            try_run!(ctx, update(ctx, &abi));
+        }
+        Commands::Repair(args) => {
+            let abi = abi_or_exit!(ctx, args.abi);
+            try_run!(ctx, repair(ctx, &abi, args.force));
         }
         Commands::Module { command } => {
-            let mut magisk = magisk_or_exit!();
+            let mut magisk = magisk_or_exit!(ctx);
             match command {
-                ModuleCommand::List => try_run!(print_modules(magisk)),
-                ModuleCommand::Install(arg) => try_run!(magisk.install_module(&arg.module)),
-                ModuleCommand::Remove(arg) => try_run!(magisk.remove_module(&arg.module)),
-                ModuleCommand::Disable(arg) => try_run!(magisk.disable_module(&arg.module)),
-                ModuleCommand::Enable(arg) => try_run!(magisk.enable_module(&arg.module)),
+                ModuleCommand::List => try_run!(ctx, print_modules(magisk)),
+                ModuleCommand::Install(arg) => {
+                    try_run!(ctx, magisk.install_module(&arg.module, arg.force))
+                }
+                ModuleCommand::Remove(arg) => try_run!(ctx, magisk.remove_module(&arg.module)),
+                ModuleCommand::Disable(arg) => try_run!(ctx, magisk.disable_module(&arg.module)),
+                ModuleCommand::Enable(arg) => try_run!(ctx, magisk.enable_module(&arg.module)),
             }
         }
         Commands::Magiskhide { command } => {
-            let mut magisk = magisk_or_exit!();
-            try_run_or_exit!(kitsune_or_err(&magisk, "magiskhide"));
+            let mut magisk = magisk_or_exit!(ctx);
+            try_run_or_exit!(ctx, kitsune_or_err(&magisk, "magiskhide"));
             match command {
                 MagiskhideCommand::Status => {
-                    try_run!(magisk.cmd("magiskhide", vec!["status"], false))
+                    try_run!(ctx, print_applet(&mut magisk, "magiskhide", vec!["status"]))
                 }
                 MagiskhideCommand::Enable => {
-                    try_run!(magisk.cmd("magiskhide", vec!["enable"], false))
+                    try_run!(ctx, print_applet(&mut magisk, "magiskhide", vec!["enable"]))
                 }
                 MagiskhideCommand::Disable => {
-                    try_run!(magisk.cmd("magiskhide", vec!["disable"], false))
+                    try_run!(
+                        ctx,
+                        print_applet(&mut magisk, "magiskhide", vec!["disable"])
+                    )
                 }
                 MagiskhideCommand::Sulist { command: Some(sub) } => match sub {
                     SulistSubCommand::Enable => {
-                        try_run!(magisk.cmd("magiskhide", vec!["sulist", "enable"], false))
+                        try_run!(
+                            ctx,
+                            print_applet(&mut magisk, "magiskhide", vec!["sulist", "enable"])
+                        )
                     }
                     SulistSubCommand::Disable => {
-                        try_run!(magisk.cmd("magiskhide", vec!["sulist", "disable"], false))
+                        try_run!(
+                            ctx,
+                            print_applet(&mut magisk, "magiskhide", vec!["sulist", "disable"])
+                        )
                     }
                 },
                 MagiskhideCommand::Sulist { command: None } => {
-                    try_run!(magisk.cmd("magiskhide", vec!["sulist"], false))
+                    try_run!(ctx, print_applet(&mut magisk, "magiskhide", vec!["sulist"]))
+                }
+                MagiskhideCommand::Ls => {
+                    try_run!(ctx, print_applet(&mut magisk, "magiskhide", vec!["ls"]))
                 }
-                MagiskhideCommand::Ls => try_run!(magisk.cmd("magiskhide", vec!["ls"], false)),
                 MagiskhideCommand::Add(arg) => {
-                    try_run!(magisk.cmd("magiskhide", vec!["add", &arg.pkg], false))
+                    try_run!(
+                        ctx,
+                        print_applet(&mut magisk, "magiskhide", vec!["add", &arg.pkg])
+                    )
                 }
                 MagiskhideCommand::Rm(arg) => {
-                    try_run!(magisk.cmd("magiskhide", vec!["rm", &arg.pkg], false))
+                    try_run!(
+                        ctx,
+                        print_applet(&mut magisk, "magiskhide", vec!["rm", &arg.pkg])
+                    )
                 }
             }
         }
         Commands::Denylist { command } => {
-            let mut magisk = magisk_or_exit!();
-            try_run_or_exit!(magisk_or_err(&magisk, "denylist"));
+            let mut magisk = magisk_or_exit!(ctx);
+            try_run_or_exit!(ctx, magisk_or_err(&magisk, "denylist"));
             match command {
                 DenylistCommand::Status => {
-                    try_run!(magisk.cmd("--denylist", vec!["status"], false))
+                    try_run!(ctx, print_applet(&mut magisk, "--denylist", vec!["status"]))
                 }
                 DenylistCommand::Enable => {
-                    try_run!(magisk.cmd("--denylist", vec!["enable"], false))
+                    try_run!(ctx, print_applet(&mut magisk, "--denylist", vec!["enable"]))
                 }
                 DenylistCommand::Disable => {
-                    try_run!(magisk.cmd("--denylist", vec!["disable"], false))
+                    try_run!(
+                        ctx,
+                        print_applet(&mut magisk, "--denylist", vec!["disable"])
+                    )
                 }
-                DenylistCommand::Ls => try_run!(magisk.cmd("--denylist", vec!["ls"], false)),
+                DenylistCommand::Ls => try_run!(ctx, print_denylist(magisk)),
                 DenylistCommand::Add(arg) => {
-                    try_run!(magisk.cmd("--denylist", vec!["add", &arg.pkg], false))
-                }
-                DenylistCommand::Rm(arg) => {
-                    try_run!(magisk.cmd("--denylist", vec!["rm", &arg.pkg], false))
+                    try_run!(ctx, magisk.denylist_add(&arg.target, arg.all_processes))
                 }
+                DenylistCommand::Rm(arg) => try_run!(ctx, magisk.denylist_rm(&arg.target)),
             }
         }
         Commands::Zygisk { command } => {
-            let mut magisk = magisk_or_exit!();
+            let mut magisk = magisk_or_exit!(ctx);
             match command {
                 ZygiskCommand::Status => {
                     let zygisk = match magisk.get_zygisk() {
                         OtherOk(z) => z,
                         Err(e) => {
-                            msg_err(&e.to_string());
+                            ctx.report().fail(&e);
                             return Ok(());
                         }
                     };
-                    if zygisk {
-                        msg_regular("Zygisk is enabled");
-                    } else {
-                        msg_regular("Zygisk is disabled");
+                    if !print_result(|| json!({"enabled": zygisk})) {
+                        msg_regular(if zygisk {
+                            "Zygisk is enabled"
+                        } else {
+                            "Zygisk is disabled"
+                        });
                     }
                 }
-                ZygiskCommand::Enable => try_run!(magisk.set_zygisk(true)),
-                ZygiskCommand::Disable => try_run!(magisk.set_zygisk(false)),
+                ZygiskCommand::Enable => try_run!(ctx, magisk.set_zygisk(true)),
+                ZygiskCommand::Disable => try_run!(ctx, magisk.set_zygisk(false)),
             }
         }
         Commands::Superuser { command } => {
-            let mut magisk = magisk_or_exit!();
+            let mut magisk = magisk_or_exit!(ctx);
             match command {
-                SuperuserCommand::Allow(arg) => try_run!(magisk.superuser_manage(&arg.pkg, true)),
-                SuperuserCommand::Deny(arg) => try_run!(magisk.superuser_manage(&arg.pkg, false)),
-                SuperuserCommand::List => try_run!(print_superuser(magisk)),
+                SuperuserCommand::Allow(arg) => {
+                    try_run!(ctx, magisk.superuser_manage(&arg.pkg, true))
+                }
+                SuperuserCommand::Deny(arg) => {
+                    try_run!(ctx, magisk.superuser_manage(&arg.pkg, false))
+                }
+                SuperuserCommand::List => try_run!(ctx, print_superuser(magisk)),
+            }
+        }
+        Commands::Doctor => try_run!(ctx, print_doctor(ctx)),
+        Commands::Selinux { command } => match command {
+            SelinuxCommand::Check => try_run!(ctx, print_labels(ctx)),
+            SelinuxCommand::Relabel => try_run!(ctx, relabel_selinux(ctx)),
+        },
+        Commands::Sepolicy { command } => match command {
+            SepolicyCommand::Add(args) => {
+                try_run!(ctx, add_rule(ctx, &args.rule.join(" "), args.live))
             }
+            SepolicyCommand::Rm(arg) => try_run!(ctx, remove_rule(ctx, &arg.rule.join(" "))),
+            SepolicyCommand::Ls => try_run!(ctx, print_sepolicy_rules(ctx)),
+            SepolicyCommand::Diff => try_run!(ctx, print_sepolicy_diff(ctx)),
+        },
+        Commands::Backup { command } => match command {
+            BackupCommand::List | BackupCommand::Verify => {
+                // list backups even when the current image can't be found
+                let scope = install_scope(ctx).unwrap_or_default();
+                let store = match BackupStore::open(ctx, &scope) {
+                    OtherOk(store) => store,
+                    Err(e) => {
+                        ctx.report().fail(&e);
+                        return Ok(());
+                    }
+                };
+                try_run!(
+                    ctx,
+                    print_backups(&store, matches!(command, BackupCommand::Verify))
+                );
+            }
+            BackupCommand::Restore(arg) => {
+                try_run_or_exit!(ctx, ctx.preflight());
+                try_run!(ctx, restore_backup(ctx, &arg.path));
+            }
+        },
+        Commands::PatchImage(args) => try_run!(
+            ctx,
+            patch_image(
+                ctx,
+                &args.system,
+                &args.vendor,
+                &args.apk,
+                args.abi.as_deref()
+            )
+        ),
+        Commands::UnpatchImage(args) => {
+            try_run!(ctx, unpatch_image(ctx, &args.system, &args.vendor))
         }
+        Commands::Packages(args) => {
+            try_run!(
+                ctx,
+                print_packages(ctx, args.filter.as_deref(), args.user_only)
+            );
+        }
+        Commands::Export(args) => {
+            let magisk = magisk_or_exit!(ctx);
+            match export(magisk, args.output.clone()) {
+                OtherOk(json) if args.output.is_none() => {
+                    // the export is a JSON document already
+                    let state = serde_json::from_str::<serde_json::Value>(&json);
+                    if !print_result(|| state.unwrap_or_default()) {
+                        println!("{}", json);
+                    }
+                }
+                OtherOk(_) => {}
+                Err(e) => ctx.report().fail(&e),
+            }
+        }
+        Commands::Apply(args) => {
+            let magisk = magisk_or_exit!(ctx);
+            match apply(magisk, &args.file, args.dry_run) {
+                OtherOk(changes) if args.dry_run => print_changes(&changes),
+                OtherOk(_) => {}
+                Err(e) => ctx.report().fail(&e),
+            }
+        }
+        Commands::Wait(args) => {
+            let (stage, done) = match args.stage {
+                WaitFor::Boot => (BootStage::Boot, "Android has booted"),
+                WaitFor::Magiskd => (BootStage::Magiskd, "Magisk daemon is running"),
+            };
+            try_run_or_exit!(ctx, wait_for(ctx, stage, Duration::from_secs(args.timeout)));
+            ctx.report().finish(done);
+        }
+        Commands::History(args) => try_run!(
+            ctx,
+            print_history(
+                ctx,
+                args.user.as_deref(),
+                args.command.as_deref(),
+                args.failed,
+                args.limit
+            )
+        ),
     }
 
     Ok(())
 }
+
+fn main() -> anyhow::Result<()> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let report = reporter(if cli.quiet {
+        ReportMode::Quiet
+    } else if cli.verbose {
+        ReportMode::Verbose
+    } else if cli.json {
+        ReportMode::Json
+    } else {
+        ReportMode::Pretty
+    });
+    let recorder = Arc::new(Recorder::new(report, cli.yes));
+    let ctx = match Context::load(ContextArgs {
+        waydroid_dir: cli.waydroid_dir,
+        lxc_path: cli.lxc_path,
+        container: cli.container,
+        data_dir: cli.data_dir,
+        state_dir: cli.state_dir,
+        reporter: Some(recorder.clone()),
+    }) {
+        OtherOk(ctx) => ctx,
+        Err(e) => {
+            recorder.fail(&e);
+            process::exit(recorder.exit_code());
+        }
+    };
+
+    let mutating = cli.command.is_mutating();
+    let started = SystemTime::now();
+    let timer = Instant::now();
+    run(cli.command, &ctx)?;
+
+    // only root can write the log, and every mutating command needs root anyway
+    if mutating && root() {
+        let entry = HistoryEntry::new(
+            &command_path(&matches),
+            env::args().skip(1).collect(),
+            started,
+            timer.elapsed(),
+            recorder.first_error(),
+        );
+        try_run!(ctx, history::append(&ctx.history, &entry));
+    }
+    match recorder.exit_code() {
+        0 => Ok(()),
+        code => process::exit(code),
+    }
+}
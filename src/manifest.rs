@@ -0,0 +1,197 @@
+use anyhow::{Ok, anyhow};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use zip::read::ZipArchive;
+
+const RES_STRING_POOL_TYPE: u16 = 0x0001;
+const RES_XML_TYPE: u16 = 0x0003;
+const RES_XML_START_ELEMENT_TYPE: u16 = 0x0102;
+const RES_XML_RESOURCE_MAP_TYPE: u16 = 0x0180;
+
+const UTF8_FLAG: u32 = 1 << 8;
+const NO_INDEX: u32 = 0xFFFF_FFFF;
+const TYPE_STRING: u8 = 0x03;
+const TYPE_INT_BOOLEAN: u8 = 0x12;
+
+pub const ATTR_LABEL: u32 = 0x0101_0001;
+
+pub struct Attribute {
+    pub name: String,
+    pub res_id: u32,
+    pub value: String,
+    pub string: bool,
+}
+
+pub struct Element {
+    pub name: String,
+    pub attrs: Vec<Attribute>,
+}
+
+impl Element {
+    /// The value of an attribute, skipping values that are resource references.
+    pub fn string_attr(&self, name: &str, res_id: u32) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|a| a.string && (a.name == name || (res_id != 0 && a.res_id == res_id)))
+            .map(|a| a.value.as_str())
+    }
+}
+
+/// The binary AndroidManifest.xml of an installed APK, read from the host.
+pub struct Manifest {
+    pub elements: Vec<Element>,
+}
+
+impl Manifest {
+    pub fn from_apk(apk: &Path) -> anyhow::Result<Self> {
+        let file = File::open(apk)?;
+        let mut archive = ZipArchive::new(BufReader::new(file))?;
+        let mut entry = archive
+            .by_name("AndroidManifest.xml")
+            .map_err(|_| anyhow!("{} has no AndroidManifest.xml", apk.to_string_lossy()))?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self {
+            elements: parse_axml(data)?,
+        })
+    }
+}
+
+fn read_u16(data: &[u8], off: usize) -> anyhow::Result<u16> {
+    data.get(off..off + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow!("Truncated binary XML"))
+}
+
+fn read_u32(data: &[u8], off: usize) -> anyhow::Result<u32> {
+    data.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| anyhow!("Truncated binary XML"))
+}
+
+fn parse_string_pool(data: &[u8], start: usize) -> anyhow::Result<Vec<String>> {
+    let count = read_u32(data, start + 8)? as usize;
+    let flags = read_u32(data, start + 16)?;
+    let strings_start = start + read_u32(data, start + 20)? as usize;
+    let header_size = read_u16(data, start + 2)? as usize;
+    let utf8 = flags & UTF8_FLAG != 0;
+
+    let mut strings = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut off = strings_start + read_u32(data, start + header_size + i * 4)? as usize;
+        let string = if utf8 {
+            // utf16 length first, then utf8 length, each 1 or 2 bytes
+            let mut len = 0;
+            for _ in 0..2 {
+                let first = *data
+                    .get(off)
+                    .ok_or_else(|| anyhow!("Truncated string pool"))?;
+                if first & 0x80 != 0 {
+                    let second = *data
+                        .get(off + 1)
+                        .ok_or_else(|| anyhow!("Truncated string pool"))?;
+                    len = (((first & 0x7f) as usize) << 8) | second as usize;
+                    off += 2;
+                } else {
+                    len = first as usize;
+                    off += 1;
+                }
+            }
+            let bytes = data
+                .get(off..off + len)
+                .ok_or_else(|| anyhow!("Truncated string pool"))?;
+            String::from_utf8_lossy(bytes).to_string()
+        } else {
+            let mut len = read_u16(data, off)? as usize;
+            off += 2;
+            if len & 0x8000 != 0 {
+                len = ((len & 0x7fff) << 16) | read_u16(data, off)? as usize;
+                off += 2;
+            }
+            let units = (0..len)
+                .map(|i| read_u16(data, off + i * 2))
+                .collect::<anyhow::Result<Vec<u16>>>()?;
+            String::from_utf16_lossy(&units)
+        };
+        strings.push(string);
+    }
+    Ok(strings)
+}
+
+fn parse_axml(data: &[u8]) -> anyhow::Result<Vec<Element>> {
+    if read_u16(data, 0)? != RES_XML_TYPE {
+        return Err(anyhow!("Not a binary XML file"));
+    }
+    let mut strings = Vec::new();
+    let mut res_ids = Vec::new();
+    let mut elements = Vec::new();
+
+    let string_at = |strings: &Vec<String>, idx: u32| -> String {
+        if idx == NO_INDEX {
+            String::new()
+        } else {
+            strings.get(idx as usize).cloned().unwrap_or_default()
+        }
+    };
+
+    let mut off = read_u16(data, 2)? as usize;
+    while off + 8 <= data.len() {
+        let chunk_type = read_u16(data, off)?;
+        let header_size = read_u16(data, off + 2)? as usize;
+        let chunk_size = read_u32(data, off + 4)? as usize;
+        if chunk_size < 8 {
+            return Err(anyhow!("Malformed binary XML chunk"));
+        }
+
+        match chunk_type {
+            RES_STRING_POOL_TYPE => strings = parse_string_pool(data, off)?,
+            RES_XML_RESOURCE_MAP_TYPE => {
+                res_ids = (off + header_size..off + chunk_size)
+                    .step_by(4)
+                    .map(|o| read_u32(data, o))
+                    .collect::<anyhow::Result<Vec<u32>>>()?;
+            }
+            RES_XML_START_ELEMENT_TYPE => {
+                let ext = off + header_size;
+                let name = string_at(&strings, read_u32(data, ext + 4)?);
+                let attr_start = read_u16(data, ext + 8)? as usize;
+                let attr_size = read_u16(data, ext + 10)? as usize;
+                let attr_count = read_u16(data, ext + 12)? as usize;
+
+                let mut attrs = Vec::with_capacity(attr_count);
+                for i in 0..attr_count {
+                    let a = ext + attr_start + i * attr_size;
+                    let name_idx = read_u32(data, a + 4)?;
+                    let raw = read_u32(data, a + 8)?;
+                    let data_type = *data.get(a + 15).ok_or_else(|| anyhow!("Truncated"))?;
+                    let value_data = read_u32(data, a + 16)?;
+                    let string = raw != NO_INDEX || data_type == TYPE_STRING;
+                    let value = if raw != NO_INDEX {
+                        string_at(&strings, raw)
+                    } else if data_type == TYPE_STRING {
+                        string_at(&strings, value_data)
+                    } else if data_type == TYPE_INT_BOOLEAN {
+                        (value_data != 0).to_string()
+                    } else {
+                        value_data.to_string()
+                    };
+                    attrs.push(Attribute {
+                        name: string_at(&strings, name_idx),
+                        res_id: res_ids.get(name_idx as usize).copied().unwrap_or(0),
+                        value,
+                        string,
+                    });
+                }
+                elements.push(Element { name, attrs });
+            }
+            _ => {}
+        }
+        off += chunk_size;
+    }
+    Ok(elements)
+}
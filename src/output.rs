@@ -0,0 +1,366 @@
+use crate::print::{msg_end, msg_err, msg_main, msg_regular, msg_sub, set_json};
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::{Value, json};
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use wsu::error::{EXIT_FAILURE, classify};
+use wsu::report::{Reporter, Target};
+
+/// Output format picked on the command line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportMode {
+    Pretty,
+    Quiet,
+    Verbose,
+    Json,
+}
+
+pub fn reporter(mode: ReportMode) -> Arc<dyn Reporter> {
+    match mode {
+        ReportMode::Pretty => Arc::new(Pretty::new(false)),
+        ReportMode::Verbose => Arc::new(Pretty::new(true)),
+        ReportMode::Quiet => Arc::new(Quiet),
+        ReportMode::Json => {
+            colored::control::set_override(false);
+            set_json(true);
+            Arc::new(JsonLines::default())
+        }
+    }
+}
+
+/// Colored terminal output, with the executed commands when verbose.
+pub struct Pretty {
+    verbose: bool,
+    bar: Mutex<Option<ProgressBar>>,
+}
+
+impl Pretty {
+    pub fn new(verbose: bool) -> Self {
+        Self {
+            verbose,
+            bar: Mutex::new(None),
+        }
+    }
+}
+
+impl Reporter for Pretty {
+    fn begin(&self, msg: &str) {
+        msg_main(msg);
+    }
+
+    fn step(&self, msg: &str) {
+        msg_sub(msg);
+    }
+
+    fn finish(&self, msg: &str) {
+        msg_end(msg);
+    }
+
+    fn info(&self, msg: &str) {
+        msg_regular(msg);
+    }
+
+    fn warn(&self, msg: &str) {
+        msg_err(msg);
+    }
+
+    fn error(&self, msg: &str) {
+        msg_err(msg);
+    }
+
+    fn command(&self, target: Target, argv: &[String]) {
+        if !self.verbose {
+            return;
+        }
+        let prompt = match target {
+            Target::Host => "$",
+            Target::Container => "#",
+        };
+        println!("   {}", format!("{} {}", prompt, argv.join(" ")).dimmed());
+    }
+
+    fn progress(&self, current: u64, total: u64) {
+        let mut bar = self.bar.lock().unwrap();
+        let bar = bar.get_or_insert_with(|| {
+            let bar = ProgressBar::new(total);
+            bar.set_style(
+                ProgressStyle::with_template(" [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            bar
+        });
+        bar.set_position(current);
+    }
+
+    fn progress_end(&self) {
+        if let Some(bar) = self.bar.lock().unwrap().take() {
+            bar.finish();
+        }
+    }
+
+    fn confirm(&self, question: &str) -> bool {
+        confirm(question)
+    }
+
+    fn choose(&self, question: &str, options: &[String]) -> Option<usize> {
+        choose(question, options)
+    }
+}
+
+/// Only errors.
+pub struct Quiet;
+
+impl Reporter for Quiet {
+    fn begin(&self, _msg: &str) {}
+    fn step(&self, _msg: &str) {}
+    fn finish(&self, _msg: &str) {}
+    fn info(&self, _msg: &str) {}
+
+    fn warn(&self, msg: &str) {
+        msg_err(msg);
+    }
+
+    fn error(&self, msg: &str) {
+        msg_err(msg);
+    }
+
+    fn command(&self, _target: Target, _argv: &[String]) {}
+    fn progress(&self, _current: u64, _total: u64) {}
+    fn progress_end(&self) {}
+
+    fn confirm(&self, question: &str) -> bool {
+        confirm(question)
+    }
+
+    fn choose(&self, question: &str, options: &[String]) -> Option<usize> {
+        choose(question, options)
+    }
+}
+
+/// One JSON object per line on stdout. Steps are numbered from 1 and every
+/// command and progress event carries the id of the step it belongs to.
+#[derive(Default)]
+pub struct JsonLines {
+    step: AtomicU64,
+    /// Last reported percentage, or MiB when the total is unknown, progress
+    /// is only sent when it changes.
+    reported: Mutex<Option<u64>>,
+}
+
+impl JsonLines {
+    fn emit(&self, mut event: Value) {
+        let step = self.step.load(Ordering::SeqCst);
+        if step > 0 && event.get("step").is_none() {
+            event["step"] = json!(step);
+        }
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "{}", event);
+        let _ = stdout.flush();
+    }
+}
+
+impl Reporter for JsonLines {
+    fn begin(&self, msg: &str) {
+        self.emit(json!({"event": "begin", "message": msg}));
+    }
+
+    fn step(&self, msg: &str) {
+        let step = self.step.fetch_add(1, Ordering::SeqCst) + 1;
+        self.emit(json!({"event": "step", "step": step, "message": msg}));
+    }
+
+    fn finish(&self, msg: &str) {
+        self.emit(json!({"event": "finish", "message": msg.trim()}));
+    }
+
+    fn info(&self, msg: &str) {
+        self.emit(json!({"event": "info", "message": msg}));
+    }
+
+    fn warn(&self, msg: &str) {
+        self.emit(json!({"event": "warning", "message": msg}));
+    }
+
+    fn error(&self, msg: &str) {
+        self.emit(json!({"event": "error", "message": msg}));
+    }
+
+    fn fail(&self, err: &anyhow::Error) {
+        let (id, code) = classify(err);
+        self.emit(json!({"event": "error", "id": id, "code": code, "message": err.to_string()}));
+    }
+
+    fn command(&self, target: Target, argv: &[String]) {
+        self.emit(json!({"event": "command", "target": target.as_str(), "argv": argv}));
+    }
+
+    fn progress(&self, current: u64, total: u64) {
+        let percent = (current * 100).checked_div(total);
+        let mark = Some(percent.unwrap_or(current >> 20));
+        let mut reported = self.reported.lock().unwrap();
+        if *reported == mark {
+            return;
+        }
+        *reported = mark;
+        let total = (total > 0).then_some(total);
+        self.emit(json!({
+            "event": "progress",
+            "current": current,
+            "total": total,
+            "percent": percent,
+        }));
+    }
+
+    fn progress_end(&self) {
+        *self.reported.lock().unwrap() = None;
+    }
+
+    /// Nobody reads stdin in JSON mode, the question is reported and declined.
+    fn confirm(&self, question: &str) -> bool {
+        self.emit(json!({"event": "prompt", "message": question, "answer": false}));
+        false
+    }
+
+    fn choose(&self, question: &str, options: &[String]) -> Option<usize> {
+        self.emit(json!({
+            "event": "prompt",
+            "message": question,
+            "options": options,
+            "answer": null,
+        }));
+        None
+    }
+}
+
+/// Passes everything on and remembers the failures, for the exit code and
+/// the history log. Confirmations are answered with yes when `--yes` was given.
+pub struct Recorder {
+    inner: Arc<dyn Reporter>,
+    /// Message and exit code of each failure.
+    failures: Mutex<Vec<(String, i32)>>,
+    yes: bool,
+}
+
+impl Recorder {
+    pub fn new(inner: Arc<dyn Reporter>, yes: bool) -> Self {
+        Self {
+            inner,
+            failures: Mutex::new(Vec::new()),
+            yes,
+        }
+    }
+
+    /// The first failure, later ones tend to come from cleaning up after it.
+    pub fn first_error(&self) -> Option<String> {
+        self.failures
+            .lock()
+            .unwrap()
+            .first()
+            .map(|(msg, _)| msg.clone())
+    }
+
+    /// The exit code of the first failure, 0 when there was none.
+    pub fn exit_code(&self) -> i32 {
+        self.failures
+            .lock()
+            .unwrap()
+            .first()
+            .map_or(0, |(_, code)| *code)
+    }
+}
+
+impl Reporter for Recorder {
+    fn begin(&self, msg: &str) {
+        self.inner.begin(msg);
+    }
+
+    fn step(&self, msg: &str) {
+        self.inner.step(msg);
+    }
+
+    fn finish(&self, msg: &str) {
+        self.inner.finish(msg);
+    }
+
+    fn info(&self, msg: &str) {
+        self.inner.info(msg);
+    }
+
+    fn warn(&self, msg: &str) {
+        self.inner.warn(msg);
+    }
+
+    fn error(&self, msg: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .push((msg.to_string(), EXIT_FAILURE));
+        self.inner.error(msg);
+    }
+
+    fn fail(&self, err: &anyhow::Error) {
+        let (_, code) = classify(err);
+        self.failures.lock().unwrap().push((err.to_string(), code));
+        self.inner.fail(err);
+    }
+
+    fn command(&self, target: Target, argv: &[String]) {
+        self.inner.command(target, argv);
+    }
+
+    fn progress(&self, current: u64, total: u64) {
+        self.inner.progress(current, total);
+    }
+
+    fn progress_end(&self) {
+        self.inner.progress_end();
+    }
+
+    fn confirm(&self, question: &str) -> bool {
+        self.yes || self.inner.confirm(question)
+    }
+
+    fn choose(&self, question: &str, options: &[String]) -> Option<usize> {
+        self.inner.choose(question, options)
+    }
+}
+
+fn confirm(question: &str) -> bool {
+    print!(
+        "[{}] {} {} ",
+        "WSU".blue().bold(),
+        question.bold(),
+        "[Y/n]".blue().bold()
+    );
+    let _ = io::stdout().flush();
+
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        return false;
+    }
+    println!();
+    input.trim().eq_ignore_ascii_case("y")
+}
+
+fn choose(question: &str, options: &[String]) -> Option<usize> {
+    if !io::stdin().is_terminal() {
+        return None;
+    }
+    println!("[{}] {}", "WSU".blue().bold(), question.bold());
+    for (i, option) in options.iter().enumerate() {
+        println!(" {} {}", format!("{})", i + 1).blue().bold(), option);
+    }
+    print!("{} ", "Select [1]:".bold());
+    let _ = io::stdout().flush();
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).ok()?;
+    let choice = match input.trim() {
+        "" => 1,
+        input => input.parse::<usize>().ok()?,
+    };
+    (1..=options.len()).contains(&choice).then(|| choice - 1)
+}
@@ -0,0 +1,197 @@
+use crate::context::Context;
+use crate::error::WsuError;
+use crate::magisk_files::waydroid_su;
+use crate::manifest::{ATTR_LABEL, Manifest};
+use crate::report::Reporter;
+use crate::utils::android_path_to_host;
+use anyhow::{Ok, anyhow};
+
+const PER_USER_RANGE: u32 = 100000;
+/// Score of a match that only has the query's letters in order.
+const SUBSEQUENCE_SCORE: u32 = 2;
+const SYSTEM_PREFIXES: [&str; 5] = [
+    "/system/",
+    "/product/",
+    "/vendor/",
+    "/system_ext/",
+    "/apex/",
+];
+
+pub struct Package {
+    pub name: String,
+    pub uid: u32,
+    pub user: u32,
+    pub system: bool,
+    pub path: String,
+    pub label: Option<String>,
+}
+
+impl Package {
+    pub fn app_id(&self) -> u32 {
+        self.uid % PER_USER_RANGE
+    }
+}
+
+pub struct PackageIndex {
+    packages: Vec<Package>,
+}
+
+impl PackageIndex {
+    pub fn fetch(ctx: &Context) -> anyhow::Result<Self> {
+        let out = waydroid_su(ctx, vec!["pm", "list", "packages", "-f", "-U"], true)?;
+        let mut packages = Vec::new();
+
+        for line in out.lines() {
+            let mut parts = line.split_whitespace();
+            let (Some(package_part), Some(uid_part)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let Some((path, name)) = package_part.trim_start_matches("package:").rsplit_once('=')
+            else {
+                continue;
+            };
+            let system = SYSTEM_PREFIXES.iter().any(|p| path.starts_with(p));
+
+            // multi-user setups list one uid per user, comma separated
+            for uid in uid_part.trim_start_matches("uid:").split(',') {
+                let Some(uid) = uid.trim().parse::<u32>().ok() else {
+                    continue;
+                };
+                packages.push(Package {
+                    name: name.to_string(),
+                    uid,
+                    user: uid / PER_USER_RANGE,
+                    system,
+                    path: path.to_string(),
+                    label: None,
+                });
+            }
+        }
+
+        if packages.is_empty() {
+            return Err(anyhow!("Couldn't get the list of installed packages!"));
+        }
+        packages.sort_by(|a, b| a.name.cmp(&b.name).then(a.user.cmp(&b.user)));
+        Ok(Self { packages })
+    }
+
+    pub fn packages(&self) -> &[Package] {
+        &self.packages
+    }
+
+    pub fn by_uid(&self, uid: u32) -> Vec<&Package> {
+        self.packages
+            .iter()
+            .filter(|p| p.uid == uid || (uid < PER_USER_RANGE && p.app_id() == uid))
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Package> {
+        self.packages.iter().find(|p| p.name == name)
+    }
+
+    pub fn load_labels(&mut self, ctx: &Context) {
+        for package in self.packages.iter_mut() {
+            let Some(path) = android_path_to_host(ctx, &package.path).ok() else {
+                continue;
+            };
+            package.label = Manifest::from_apk(&path).ok().and_then(|m| {
+                m.elements
+                    .iter()
+                    .find(|e| e.name == "application")
+                    .and_then(|e| e.string_attr("label", ATTR_LABEL))
+                    .map(|l| l.to_string())
+            });
+        }
+    }
+
+    /// Packages matching the query, best matches first.
+    pub fn search(&self, query: &str) -> Vec<&Package> {
+        self.scored(query).into_iter().map(|(_, p)| p).collect()
+    }
+
+    fn scored(&self, query: &str) -> Vec<(u32, &Package)> {
+        let query = query.to_ascii_lowercase();
+        let mut matches: Vec<(u32, &Package)> = self
+            .packages
+            .iter()
+            .filter(|p| p.user == 0)
+            .filter_map(|p| match_score(p, &query).map(|score| (score, p)))
+            .collect();
+        matches.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.name.cmp(&b.1.name)));
+        matches
+    }
+
+    /// Exact package name, or a fuzzy match confirmed or picked through `report`.
+    /// A unique match that contains the query is taken without asking unless
+    /// `confirm` is set, a subsequence match never is.
+    pub fn resolve(
+        &self,
+        report: &dyn Reporter,
+        query: &str,
+        confirm: bool,
+    ) -> anyhow::Result<&Package> {
+        if let Some(package) = self.get(query) {
+            return Ok(package);
+        }
+
+        let candidates = self.scored(query);
+        match candidates[..] {
+            [] => Err(anyhow!(format!(
+                "No installed package matches \'{}\'",
+                query
+            ))),
+            [(score, package)] => {
+                if (score < SUBSEQUENCE_SCORE && !confirm)
+                    || report.confirm(&format!("Use \'{}\' for \'{}\'?", package.name, query))
+                {
+                    return Ok(package);
+                }
+                Err(WsuError::InvalidInput(format!(
+                    "\'{}\' isn't a package name, did you mean \'{}\'?",
+                    query, package.name
+                ))
+                .into())
+            }
+            _ => {
+                let candidates: Vec<&Package> = candidates.into_iter().map(|(_, p)| p).collect();
+                let shown = &candidates[..candidates.len().min(10)];
+                let names: Vec<String> = shown.iter().map(|p| p.name.clone()).collect();
+                let question = format!("Multiple packages match \'{}\':", query);
+                if let Some(choice) = report.choose(&question, &names) {
+                    return Ok(shown[choice]);
+                }
+                Err(WsuError::InvalidInput(format!(
+                    "\'{}\' is ambiguous: {}",
+                    query,
+                    names.join(", ")
+                ))
+                .into())
+            }
+        }
+    }
+}
+
+fn match_score(package: &Package, query: &str) -> Option<u32> {
+    let name = package.name.to_ascii_lowercase();
+    let label = package
+        .label
+        .as_deref()
+        .map(|l| l.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    if name.ends_with(&format!(".{}", query)) || label == query {
+        Some(0)
+    } else if name.contains(query) || label.contains(query) {
+        Some(1)
+    } else if is_subsequence(query, &name) {
+        Some(SUBSEQUENCE_SCORE)
+    } else {
+        None
+    }
+}
+
+fn is_subsequence(query: &str, target: &str) -> bool {
+    let mut target = target.chars();
+    query.chars().all(|c| target.any(|t| t == c))
+}
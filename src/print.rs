@@ -1,13 +1,39 @@
-use crate::magisk::Magisk;
-use crate::magisk_files::get_status;
 use anyhow::Ok;
 use colored::*;
-pub fn msg_err(msg: &str) {
-    eprintln!("{}: {}", "error".red().bold(), msg);
+use serde_json::{Value, json};
+use std::result::Result::Ok as OtherOk;
+use std::sync::atomic::{AtomicBool, Ordering};
+use wsu::backup::{Backup, BackupStore};
+use wsu::context::Context;
+use wsu::doctor::{CheckStatus, run_doctor};
+use wsu::error::WsuError;
+use wsu::history::{self, HistoryEntry, Outcome};
+use wsu::install::{get_status, images_changed, inspect_rootfs};
+use wsu::magisk::Magisk;
+use wsu::packages::{Package, PackageIndex};
+use wsu::selinux::{SelinuxMode, audit_labels, container_selinux, host_selinux};
+use wsu::sepolicy::{RulesDiff, policy_diffs, read_rules};
+use wsu::state::Change;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Makes the print_* functions emit a single `result` event instead of text.
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
 }
 
-pub fn msg_err_str(msg: &str) -> String {
-    format!("{}: {}", "error".red().bold(), msg)
+/// Prints `data` as a `result` event in JSON mode. Returns whether it did,
+/// the text output is skipped then.
+pub fn print_result(data: impl FnOnce() -> Value) -> bool {
+    if !JSON.load(Ordering::Relaxed) {
+        return false;
+    }
+    println!("{}", json!({"event": "result", "data": data()}));
+    true
+}
+
+pub fn msg_err(msg: &str) {
+    eprintln!("{}: {}", "error".red().bold(), msg);
 }
 
 pub fn msg_main(msg: &str) {
@@ -32,16 +58,40 @@ pub fn msg_regular(msg: &str) {
 pub fn print_modules(mut magisk: Magisk) -> anyhow::Result<()> {
     let modules = magisk.get_list_modules()?;
     let path = magisk.modules_path.clone();
-    msg_regular(&format!("Modules: {}", modules.len().to_string().blue()));
-
+    let mut listed = Vec::new();
     for module in modules {
-        let module_path = path.join(&module);
-        let disabled = module_path.join("disable").exists();
+        let disabled = path.join(&module).join("disable").exists();
+        let missing = magisk.module_missing_zygisk_abis(&module)?;
+        listed.push((module, disabled, missing));
+    }
+    if print_result(|| {
+        json!(
+            listed
+                .iter()
+                .map(|(name, disabled, missing)| json!({
+                    "name": name,
+                    "disabled": disabled,
+                    "missing_zygisk_abis": missing,
+                }))
+                .collect::<Vec<_>>()
+        )
+    }) {
+        return Ok(());
+    }
+    msg_regular(&format!("Modules: {}", listed.len().to_string().blue()));
+
+    for (module, disabled, missing) in listed {
+        let mut line = module.clone();
         if disabled {
-            msg_sub(&format!("{module} (disabled)"));
-        } else {
-            msg_sub(&module);
+            line.push_str(" (disabled)");
         }
+        if !missing.is_empty() {
+            line.push_str(&format!(
+                " {}",
+                format!("(no zygisk libraries for {})", missing.join(", ")).red()
+            ));
+        }
+        msg_sub(&line);
     }
 
     Ok(())
@@ -49,6 +99,16 @@ pub fn print_modules(mut magisk: Magisk) -> anyhow::Result<()> {
 
 pub fn print_superuser(mut magisk: Magisk) -> anyhow::Result<()> {
     let superuser_list = magisk.get_superuser_list()?;
+    if print_result(|| {
+        json!(
+            superuser_list
+                .iter()
+                .map(|(pkg, verdict)| json!({"package": pkg, "verdict": verdict}))
+                .collect::<Vec<_>>()
+        )
+    }) {
+        return Ok(());
+    }
     for (pkg, verdict) in &superuser_list {
         if superuser_list[0] == (pkg.clone(), verdict) {
             msg_regular("Superuser:");
@@ -66,8 +126,422 @@ pub fn print_superuser(mut magisk: Magisk) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn print_status() -> anyhow::Result<()> {
-    let (daemon_running, version, zygisk) = get_status()?;
+pub fn print_denylist(mut magisk: Magisk) -> anyhow::Result<()> {
+    let denylist = magisk.get_denylist()?;
+    if print_result(|| {
+        json!(
+            denylist
+                .iter()
+                .map(|(pkg, processes)| json!({"package": pkg, "processes": processes}))
+                .collect::<Vec<_>>()
+        )
+    }) {
+        return Ok(());
+    }
+    msg_regular(&format!("Denylist: {}", denylist.len().to_string().blue()));
+    for (pkg, processes) in denylist {
+        msg_sub(&pkg);
+        for process in processes {
+            msg_sub(&format!("  {}", process));
+        }
+    }
+    Ok(())
+}
+
+/// Runs a Magisk applet and prints what it has to say.
+pub fn print_applet(magisk: &mut Magisk, applet: &str, args: Vec<&str>) -> anyhow::Result<()> {
+    let out = magisk.cmd(applet, args, false)?;
+    if print_result(|| json!({"output": out})) {
+        return Ok(());
+    }
+    if !out.is_empty() {
+        println!("{}", out.bold());
+    }
+    Ok(())
+}
+
+pub fn print_changes(changes: &[Change]) {
+    if print_result(|| json!(changes.iter().map(Change::describe).collect::<Vec<_>>())) {
+        return;
+    }
+    if changes.is_empty() {
+        return;
+    }
+    msg_regular(&format!("Changes: {}", changes.len().to_string().blue()));
+    for change in changes {
+        msg_sub(&change.describe());
+    }
+}
+
+pub fn print_doctor(ctx: &Context) -> anyhow::Result<()> {
+    let checks = run_doctor(ctx)?;
+    let failed = checks
+        .iter()
+        .filter(|check| matches!(check.status, CheckStatus::Fail))
+        .count();
+    if print_result(|| {
+        json!(
+            checks
+                .iter()
+                .map(|check| json!({
+                    "name": check.name,
+                    "status": match check.status {
+                        CheckStatus::Pass => "pass",
+                        CheckStatus::Warn => "warn",
+                        CheckStatus::Fail => "fail",
+                        CheckStatus::Skip => "skip",
+                    },
+                    "detail": check.detail,
+                    "hint": check.hint,
+                }))
+                .collect::<Vec<_>>()
+        )
+    }) {
+        if failed > 0 {
+            return Err(WsuError::ChecksFailed(failed).into());
+        }
+        return Ok(());
+    }
+    for check in &checks {
+        let status = match check.status {
+            CheckStatus::Pass => "PASS".blue().bold(),
+            CheckStatus::Warn => "WARN".yellow().bold(),
+            CheckStatus::Fail => "FAIL".red().bold(),
+            CheckStatus::Skip => "SKIP".dimmed().bold(),
+        };
+        println!("[{}] {}: {}", status, check.name.bold(), check.detail);
+        if !check.hint.is_empty() && !matches!(check.status, CheckStatus::Pass) {
+            msg_sub(&format!("  {} {}", "hint:".bold(), check.hint));
+        }
+    }
+    if failed > 0 {
+        return Err(WsuError::ChecksFailed(failed).into());
+    }
+    msg_end("No problems found.");
+    Ok(())
+}
+
+pub fn print_labels(ctx: &Context) -> anyhow::Result<()> {
+    let labels = inspect_rootfs(ctx, true, |rootfs| {
+        audit_labels(rootfs, &[rootfs, &ctx.rootfs()])
+    })?;
+    if print_result(|| {
+        json!(
+            labels
+                .iter()
+                .map(|label| json!({
+                    "path": label.path,
+                    "expected": label.expected,
+                    "actual": label.actual,
+                    "wrong": label.is_wrong(),
+                }))
+                .collect::<Vec<_>>()
+        )
+    }) {
+        return Ok(());
+    }
+    if labels.is_empty() {
+        msg_regular("No Magisk files found.");
+        return Ok(());
+    }
+
+    let mut wrong = 0;
+    for label in &labels {
+        let actual = label.actual.as_deref().unwrap_or("unlabeled");
+        let line = match &label.expected {
+            None => format!(
+                "{} | {} {}",
+                label.path,
+                actual,
+                "(no file context)".dimmed()
+            ),
+            Some(_) if !label.is_wrong() => format!("{} | {}", label.path, actual.blue()),
+            Some(expected) => {
+                wrong += 1;
+                format!(
+                    "{} | {} {}",
+                    label.path,
+                    actual.red(),
+                    format!("(expected {})", expected).red()
+                )
+            }
+        };
+        msg_sub(&line);
+    }
+
+    if wrong == 0 {
+        msg_end("All labels are correct.");
+    } else {
+        msg_end(&format!("{} file(s) mislabeled.", wrong));
+        msg_regular("Run 'sudo wsu selinux relabel' to fix them.");
+    }
+    Ok(())
+}
+
+pub fn print_sepolicy_rules(ctx: &Context) -> anyhow::Result<()> {
+    let rules = read_rules(ctx)?;
+    if print_result(|| json!(rules)) {
+        return Ok(());
+    }
+    msg_regular(&format!("Rules: {}", rules.len().to_string().blue()));
+    for rule in rules {
+        msg_sub(&rule);
+    }
+    Ok(())
+}
+
+pub fn print_sepolicy_diff(ctx: &Context) -> anyhow::Result<()> {
+    let print_rules = |title: &str, diff: &RulesDiff| {
+        if diff.is_empty() {
+            return;
+        }
+        msg_sub(&format!(
+            "{} ({}, {})",
+            title.bold(),
+            format!("+{}", diff.added.len()).blue(),
+            format!("-{}", diff.removed.len()).red()
+        ));
+        for rule in &diff.added {
+            msg_sub(&format!("  {}", format!("+ {}", rule).blue()));
+        }
+        for rule in &diff.removed {
+            msg_sub(&format!("  {}", format!("- {}", rule).red()));
+        }
+    };
+
+    let diffs = policy_diffs(ctx)?;
+    let rules_json = |diff: &RulesDiff| json!({"added": diff.added, "removed": diff.removed});
+    if print_result(|| {
+        json!(
+            diffs
+                .iter()
+                .map(|(title, diff)| json!({
+                    "policy": title,
+                    "allow": rules_json(&diff.allow),
+                    "types": rules_json(&diff.types),
+                    "attributes": rules_json(&diff.attributes),
+                    "other": rules_json(&diff.other),
+                }))
+                .collect::<Vec<_>>()
+        )
+    }) {
+        return Ok(());
+    }
+
+    for (title, diff) in diffs {
+        msg_regular(&format!("Policy {}:", title));
+        let sections = [
+            ("Allow rules", &diff.allow),
+            ("Types", &diff.types),
+            ("Attributes", &diff.attributes),
+            ("Other rules", &diff.other),
+        ];
+        if sections.iter().all(|(_, diff)| diff.is_empty()) {
+            msg_sub("No changes");
+        }
+        for (name, rules) in sections {
+            print_rules(name, rules);
+        }
+    }
+    Ok(())
+}
+
+pub fn print_backups(store: &BackupStore, verify: bool) -> anyhow::Result<()> {
+    let mut backups: Vec<&Backup> = store.all().iter().collect();
+    backups.sort_by(|a, b| a.scope.cmp(&b.scope));
+    if print_result(|| {
+        json!(
+            backups
+                .iter()
+                .map(|backup| {
+                    let mut value = json!(backup);
+                    value["current"] = json!(backup.scope == store.scope());
+                    if verify {
+                        value["error"] = json!(store.verify(backup).err().map(|e| e.to_string()));
+                    }
+                    value
+                })
+                .collect::<Vec<_>>()
+        )
+    }) {
+        return Ok(());
+    }
+    msg_regular(&format!("Backups: {}", backups.len().to_string().blue()));
+
+    let mut damaged = 0;
+    let mut scope = "";
+    for backup in backups {
+        if backup.scope != scope {
+            scope = &backup.scope;
+            let current = if scope == store.scope() {
+                " (current)"
+            } else {
+                ""
+            };
+            msg_sub(&format!("{}{}", scope.bold(), current));
+        }
+        let mut line = format!(
+            "  {} | {} KiB | {:04o} {}:{} | {} | {}",
+            backup.path,
+            backup.size.div_ceil(1024),
+            backup.mode,
+            backup.uid,
+            backup.gid,
+            &backup.sha1[..backup.sha1.len().min(12)],
+            format_date(backup.created)
+        );
+        if verify {
+            match store.verify(backup) {
+                OtherOk(()) => line.push_str(&format!(" {}", "OK".blue())),
+                Err(e) => {
+                    damaged += 1;
+                    line.push_str(&format!(" {}", format!("FAIL ({})", e).red()));
+                }
+            }
+        }
+        msg_sub(&line);
+    }
+
+    if verify {
+        if damaged == 0 {
+            msg_end("All backups are intact.");
+        } else {
+            msg_end(&format!("{} backup(s) are damaged.", damaged));
+        }
+    }
+    Ok(())
+}
+
+/// Formats a unix timestamp as a UTC date.
+fn format_date(secs: u64) -> String {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub fn print_history(
+    ctx: &Context,
+    user: Option<&str>,
+    command: Option<&str>,
+    failed: bool,
+    limit: Option<usize>,
+) -> anyhow::Result<()> {
+    let entries: Vec<HistoryEntry> = history::read(&ctx.history)?
+        .into_iter()
+        .filter(|entry| user.is_none_or(|user| entry.user == user))
+        .filter(|entry| command.is_none_or(|command| entry.is_command(command)))
+        .filter(|entry| !failed || entry.outcome == Outcome::Failure)
+        .collect();
+    let skip = limit.map_or(0, |limit| entries.len().saturating_sub(limit));
+    if print_result(|| json!(&entries[skip..])) {
+        return Ok(());
+    }
+    msg_regular(&format!("History: {}", entries.len().to_string().blue()));
+
+    for entry in &entries[skip..] {
+        let outcome = match entry.outcome {
+            Outcome::Success => "ok".blue(),
+            Outcome::Failure => "failed".red(),
+        };
+        msg_sub(&format!(
+            "{} | {} | wsu {} | {} | {:.1}s",
+            format_time(entry.time),
+            entry.user,
+            entry.args.join(" "),
+            outcome,
+            entry.duration_ms as f64 / 1000.0
+        ));
+        if let Some(error) = &entry.error {
+            msg_sub(&format!("  {}", error.red()));
+        }
+    }
+    Ok(())
+}
+
+/// Formats a unix timestamp as a UTC date and time.
+fn format_time(secs: u64) -> String {
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        format_date(secs),
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+pub fn print_packages(ctx: &Context, filter: Option<&str>, user_only: bool) -> anyhow::Result<()> {
+    let mut index = PackageIndex::fetch(ctx)?;
+    index.load_labels(ctx);
+    let packages: Vec<&Package> = match filter {
+        Some(filter) => index.search(filter),
+        None => index.packages().iter().collect(),
+    };
+    let packages: Vec<&Package> = packages
+        .into_iter()
+        .filter(|p| !user_only || !p.system)
+        .collect();
+    if print_result(|| {
+        json!(
+            packages
+                .iter()
+                .map(|package| json!({
+                    "name": package.name,
+                    "uid": package.uid,
+                    "user": package.user,
+                    "system": package.system,
+                    "label": package.label,
+                }))
+                .collect::<Vec<_>>()
+        )
+    }) {
+        return Ok(());
+    }
+
+    msg_regular(&format!("Packages: {}", packages.len().to_string().blue()));
+    for package in packages {
+        msg_sub(&format!(
+            "{} | {} | user {} | {}{}",
+            package.name,
+            package.uid,
+            package.user,
+            if package.system {
+                "system".red()
+            } else {
+                "user".blue()
+            },
+            package
+                .label
+                .as_ref()
+                .map(|l| format!(" | {}", l))
+                .unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+pub fn print_status(ctx: &Context) -> anyhow::Result<()> {
+    let (daemon_running, version, zygisk) = get_status(ctx)?;
+    let (host, container) = (host_selinux(), container_selinux(ctx));
+    let changed = images_changed(ctx).unwrap_or(false);
+    if print_result(|| {
+        json!({
+            "daemon_running": daemon_running,
+            "version": daemon_running.then_some(&version),
+            "zygisk": daemon_running.then_some(zygisk),
+            "selinux": {"host": host.to_string(), "container": container.to_string()},
+            "images_changed": changed,
+        })
+    }) {
+        return Ok(());
+    }
     let daemon_running_str = if daemon_running {
         "Running".blue()
     } else {
@@ -85,5 +559,17 @@ pub fn print_status() -> anyhow::Result<()> {
     msg_regular(&format!("Daemon: {}", daemon_running_str));
     msg_regular(&format!("Installed: {}", version_str));
     msg_regular(&format!("Zygisk: {}", zygisk_str));
+    let selinux_str = |mode: SelinuxMode| match mode {
+        SelinuxMode::Unknown => mode.to_string().red(),
+        _ => mode.to_string().blue(),
+    };
+    msg_regular(&format!(
+        "SELinux: {} (host), {} (container)",
+        selinux_str(host),
+        selinux_str(container)
+    ));
+    if changed {
+        msg_err("Waydroid images changed since Magisk was installed, run \'sudo wsu repair\'");
+    }
     Ok(())
 }
@@ -0,0 +1,132 @@
+use crate::context::Context;
+use crate::utils::read_prop_file;
+use anyhow::{Ok, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const RECORD_FILE: &str = "install.json";
+
+/// What wsu knows about the current installation, kept across invocations.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InstallRecord {
+    #[serde(default)]
+    pub system_fingerprint: String,
+    #[serde(default)]
+    pub vendor_fingerprint: String,
+    #[serde(default)]
+    pub kitsune: bool,
+}
+
+impl InstallRecord {
+    fn path(ctx: &Context) -> PathBuf {
+        ctx.state_dir.join(RECORD_FILE)
+    }
+
+    pub fn load(ctx: &Context) -> anyhow::Result<Option<Self>> {
+        let path = Self::path(ctx);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let record = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| anyhow!("Invalid {}: {}", path.to_string_lossy(), e))?;
+        Ok(Some(record))
+    }
+
+    pub fn save(&self, ctx: &Context) -> anyhow::Result<()> {
+        fs::create_dir_all(&ctx.state_dir)?;
+        fs::write(Self::path(ctx), serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(())
+    }
+
+    pub fn delete(ctx: &Context) -> anyhow::Result<()> {
+        let path = Self::path(ctx);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// False when the images couldn't be fingerprinted at install time.
+    pub fn has_fingerprints(&self) -> bool {
+        !self.system_fingerprint.is_empty() && !self.vendor_fingerprint.is_empty()
+    }
+
+    /// Unknown fingerprints on either side never match.
+    pub fn matches(&self, fingerprints: &(String, String)) -> bool {
+        self.has_fingerprints()
+            && self.system_fingerprint == fingerprints.0
+            && self.vendor_fingerprint == fingerprints.1
+    }
+}
+
+const SYSTEM_FINGERPRINT: [&str; 2] = ["ro.system.build.fingerprint", "ro.build.fingerprint"];
+const VENDOR_FINGERPRINT: [&str; 2] = ["ro.vendor.build.fingerprint", "ro.build.fingerprint"];
+
+fn read_fingerprint(build_prop: &Path, keys: &[&str]) -> anyhow::Result<String> {
+    let props = read_prop_file(build_prop)
+        .map_err(|e| anyhow!("Couldn't read {}: {}", build_prop.to_string_lossy(), e))?;
+    keys.iter()
+        .find_map(|key| props.get(*key).cloned())
+        .ok_or_else(|| anyhow!("No build fingerprint in {}", build_prop.to_string_lossy()))
+}
+
+/// Build fingerprints of the system and vendor images mounted at `rootfs`.
+pub fn image_fingerprints(rootfs: &Path) -> anyhow::Result<(String, String)> {
+    let system = read_fingerprint(&rootfs.join("system/build.prop"), &SYSTEM_FINGERPRINT)?;
+    let vendor = read_fingerprint(&rootfs.join("vendor/build.prop"), &VENDOR_FINGERPRINT)?;
+    Ok((system, vendor))
+}
+
+/// Fingerprint of the image `path` below `rootfs` belongs to, None when the
+/// rootfs has no build.prop for it, as in an overlay.
+pub fn fingerprint_of(rootfs: &Path, path: &str) -> Option<String> {
+    if on_vendor(path) {
+        read_fingerprint(&rootfs.join("vendor/build.prop"), &VENDOR_FINGERPRINT).ok()
+    } else {
+        read_fingerprint(&rootfs.join("system/build.prop"), &SYSTEM_FINGERPRINT).ok()
+    }
+}
+
+/// Picks the fingerprint of the image `path` belongs to from a (system, vendor)
+/// pair, None when it's unknown.
+pub fn fingerprint_in(fingerprints: &(String, String), path: &str) -> Option<String> {
+    let fingerprint = if on_vendor(path) {
+        &fingerprints.1
+    } else {
+        &fingerprints.0
+    };
+    Some(fingerprint.clone()).filter(|fingerprint| !fingerprint.is_empty())
+}
+
+fn on_vendor(path: &str) -> bool {
+    path.trim_start_matches('/').starts_with("vendor/")
+}
+
+/// Fingerprints of the images the running container booted from.
+pub fn running_fingerprints(ctx: &Context) -> anyhow::Result<(String, String)> {
+    image_fingerprints(&ctx.rootfs())
+}
+
+/// Like [`running_fingerprints`], but warns and returns empty (unknown)
+/// fingerprints instead of failing.
+pub fn running_fingerprints_or_unknown(ctx: &Context) -> (String, String) {
+    running_fingerprints(ctx).unwrap_or_else(|e| {
+        ctx.report().warn(&format!(
+            "Couldn't fingerprint the Waydroid images, image updates won't be detected: {}",
+            e
+        ));
+        Default::default()
+    })
+}
+
+/// True when the running images differ from the ones Magisk was patched against.
+pub fn images_changed(ctx: &Context) -> anyhow::Result<bool> {
+    let Some(record) = InstallRecord::load(ctx)? else {
+        return Ok(false);
+    };
+    if !record.has_fingerprints() {
+        return Ok(false);
+    }
+    Ok(!record.matches(&running_fingerprints(ctx)?))
+}
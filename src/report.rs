@@ -0,0 +1,77 @@
+use std::process::Command;
+
+/// Where a reported command runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Host,
+    Container,
+}
+
+impl Target {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Target::Host => "host",
+            Target::Container => "container",
+        }
+    }
+}
+
+/// Receives what wsu has to say while it works. The library never writes to
+/// the terminal itself, everything goes through the reporter of the `Context`.
+pub trait Reporter: Send + Sync {
+    /// An operation starts, "Installing Magisk..." and the like.
+    fn begin(&self, msg: &str);
+    /// A step of the current operation.
+    fn step(&self, msg: &str);
+    /// The current operation finished.
+    fn finish(&self, msg: &str);
+    /// A note that isn't part of an operation's steps.
+    fn info(&self, msg: &str);
+    /// Something went wrong, but the operation carries on.
+    fn warn(&self, msg: &str);
+    fn error(&self, msg: &str);
+    /// A command is about to run.
+    fn command(&self, target: Target, argv: &[String]);
+    /// `current` out of `total` bytes of the current step are done, total is 0 when unknown.
+    fn progress(&self, current: u64, total: u64);
+    /// The current step won't report progress anymore.
+    fn progress_end(&self);
+
+    /// A command failed with `err`.
+    fn fail(&self, err: &anyhow::Error) {
+        self.error(&err.to_string());
+    }
+
+    /// Asks a yes or no question, false when nobody can answer.
+    fn confirm(&self, _question: &str) -> bool {
+        false
+    }
+
+    /// Asks to pick one of `options`, None when nobody can answer.
+    fn choose(&self, _question: &str, _options: &[String]) -> Option<usize> {
+        None
+    }
+
+    fn host_command(&self, cmd: &Command) {
+        let argv: Vec<String> = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        self.command(Target::Host, &argv);
+    }
+}
+
+/// Drops everything, the reporter used when the caller doesn't pass one.
+pub struct Silent;
+
+impl Reporter for Silent {
+    fn begin(&self, _msg: &str) {}
+    fn step(&self, _msg: &str) {}
+    fn finish(&self, _msg: &str) {}
+    fn info(&self, _msg: &str) {}
+    fn warn(&self, _msg: &str) {}
+    fn error(&self, _msg: &str) {}
+    fn command(&self, _target: Target, _argv: &[String]) {}
+    fn progress(&self, _current: u64, _total: u64) {}
+    fn progress_end(&self) {}
+}
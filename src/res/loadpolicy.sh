@@ -14,4 +14,5 @@ for r in $MAGISKTMP/.magisk/preinit/*/sepolicy.rule; do
     [ -f "$r" ] || continue
     RULESCMD="$RULESCMD --apply $r"
 done
+[ -f /data/adb/wsu/sepolicy.rule ] && RULESCMD="$RULESCMD --apply /data/adb/wsu/sepolicy.rule"
 $MAGISKTMP/magiskpolicy --live $RULESCMD 2>&1
\ No newline at end of file
@@ -1,26 +1,209 @@
+use crate::backup::BackupStore;
 use crate::constants::*;
-use crate::magisk_files::waydroid_su;
-use crate::print::msg_sub;
+use crate::context::Context;
+use crate::error::WsuError;
+use crate::file_contexts::{ContextsFile, FileContexts};
+use crate::magisk_files::{find_magisk_rc, waydroid_su};
+use crate::report::Reporter;
 use crate::utils::*;
-use anyhow::Ok;
-use libc::setxattr;
+use anyhow::{Ok, anyhow};
+use libc::{getxattr, setxattr};
 use std::ffi::CString;
-use std::fs::{self, OpenOptions, Permissions};
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::result::Result::Ok as OtherOk;
+use std::sync::OnceLock;
 
-pub fn getenforce() -> anyhow::Result<bool> {
-    let output;
-    if let OtherOk(out) = Command::new("getenforce").output() {
-        output = String::from_utf8_lossy(&out.stdout).trim().to_string();
+pub const SELINUX_XATTR: &str = "security.selinux";
+const MAGISK_FILE: &str = "u:object_r:magisk_file:s0";
+/// The SELinux label of a file wsu manages.
+pub struct Label {
+    /// Path inside the image.
+    pub path: String,
+    file: PathBuf,
+    /// None when no file context covers the file.
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl Label {
+    pub fn is_wrong(&self) -> bool {
+        self.expected.is_some() && self.expected != self.actual
+    }
+}
+
+/// Files wsu writes into `rootfs`, flagged when they belong to the Magisk dir.
+fn managed_files(rootfs: &Path) -> anyhow::Result<Vec<(PathBuf, bool)>> {
+    let mut files: Vec<(PathBuf, bool)> = walk_files(&rootfs.join(MAGISK_DIR))
+        .into_iter()
+        .map(|file| (file, true))
+        .collect();
+    let mut others: Vec<PathBuf> = ZYGOTE_RCS
+        .iter()
+        .map(|rc| rootfs.join("system/etc/init/hw").join(rc))
+        .collect();
+    others.push(rootfs.join(BOOTANIM_RC_PATH));
+    others.extend(find_magisk_rc(rootfs)?);
+    others.extend(SEPOLICY_FILES.iter().map(|path| rootfs.join(path)));
+    files.extend(
+        others
+            .into_iter()
+            .filter(|file| file.exists())
+            .map(|file| (file, false)),
+    );
+    Ok(files)
+}
+
+fn walk_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let OtherOk(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                files.extend(walk_files(&path));
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+fn file_type_of(file: &Path) -> anyhow::Result<char> {
+    let file_type = fs::symlink_metadata(file)?.file_type();
+    Ok(if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_socket() {
+        's'
+    } else if file_type.is_fifo() {
+        'p'
     } else {
-        output = String::new();
+        '-'
+    })
+}
+
+/// Reads the labels of everything wsu manages in `rootfs` and what they should be.
+/// File contexts come from the first of `context_roots` that has them.
+pub fn audit_labels(rootfs: &Path, context_roots: &[&Path]) -> anyhow::Result<Vec<Label>> {
+    let contexts = FileContexts::load(context_roots)?;
+    // the Magisk dir is only relabeled when the patched sepolicy knows magisk_file
+    let magisk_file = contexts.defines(MAGISK_FILE);
+    let mut labels = Vec::new();
+    for (file, in_magisk_dir) in managed_files(rootfs)? {
+        let path = format!(
+            "/{}",
+            file.strip_prefix(rootfs).unwrap_or(&file).to_string_lossy()
+        );
+        let expected = if in_magisk_dir && magisk_file {
+            Some(MAGISK_FILE.to_string())
+        } else {
+            contexts
+                .lookup(&path, file_type_of(&file)?)
+                .map(str::to_string)
+        };
+        labels.push(Label {
+            actual: get_selinux_attr(&file.to_string_lossy(), SELINUX_XATTR)?,
+            path,
+            file,
+            expected,
+        });
+    }
+    Ok(labels)
+}
+
+/// Sets the expected label on every mislabeled file, returns how many were fixed.
+pub fn relabel(labels: &[Label]) -> anyhow::Result<usize> {
+    let mut fixed = 0;
+    for label in labels.iter().filter(|label| label.is_wrong()) {
+        if let Some(expected) = &label.expected {
+            set_selinux_attr(&label.file.to_string_lossy(), SELINUX_XATTR, expected)?;
+            fixed += 1;
+        }
+    }
+    Ok(fixed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelinuxMode {
+    Disabled,
+    Permissive,
+    Enforcing,
+    Unknown,
+}
+
+impl SelinuxMode {
+    pub fn is_enforcing(self) -> bool {
+        self == SelinuxMode::Enforcing
+    }
+
+    /// Parses the output of `getenforce`.
+    pub fn parse(mode: &str) -> Self {
+        match mode.trim() {
+            "Enforcing" => SelinuxMode::Enforcing,
+            "Permissive" => SelinuxMode::Permissive,
+            "Disabled" => SelinuxMode::Disabled,
+            _ => SelinuxMode::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for SelinuxMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            SelinuxMode::Disabled => "Disabled",
+            SelinuxMode::Permissive => "Permissive",
+            SelinuxMode::Enforcing => "Enforcing",
+            SelinuxMode::Unknown => "Unknown",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+static HOST_SELINUX: OnceLock<SelinuxMode> = OnceLock::new();
+
+/// The host's SELinux mode, read once per invocation.
+pub fn host_selinux() -> SelinuxMode {
+    *HOST_SELINUX.get_or_init(read_host_selinux)
+}
+
+fn read_host_selinux() -> SelinuxMode {
+    let Some(selinuxfs) = selinuxfs_mount() else {
+        // the kernel has no SELinux or it was never mounted, which both mean disabled
+        return SelinuxMode::Disabled;
+    };
+    match fs::read_to_string(selinuxfs.join("enforce")).map(|s| s.trim().to_string()) {
+        OtherOk(mode) if mode == "1" => SelinuxMode::Enforcing,
+        OtherOk(mode) if mode == "0" => SelinuxMode::Permissive,
+        _ => SelinuxMode::Unknown,
     }
+}
 
-    Ok(output == "Enforcing")
+fn selinuxfs_mount() -> Option<PathBuf> {
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    mounts.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [_, target, "selinuxfs", ..] => Some(PathBuf::from(target)),
+            _ => None,
+        }
+    })
+}
+
+/// The SELinux mode inside the running container.
+pub fn container_selinux(ctx: &Context) -> SelinuxMode {
+    match waydroid_su(ctx, vec!["getenforce"], true) {
+        OtherOk(mode) => SelinuxMode::parse(&mode),
+        Err(_) => SelinuxMode::Unknown,
+    }
 }
 
 pub fn set_selinux_attr(file: &str, name: &str, value: &str) -> anyhow::Result<()> {
@@ -28,7 +211,7 @@ pub fn set_selinux_attr(file: &str, name: &str, value: &str) -> anyhow::Result<(
     let name = CString::new(name)?;
     let value = CString::new(value)?;
 
-    unsafe {
+    let ret = unsafe {
         setxattr(
             path.as_ptr(),
             name.as_ptr(),
@@ -37,60 +220,107 @@ pub fn set_selinux_attr(file: &str, name: &str, value: &str) -> anyhow::Result<(
             0,
         )
     };
+    if ret != 0 {
+        return Err(WsuError::Selinux(format!(
+            "Couldn't set {} of {} to {}: {}",
+            name.to_string_lossy(),
+            file,
+            value.to_string_lossy(),
+            std::io::Error::last_os_error()
+        ))
+        .into());
+    }
 
     Ok(())
 }
 
+pub fn get_selinux_attr(file: &str, name: &str) -> anyhow::Result<Option<String>> {
+    let path = CString::new(file)?;
+    let name = CString::new(name)?;
+    let mut value = vec![0u8; 256];
+
+    let len = unsafe {
+        getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut _,
+            value.len(),
+        )
+    };
+    if len < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENODATA) {
+            return Ok(None);
+        }
+        return Err(anyhow!(
+            "Couldn't read {} of {}: {}",
+            name.to_string_lossy(),
+            file,
+            err
+        ));
+    }
+    value.truncate(len as usize);
+    Ok(Some(
+        String::from_utf8_lossy(&value)
+            .trim_end_matches('\0')
+            .to_string(),
+    ))
+}
+
 pub fn set_selinux_attr_recursive(dir: PathBuf, name: &str, value: &str) -> anyhow::Result<()> {
     if let OtherOk(entries) = fs::read_dir(dir) {
-        for entry in entries {
-            if let OtherOk(entry) = entry {
-                let path = entry.path();
-                if path.is_dir() {
-                    set_selinux_attr_recursive(path, name, value)?;
-                } else {
-                    set_selinux_attr(&path.to_string_lossy(), name, value)?;
-                }
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                set_selinux_attr_recursive(path, name, value)?;
+            } else {
+                set_selinux_attr(&path.to_string_lossy(), name, value)?;
             }
         }
     }
     Ok(())
 }
 
-pub fn restore_sepolicy(rootfs: PathBuf, has_overlay: bool) -> anyhow::Result<()> {
-    let waydroid_vendor_selinux = rootfs.clone().join("vendor/etc/selinux");
-    let se_files = [
-        ("precompiled_sepolicy", "precompiled_sepolicy.gz"),
-        ("vendor_file_contexts", "vendor_file_contexts.gz"),
-    ];
+pub fn restore_sepolicy(
+    report: &dyn Reporter,
+    rootfs: PathBuf,
+    has_overlay: bool,
+    backups: &mut BackupStore,
+) -> anyhow::Result<()> {
     let mut msg = false;
 
-    for (plain, gz) in se_files {
-        let plain_path = waydroid_vendor_selinux.join(plain);
-        let gz_path = waydroid_vendor_selinux.join(gz);
-
-        if has_overlay || gz_path.exists() {
-            if remove_check(plain_path.clone())? {
-                msg = true;
-            }
-        }
-        if gz_path.exists() {
+    for path in SEPOLICY_FILES {
+        backups.migrate_sidecar(&rootfs, path)?;
+        if backups.restore(&rootfs, path)? || (has_overlay && remove_check(rootfs.join(path))?) {
             msg = true;
-            gzip_decompress(&gz_path.to_string_lossy(), &plain_path.to_string_lossy())?;
-            remove_check(gz_path)?;
         }
     }
+    // the entries stay behind when the backup was dropped with a replaced image
+    if edit_file_contexts(&rootfs.join(VENDOR_FILE_CONTEXTS), |contexts| {
+        Ok(contexts.remove_added())
+    })? {
+        msg = true;
+    }
 
     if msg {
-        msg_sub("Restoring sepolicy");
+        report.step("Restoring sepolicy");
     }
     Ok(())
 }
 
-pub fn patch_sepolicy_prepare(waydroid_data: PathBuf, magiskpolicy: PathBuf) -> anyhow::Result<()> {
+pub fn patch_sepolicy_prepare(
+    ctx: &Context,
+    waydroid_data: PathBuf,
+    magiskpolicy: PathBuf,
+) -> anyhow::Result<()> {
     fs::copy(magiskpolicy, waydroid_data.join("local/tmp/magiskpolicy"))?;
-    waydroid_su(vec!["chmod", "755", "/data/local/tmp/magiskpolicy"], true)?;
     waydroid_su(
+        ctx,
+        vec!["chmod", "755", "/data/local/tmp/magiskpolicy"],
+        true,
+    )?;
+    waydroid_su(
+        ctx,
         vec![
             "/data/local/tmp/magiskpolicy",
             "--load",
@@ -101,6 +331,7 @@ pub fn patch_sepolicy_prepare(waydroid_data: PathBuf, magiskpolicy: PathBuf) ->
         true,
     )?;
     waydroid_su(
+        ctx,
         vec![
             "cp",
             "/vendor/etc/selinux/vendor_file_contexts",
@@ -108,75 +339,128 @@ pub fn patch_sepolicy_prepare(waydroid_data: PathBuf, magiskpolicy: PathBuf) ->
         ],
         true,
     )?;
-    waydroid_su(vec!["rm", "/data/local/tmp/magiskpolicy"], true)?;
+    waydroid_su(ctx, vec!["rm", "/data/local/tmp/magiskpolicy"], true)?;
+    Ok(())
+}
+
+/// Same as `patch_sepolicy_prepare`, but runs magiskpolicy on the host against an offline rootfs.
+pub fn patch_sepolicy_prepare_host(
+    report: &dyn Reporter,
+    rootfs: &Path,
+    staged: &Path,
+    magiskpolicy: PathBuf,
+) -> anyhow::Result<()> {
+    let vendor_selinux = rootfs.join("vendor/etc/selinux");
+    let host_magiskpolicy = staged.join("magiskpolicy");
+    fs::copy(magiskpolicy, &host_magiskpolicy)?;
+    fs::set_permissions(&host_magiskpolicy, Permissions::from_mode(0o755))?;
+
+    let mut cmd = Command::new(&host_magiskpolicy);
+    cmd.arg("--load")
+        .arg(vendor_selinux.join("precompiled_sepolicy"))
+        .arg("--save")
+        .arg(staged.join("precompiled_sepolicy"));
+    report.host_command(&cmd);
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow!("Couldn't run magiskpolicy on the host: {}", e))?;
+    if !out.status.success() {
+        return Err(WsuError::Selinux(format!(
+            "magiskpolicy failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ))
+        .into());
+    }
+    fs::copy(
+        vendor_selinux.join("vendor_file_contexts"),
+        staged.join("vendor_file_contexts"),
+    )?;
+    fs::remove_file(host_magiskpolicy)?;
     Ok(())
 }
 
 pub fn patch_sepolicy(
+    report: &dyn Reporter,
     magisk_dir: PathBuf,
     rootfs: PathBuf,
-    waydroid_data: PathBuf,
+    staged: &Path,
+    loadpolicy: &str,
+    backups: &mut BackupStore,
 ) -> anyhow::Result<()> {
     let vendor_selinux = rootfs.join("vendor/etc/selinux");
     let init_hw_dir = rootfs.join("system/etc/init/hw");
 
     let precompiled = vendor_selinux.join("precompiled_sepolicy");
-    let precompiled_gz = vendor_selinux.join("precompiled_sepolicy.gz");
     let contexts_file = vendor_selinux.join("vendor_file_contexts");
-    let contexts_file_gz = vendor_selinux.join("vendor_file_contexts.gz");
 
-    msg_sub("Patching sepolicy");
+    report.step("Patching sepolicy");
 
     let loadpolicy_path = magisk_dir.join("loadpolicy.sh");
     remove_check(loadpolicy_path.clone())?;
-    fs::write(&loadpolicy_path, LOADPOLICY)?;
+    fs::write(&loadpolicy_path, loadpolicy)?;
     fs::set_permissions(&loadpolicy_path, Permissions::from_mode(0o755))?;
 
-    set_selinux_attr_recursive(magisk_dir, "security.selinux", "u:object_r:magisk_file:s0")?;
+    set_selinux_attr_recursive(magisk_dir, SELINUX_XATTR, MAGISK_FILE)?;
 
     create_dir_check(vendor_selinux, false)?;
     create_dir_check(init_hw_dir, false)?;
 
-    if precompiled.exists() && !precompiled_gz.exists() {
-        msg_sub("Backing up 'precompiled_sepolicy'");
-        gzip_compress(
-            &precompiled.to_string_lossy(),
-            &precompiled_gz.to_string_lossy(),
-        )?;
+    for path in SEPOLICY_FILES {
+        backups.migrate_sidecar(&rootfs, path)?;
+    }
+    if precompiled.exists() && backups.backup(&rootfs, PRECOMPILED_SEPOLICY)? {
+        report.step("Backing up \'precompiled_sepolicy\'");
     }
     remove_check(precompiled.clone())?;
-    fs::copy(
-        waydroid_data.join("local/tmp/precompiled_sepolicy"),
-        &precompiled,
-    )?;
+    fs::copy(staged.join("precompiled_sepolicy"), &precompiled)?;
+    if contexts_file.exists() {
+        backups.backup(&rootfs, VENDOR_FILE_CONTEXTS)?;
+    } else {
+        fs::copy(staged.join("vendor_file_contexts"), &contexts_file)?;
+    }
 
-    if !contexts_file.exists() {
-        fs::copy(
-            waydroid_data.join("local/tmp/vendor_file_contexts"),
-            &contexts_file,
-        )?;
-    } else if !contexts_file_gz.exists() {
-        msg_sub("Backing up 'vendor_file_contexts'");
-        gzip_compress(
-            &contexts_file.to_string_lossy(),
-            &contexts_file_gz.to_string_lossy(),
-        )?;
-    }
-
-    fs::remove_file(waydroid_data.join("local/tmp/precompiled_sepolicy"))?;
-    fs::remove_file(waydroid_data.join("local/tmp/vendor_file_contexts"))?;
-
-    let file_string = fs::read_to_string(&contexts_file)?;
-    let mut file = OpenOptions::new().append(true).open(&contexts_file)?;
-    let contexts = [
-        "/debug_ramdisk(/.*)?    u:object_r:magisk_file:s0",
-        "/data/adb/magisk(/.*)?   u:object_r:magisk_file:s0",
-    ];
-    for line in contexts {
-        if !file_string.contains(line) {
-            writeln!(file, "{line}")?;
+    fs::remove_file(staged.join("precompiled_sepolicy"))?;
+    fs::remove_file(staged.join("vendor_file_contexts"))?;
+
+    // older wsu versions appended the entries unmarked, those the original lacks are theirs
+    let original = match backups.get(VENDOR_FILE_CONTEXTS) {
+        Some(backup) => {
+            let stored = backups.stored_copy(backup);
+            Some(
+                ContextsFile::parse(&fs::read_to_string(&stored)?)
+                    .map_err(|e| anyhow!("{}: {}", stored.to_string_lossy(), e))?,
+            )
         }
-    }
+        None => None,
+    };
+    edit_file_contexts(&contexts_file, |contexts| {
+        for entry in MAGISK_FILE_CONTEXTS {
+            let adopt = match &original {
+                Some(original) => !original.contains(entry)?,
+                None => false,
+            };
+            contexts.add(entry, adopt)?;
+        }
+        Ok(true)
+    })?;
 
     Ok(())
 }
+
+/// Rewrites a file_contexts file in place when `edit` reports a change,
+/// returns whether it did. Missing files are left alone.
+fn edit_file_contexts(
+    path: &Path,
+    edit: impl FnOnce(&mut ContextsFile) -> anyhow::Result<bool>,
+) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let mut contexts = ContextsFile::parse(&fs::read_to_string(path)?)
+        .map_err(|e| anyhow!("{}: {}", path.to_string_lossy(), e))?;
+    if !edit(&mut contexts)? {
+        return Ok(false);
+    }
+    fs::write(path, contexts.serialize())?;
+    Ok(true)
+}
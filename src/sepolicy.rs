@@ -0,0 +1,558 @@
+use crate::backup::install_backups;
+use crate::constants::*;
+use crate::container::WaydroidContainer;
+use crate::context::Context;
+use crate::error::WsuError;
+use crate::install::inspect_rootfs;
+use crate::loopdev::mount_loop;
+use crate::magisk_files::waydroid_su;
+use crate::report::Reporter;
+use crate::utils::get_vendorimg_path;
+use anyhow::{Ok, anyhow};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env::temp_dir;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::result::Result::Ok as OtherOk;
+
+/// Argument kinds of policy statements, as in `magiskpolicy --help`.
+#[derive(Clone, Copy)]
+enum Param {
+    /// A single name.
+    Name,
+    /// A name, `*` or a `{ ... }` set of names.
+    Set,
+    /// A name or a `{ ... }` set of names.
+    Names,
+    /// `ioctl`, the only extended permission kind.
+    Ioctl,
+    /// `*`, a hex value or range, or a `{ ... }` set of them.
+    Xperms,
+    /// An absolute path.
+    Path,
+    /// A security context like `u:object_r:type:s0`.
+    Context,
+}
+
+use Param::*;
+
+/// Statement keyword, required and optional parameters.
+const STATEMENTS: [(&str, &[Param], &[Param]); 16] = [
+    ("allow", &[Set, Set, Set, Set], &[]),
+    ("deny", &[Set, Set, Set, Set], &[]),
+    ("auditallow", &[Set, Set, Set, Set], &[]),
+    ("dontaudit", &[Set, Set, Set, Set], &[]),
+    ("allowxperm", &[Set, Set, Set, Ioctl, Xperms], &[]),
+    ("auditallowxperm", &[Set, Set, Set, Ioctl, Xperms], &[]),
+    ("dontauditxperm", &[Set, Set, Set, Ioctl, Xperms], &[]),
+    ("permissive", &[Names], &[]),
+    ("enforce", &[Names], &[]),
+    ("typeattribute", &[Names, Names], &[]),
+    ("type", &[Name], &[Names]),
+    ("attribute", &[Name], &[]),
+    ("type_transition", &[Name, Name, Name, Name], &[Name]),
+    ("type_change", &[Name, Name, Name, Name], &[]),
+    ("type_member", &[Name, Name, Name, Name], &[]),
+    ("genfscon", &[Name, Path, Context], &[]),
+];
+
+/// Checks the syntax of a policy statement and returns it in canonical form.
+pub fn validate_rule(rule: &str) -> anyhow::Result<String> {
+    if let Some(c) = rule
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !" \t_.-*{}/:~".contains(*c))
+    {
+        return Err(anyhow!("Invalid character '{}' in rule", c));
+    }
+
+    let args = split_args(rule)?;
+    let Some((keyword, args)) = args.split_first() else {
+        return Err(anyhow!("Rule is empty"));
+    };
+    let keyword = match keyword {
+        Arg::Word(word) => word.as_str(),
+        Arg::Group(_) => return Err(anyhow!("Rule has to start with a statement")),
+    };
+    let (_, required, optional) = STATEMENTS
+        .iter()
+        .find(|(name, _, _)| *name == keyword)
+        .ok_or_else(|| anyhow!("Unknown statement '{}'", keyword))?;
+
+    if args.len() < required.len() || args.len() > required.len() + optional.len() {
+        let expected = if optional.is_empty() {
+            required.len().to_string()
+        } else {
+            format!("{} to {}", required.len(), required.len() + optional.len())
+        };
+        return Err(anyhow!(
+            "'{}' takes {} arguments, got {}",
+            keyword,
+            expected,
+            args.len()
+        ));
+    }
+    for (i, (arg, param)) in args
+        .iter()
+        .zip(required.iter().chain(optional.iter()))
+        .enumerate()
+    {
+        check_arg(arg, *param)
+            .map_err(|e| anyhow!("Argument {} of '{}': {}", i + 1, keyword, e))?;
+    }
+
+    let mut canonical = vec![keyword.to_string()];
+    canonical.extend(args.iter().map(Arg::to_string));
+    Ok(canonical.join(" "))
+}
+
+enum Arg {
+    Word(String),
+    Group(Vec<String>),
+}
+
+impl std::fmt::Display for Arg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arg::Word(word) => write!(f, "{}", word),
+            Arg::Group(words) => write!(f, "{{ {} }}", words.join(" ")),
+        }
+    }
+}
+
+fn split_args(rule: &str) -> anyhow::Result<Vec<Arg>> {
+    let spaced = rule.replace('{', " { ").replace('}', " } ");
+    let mut args = Vec::new();
+    let mut group: Option<Vec<String>> = None;
+    for token in spaced.split_whitespace() {
+        match (token, group.as_mut()) {
+            ("{", None) => group = Some(Vec::new()),
+            ("{", Some(_)) => return Err(anyhow!("Nested '{{' in rule")),
+            ("}", None) => return Err(anyhow!("Unmatched '}}' in rule")),
+            ("}", Some(words)) => {
+                if words.is_empty() {
+                    return Err(anyhow!("Empty '{{ }}' in rule"));
+                }
+                args.push(Arg::Group(group.take().unwrap_or_default()));
+            }
+            (word, Some(words)) => words.push(word.to_string()),
+            (word, None) => args.push(Arg::Word(word.to_string())),
+        }
+    }
+    if group.is_some() {
+        return Err(anyhow!("Unclosed '{{' in rule"));
+    }
+    Ok(args)
+}
+
+fn check_arg(arg: &Arg, param: Param) -> anyhow::Result<()> {
+    match (param, arg) {
+        (Set, Arg::Word(word)) if word == "*" => Ok(()),
+        (Xperms, Arg::Word(word)) if word == "*" => Ok(()),
+        (Set | Names, Arg::Group(words)) => words.iter().try_for_each(|word| check_name(word)),
+        (Name | Set | Names, Arg::Word(word)) => check_name(word),
+        (Ioctl, Arg::Word(word)) if word == "ioctl" => Ok(()),
+        (Ioctl, _) => Err(anyhow!("expected 'ioctl'")),
+        (Xperms, Arg::Group(words)) => words.iter().try_for_each(|word| check_xperm(word)),
+        (Xperms, Arg::Word(word)) => check_xperm(word),
+        (Path, Arg::Word(word)) if word.starts_with('/') => Ok(()),
+        (Path, _) => Err(anyhow!("expected an absolute path")),
+        (Context, Arg::Word(word)) if word.split(':').count() >= 4 => Ok(()),
+        (Context, _) => Err(anyhow!("expected a context like u:object_r:type:s0")),
+        (Name, Arg::Group(_)) => Err(anyhow!("expected a single name")),
+    }
+}
+
+fn check_name(word: &str) -> anyhow::Result<()> {
+    if word.is_empty()
+        || !word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+    {
+        return Err(anyhow!("'{}' isn't a valid name", word));
+    }
+    Ok(())
+}
+
+fn check_xperm(word: &str) -> anyhow::Result<()> {
+    let word = word.strip_prefix('~').unwrap_or(word);
+    let is_hex = |value: &str| {
+        value
+            .strip_prefix("0x")
+            .is_some_and(|hex| !hex.is_empty() && u16::from_str_radix(hex, 16).is_ok())
+    };
+    let valid = match word.split_once('-') {
+        Some((low, high)) => is_hex(low) && is_hex(high),
+        None => is_hex(word),
+    };
+    if !valid {
+        return Err(anyhow!("'{}' isn't an ioctl number or range", word));
+    }
+    Ok(())
+}
+
+fn rules_path(ctx: &Context) -> anyhow::Result<PathBuf> {
+    Ok(ctx.data_dir()?.join(SEPOLICY_RULES))
+}
+
+/// The custom rules, one statement per entry.
+pub fn read_rules(ctx: &Context) -> anyhow::Result<Vec<String>> {
+    let path = rules_path(ctx)?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn write_rules(ctx: &Context, rules: &[String]) -> anyhow::Result<()> {
+    let path = rules_path(ctx)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut content = rules.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    fs::write(path, content)?;
+    Ok(())
+}
+
+pub fn add_rule(ctx: &Context, rule: &str, live: bool) -> anyhow::Result<()> {
+    let rule = validate_rule(rule).map_err(|e| WsuError::InvalidInput(e.to_string()))?;
+    ctx.report().begin("Adding sepolicy rule");
+    if live {
+        ctx.report().step("Applying to the running policy");
+        let magiskpolicy = format!("/{}/magiskpolicy", MAGISKTMP);
+        waydroid_su(
+            ctx,
+            vec![&magiskpolicy, "--live", &format!("\"{}\"", rule)],
+            false,
+        )
+        .map_err(|e| WsuError::Selinux(format!("magiskpolicy failed: {}", e)))?;
+    }
+
+    let mut rules = read_rules(ctx)?;
+    if rules.contains(&rule) {
+        ctx.report().step(&format!("Already added: {}", rule));
+        return Ok(());
+    }
+    rules.push(rule.clone());
+    write_rules(ctx, &rules)?;
+    ctx.report().step(&format!("Added: {}", rule));
+    if !live {
+        ctx.report()
+            .info("The rule will be applied on the next boot.");
+    }
+    warn_outdated_loadpolicy(ctx);
+    Ok(())
+}
+
+pub fn remove_rule(ctx: &Context, rule: &str) -> anyhow::Result<()> {
+    let rule = validate_rule(rule).map_err(|e| WsuError::InvalidInput(e.to_string()))?;
+    let mut rules = read_rules(ctx)?;
+    let before = rules.len();
+    rules.retain(|existing| validate_rule(existing).ok().as_ref() != Some(&rule));
+    if rules.len() == before {
+        return Err(WsuError::InvalidInput(format!("No such rule: {}", rule)).into());
+    }
+    ctx.report().begin("Removing sepolicy rule");
+    write_rules(ctx, &rules)?;
+    ctx.report().step(&format!("Removed: {}", rule));
+    ctx.report()
+        .info("The running policy keeps the rule until Waydroid restarts.");
+    Ok(())
+}
+
+/// Installs from before custom rules existed ship a loadpolicy.sh that ignores them.
+fn warn_outdated_loadpolicy(ctx: &Context) {
+    let loadpolicy = ctx.rootfs().join(MAGISK_DIR).join("loadpolicy.sh");
+    if let OtherOk(script) = fs::read_to_string(loadpolicy)
+        && !script.contains(SEPOLICY_RULES)
+    {
+        ctx.report().info(
+            "The installed loadpolicy.sh doesn't load custom rules, run 'sudo wsu repair --force'.",
+        );
+    }
+}
+
+/// The rules of a policy as printed by `magiskpolicy --print-rules`.
+#[derive(Default)]
+pub struct PolicyRules {
+    /// Source, target, class and a single permission.
+    allow: BTreeSet<(String, String, String, String)>,
+    types: BTreeSet<String>,
+    attributes: BTreeSet<String>,
+    /// Every other statement, with type declarations split into `typeattribute` lines.
+    other: BTreeSet<String>,
+}
+
+pub struct RulesDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl RulesDiff {
+    fn new(old: &BTreeSet<String>, new: &BTreeSet<String>) -> Self {
+        Self {
+            added: new.difference(old).cloned().collect(),
+            removed: old.difference(new).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+pub struct PolicyDiff {
+    pub allow: RulesDiff,
+    pub types: RulesDiff,
+    pub attributes: RulesDiff,
+    pub other: RulesDiff,
+}
+
+impl PolicyRules {
+    pub fn parse(dump: &str) -> Self {
+        let mut rules = Self::default();
+        for line in dump.lines() {
+            let spaced = line.replace(['{', '}'], " ");
+            let tokens: Vec<&str> = spaced.split_whitespace().collect();
+            match tokens[..] {
+                [] => {}
+                ["allow", source, target, class, ref perms @ ..] if !perms.is_empty() => {
+                    for perm in perms {
+                        rules.allow.insert((
+                            source.to_string(),
+                            target.to_string(),
+                            class.to_string(),
+                            perm.to_string(),
+                        ));
+                    }
+                }
+                ["type", name, ref attributes @ ..] => {
+                    rules.types.insert(name.to_string());
+                    for attribute in attributes {
+                        rules
+                            .other
+                            .insert(format!("typeattribute {} {}", name, attribute));
+                    }
+                }
+                ["attribute", name] => {
+                    rules.attributes.insert(name.to_string());
+                }
+                _ => {
+                    rules.other.insert(tokens.join(" "));
+                }
+            }
+        }
+        rules
+    }
+
+    /// What changed from `self` to `newer`, allow rules are grouped per source, target and class.
+    pub fn diff(&self, newer: &PolicyRules) -> PolicyDiff {
+        let group = |rules: Vec<&(String, String, String, String)>| {
+            let mut grouped: BTreeMap<(&str, &str, &str), Vec<&str>> = BTreeMap::new();
+            for (source, target, class, perm) in rules {
+                grouped
+                    .entry((source, target, class))
+                    .or_default()
+                    .push(perm);
+            }
+            grouped
+                .into_iter()
+                .map(|((source, target, class), perms)| match perms[..] {
+                    [perm] => format!("allow {} {} {} {}", source, target, class, perm),
+                    _ => format!(
+                        "allow {} {} {} {{ {} }}",
+                        source,
+                        target,
+                        class,
+                        perms.join(" ")
+                    ),
+                })
+                .collect()
+        };
+        PolicyDiff {
+            allow: RulesDiff {
+                added: group(newer.allow.difference(&self.allow).collect()),
+                removed: group(self.allow.difference(&newer.allow).collect()),
+            },
+            types: RulesDiff::new(&self.types, &newer.types),
+            attributes: RulesDiff::new(&self.attributes, &newer.attributes),
+            other: RulesDiff::new(&self.other, &newer.other),
+        }
+    }
+}
+
+/// Dumps a policy file with a magiskpolicy binary runnable on the host.
+fn dump_policy_file(
+    report: &dyn Reporter,
+    magiskpolicy: &Path,
+    policy: &Path,
+) -> anyhow::Result<String> {
+    let mut cmd = Command::new(magiskpolicy);
+    cmd.arg("--load").arg(policy).arg("--print-rules");
+    report.host_command(&cmd);
+    let out = cmd
+        .output()
+        .map_err(|e| anyhow!("Couldn't run magiskpolicy on the host: {}", e))?;
+    if !out.status.success() {
+        return Err(WsuError::Selinux(format!(
+            "magiskpolicy failed on {}: {}",
+            policy.to_string_lossy(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ))
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+/// Compares the stock policy with the installed one and, while the container runs,
+/// the installed policy with the one the kernel has loaded.
+pub fn policy_diffs(ctx: &Context) -> anyhow::Result<Vec<(&'static str, PolicyDiff)>> {
+    let backups = install_backups(ctx)?;
+    // Overlay installs patch the policy in the overlay only, so the vendor
+    // image below still holds the stock one
+    let mut _lower = None;
+    let stock = match backups.get(PRECOMPILED_SEPOLICY) {
+        Some(backup) => backups.stored_copy(backup),
+        None if ctx.has_overlay()? => {
+            let mnt = temp_dir().join("waydroidsu-stock");
+            fs::create_dir_all(&mnt)?;
+            _lower = Some(mount_loop(&get_vendorimg_path(ctx)?, &mnt, true)?);
+            mnt.join(PRECOMPILED_SEPOLICY.trim_start_matches("vendor/"))
+        }
+        None => {
+            return Err(anyhow!(
+                "No backup of the stock policy, the installed policy isn't patched by wsu"
+            ));
+        }
+    };
+
+    let mut waydroid = WaydroidContainer::new()?;
+    let running = waydroid.is_container_running()?;
+    let dump = |rootfs: &Path| -> anyhow::Result<(PolicyRules, PolicyRules)> {
+        let magiskpolicy = rootfs.join(MAGISK_DIR).join("magiskpolicy");
+        if !magiskpolicy.exists() {
+            return Err(WsuError::NotInstalled.into());
+        }
+        Ok((
+            PolicyRules::parse(&dump_policy_file(ctx.report(), &magiskpolicy, &stock)?),
+            PolicyRules::parse(&dump_policy_file(
+                ctx.report(),
+                &magiskpolicy,
+                &rootfs.join(PRECOMPILED_SEPOLICY),
+            )?),
+        ))
+    };
+    let (stock, installed) = if running {
+        dump(&ctx.rootfs())?
+    } else {
+        inspect_rootfs(ctx, true, dump)?
+    };
+
+    let mut diffs = vec![("stock -> installed", stock.diff(&installed))];
+    if running {
+        let magiskpolicy = format!("/{}/magiskpolicy", MAGISKTMP);
+        let live = waydroid_su(ctx, vec![&magiskpolicy, "--print-rules"], false)?;
+        diffs.push((
+            "installed -> live",
+            installed.diff(&PolicyRules::parse(&live)),
+        ));
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rule_canonicalizes_spacing() {
+        assert_eq!(
+            validate_rule("allow  untrusted_app {magisk_file   su} file  *").unwrap(),
+            "allow untrusted_app { magisk_file su } file *"
+        );
+        assert_eq!(
+            validate_rule("allowxperm domain devpts chr_file ioctl { 0x5401-0x5402 ~0x5403 }")
+                .unwrap(),
+            "allowxperm domain devpts chr_file ioctl { 0x5401-0x5402 ~0x5403 }"
+        );
+        assert_eq!(
+            validate_rule("genfscon proc /magisk u:object_r:magisk_file:s0").unwrap(),
+            "genfscon proc /magisk u:object_r:magisk_file:s0"
+        );
+        assert_eq!(
+            validate_rule("type magisk_log file_type").unwrap(),
+            "type magisk_log file_type"
+        );
+    }
+
+    #[test]
+    fn validate_rule_rejects_malformed_rules() {
+        for rule in [
+            "",
+            "{ allow } a b c",
+            "neverallow a b c d",
+            "allow a b c",
+            "allow a b c d e",
+            "allow a b c d; rm -rf /",
+            "allow a { b { c } } d e",
+            "allow a { } c d",
+            "allow a b } c d",
+            "allow a { b c d",
+            "allowxperm a b c read 0x1",
+            "allowxperm a b c ioctl 12",
+            "type { a b }",
+            "genfscon proc magisk u:object_r:magisk_file:s0",
+            "genfscon proc /magisk magisk_file",
+        ] {
+            assert!(validate_rule(rule).is_err(), "{} was accepted", rule);
+        }
+    }
+
+    #[test]
+    fn parse_splits_dump_into_kinds() {
+        let rules = PolicyRules::parse(
+            "allow su adbd process { fork sigchld }\ntype magisk domain mlstrustedsubject\nattribute magisk_attr\n\npermissive magisk\n",
+        );
+        assert_eq!(rules.allow.len(), 2);
+        assert!(rules.allow.contains(&(
+            "su".to_string(),
+            "adbd".to_string(),
+            "process".to_string(),
+            "sigchld".to_string()
+        )));
+        assert!(rules.types.contains("magisk"));
+        assert!(rules.attributes.contains("magisk_attr"));
+        assert!(rules.other.contains("typeattribute magisk domain"));
+        assert!(
+            rules
+                .other
+                .contains("typeattribute magisk mlstrustedsubject")
+        );
+        assert!(rules.other.contains("permissive magisk"));
+    }
+
+    #[test]
+    fn diff_groups_permissions() {
+        let stock = PolicyRules::parse("allow a b file read\ntype a\n");
+        let patched = PolicyRules::parse(
+            "allow a b file { read write open }\nallow c d dir search\ntype a\ntype magisk\n",
+        );
+        let diff = stock.diff(&patched);
+        assert_eq!(
+            diff.allow.added,
+            vec!["allow a b file { open write }", "allow c d dir search"]
+        );
+        assert!(diff.allow.removed.is_empty());
+        assert_eq!(diff.types.added, vec!["magisk"]);
+        assert!(diff.attributes.is_empty());
+        assert!(stock.diff(&stock).allow.is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+use crate::error::WsuError;
+use crate::magisk::Magisk;
+use anyhow::{Ok, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ModuleState {
+    pub id: String,
+    #[serde(default)]
+    pub version: String,
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zip: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    pub flavor: String,
+    pub version: String,
+    #[serde(default)]
+    pub settings: BTreeMap<String, i64>,
+    #[serde(default)]
+    pub policies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub denylist: Vec<String>,
+    #[serde(default)]
+    pub modules: Vec<ModuleState>,
+}
+
+/// A difference between the live state and a state file.
+pub enum Change {
+    Setting(String, i64),
+    Policy(String, bool),
+    PolicyRemove(String),
+    DenylistAdd(String),
+    DenylistRm(String),
+    ModuleInstall(String, String),
+    ModuleRemove(String),
+    ModuleEnable(String, bool),
+}
+
+impl Change {
+    pub fn describe(&self) -> String {
+        match self {
+            Change::Setting(key, value) => format!("set {} = {}", key, value),
+            Change::Policy(pkg, allow) => format!(
+                "{} su access for {}",
+                if *allow { "allow" } else { "deny" },
+                pkg
+            ),
+            Change::PolicyRemove(pkg) => format!("remove su policy for {}", pkg),
+            Change::DenylistAdd(target) => format!("add {} to denylist", target),
+            Change::DenylistRm(target) => format!("remove {} from denylist", target),
+            Change::ModuleInstall(id, zip) => format!("install module {} from {}", id, zip),
+            Change::ModuleRemove(id) => format!("remove module {}", id),
+            Change::ModuleEnable(id, enabled) => format!(
+                "{} module {}",
+                if *enabled { "enable" } else { "disable" },
+                id
+            ),
+        }
+    }
+
+    fn apply(&self, magisk: &mut Magisk) -> anyhow::Result<()> {
+        match self {
+            Change::Setting(key, value) => magisk.set_setting(key, *value),
+            Change::Policy(pkg, allow) => magisk.superuser_manage(pkg, *allow),
+            Change::PolicyRemove(pkg) => magisk.superuser_remove(pkg),
+            Change::DenylistAdd(target) => magisk.denylist_add(target, false),
+            Change::DenylistRm(target) => magisk.denylist_rm(target),
+            Change::ModuleInstall(_, zip) => magisk.install_module(zip, false),
+            Change::ModuleRemove(id) => magisk.remove_module(id),
+            Change::ModuleEnable(id, true) => magisk.enable_module(id),
+            Change::ModuleEnable(id, false) => magisk.disable_module(id),
+        }
+    }
+}
+
+fn flavor(magisk: &Magisk) -> &'static str {
+    if magisk.is_kitsune() {
+        "kitsune"
+    } else {
+        "magisk"
+    }
+}
+
+pub fn capture_state(magisk: &mut Magisk) -> anyhow::Result<State> {
+    let settings = magisk.get_settings()?;
+
+    let mut policies = BTreeMap::new();
+    for (pkg, verdict) in magisk.get_superuser_list()? {
+        let verdict = if verdict == "allowed" {
+            "allow"
+        } else {
+            "deny"
+        };
+        policies.insert(pkg, verdict.to_string());
+    }
+
+    let mut denylist = Vec::new();
+    for (pkg, processes) in magisk.get_denylist()? {
+        for process in processes {
+            denylist.push(format!("{}/{}", pkg, process));
+        }
+    }
+
+    let mut modules = Vec::new();
+    for id in magisk.get_list_modules().unwrap_or_default() {
+        let (version, enabled) = magisk.get_module_info(&id)?;
+        modules.push(ModuleState {
+            id,
+            version,
+            enabled,
+            zip: None,
+        });
+    }
+    modules.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(State {
+        flavor: flavor(magisk).to_string(),
+        version: magisk.version().to_string(),
+        settings,
+        policies,
+        denylist,
+        modules,
+    })
+}
+
+/// The live state as JSON, also written to `output` when given.
+pub fn export(mut magisk: Magisk, output: Option<String>) -> anyhow::Result<String> {
+    let state = capture_state(&mut magisk)?;
+    let json = serde_json::to_string_pretty(&state)?;
+    if let Some(path) = output {
+        fs::write(&path, json.clone() + "\n")?;
+        magisk.report().info(&format!("State exported to {}", path));
+    }
+    Ok(json)
+}
+
+fn diff(live: &State, target: &State) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    for (key, value) in &target.settings {
+        if live.settings.get(key) != Some(value) {
+            changes.push(Change::Setting(key.clone(), *value));
+        }
+    }
+
+    for (pkg, verdict) in &target.policies {
+        if live.policies.get(pkg) != Some(verdict) {
+            changes.push(Change::Policy(pkg.clone(), verdict == "allow"));
+        }
+    }
+    for pkg in live.policies.keys() {
+        if !target.policies.contains_key(pkg) && !pkg.starts_with("uid:") {
+            changes.push(Change::PolicyRemove(pkg.clone()));
+        }
+    }
+
+    for target_entry in &target.denylist {
+        if !live.denylist.contains(target_entry) {
+            changes.push(Change::DenylistAdd(target_entry.clone()));
+        }
+    }
+    for live_entry in &live.denylist {
+        if !target.denylist.contains(live_entry) {
+            changes.push(Change::DenylistRm(live_entry.clone()));
+        }
+    }
+
+    for module in &target.modules {
+        match live.modules.iter().find(|m| m.id == module.id) {
+            Some(installed) if installed.version == module.version || module.zip.is_none() => {
+                if installed.enabled != module.enabled {
+                    changes.push(Change::ModuleEnable(module.id.clone(), module.enabled));
+                }
+            }
+            _ => {
+                if let Some(zip) = &module.zip {
+                    changes.push(Change::ModuleInstall(module.id.clone(), zip.clone()));
+                }
+            }
+        }
+    }
+    for module in &live.modules {
+        if !target.modules.iter().any(|m| m.id == module.id) {
+            changes.push(Change::ModuleRemove(module.id.clone()));
+        }
+    }
+
+    changes
+}
+
+/// Rejects verdicts other than allow and deny, and resolves module zips
+/// relative to `dir`, the state file's directory.
+fn check_target(target: &mut State, dir: &Path) -> anyhow::Result<()> {
+    if let Some((pkg, verdict)) = target
+        .policies
+        .iter()
+        .find(|(_, verdict)| !["allow", "deny"].contains(&verdict.as_str()))
+    {
+        return Err(WsuError::InvalidInput(format!(
+            "Invalid verdict \'{}\' for {}, expected allow or deny",
+            verdict, pkg
+        ))
+        .into());
+    }
+    for module in &mut target.modules {
+        if let Some(zip) = &mut module.zip {
+            let resolved = dir.join(&*zip);
+            if !resolved.is_file() {
+                return Err(WsuError::InvalidInput(format!(
+                    "Zip of module \'{}\' not found: {}",
+                    module.id,
+                    resolved.to_string_lossy()
+                ))
+                .into());
+            }
+            *zip = resolved.to_string_lossy().to_string();
+        }
+    }
+    Ok(())
+}
+
+/// Brings the install to the state in `file` and returns the changes made,
+/// or the ones that would be with `dry_run`.
+pub fn apply(mut magisk: Magisk, file: &str, dry_run: bool) -> anyhow::Result<Vec<Change>> {
+    let path = PathBuf::from(file);
+    if !path.is_file() {
+        return Err(WsuError::InvalidInput("No such file or directory.".to_string()).into());
+    }
+    let mut target: State = serde_json::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| WsuError::InvalidInput(format!("Invalid state file: {}", e)))?;
+    check_target(&mut target, path.parent().unwrap_or(Path::new("")))?;
+    let live = capture_state(&mut magisk)?;
+
+    if target.flavor != live.flavor {
+        return Err(anyhow!(
+            "State file is for {}, but {} is installed",
+            target.flavor,
+            live.flavor
+        ));
+    }
+    if target.version != live.version {
+        magisk.report().warn(&format!(
+            "State file was exported from {}, installed version is {}",
+            target.version, live.version
+        ));
+    }
+    for module in &target.modules {
+        let installed = live.modules.iter().find(|m| m.id == module.id);
+        if module.zip.is_none() && installed.is_none_or(|m| m.version != module.version) {
+            magisk.report().warn(&format!(
+                "Module \'{}\' {} is missing and has no zip to install from",
+                module.id, module.version
+            ));
+        }
+    }
+
+    let changes = diff(&live, &target);
+    if changes.is_empty() {
+        magisk.report().info("Already up to date");
+        return Ok(changes);
+    }
+    if dry_run {
+        return Ok(changes);
+    }
+
+    magisk.report().begin("Applying state...");
+    for change in &changes {
+        magisk.report().step(&change.describe());
+        change.apply(&mut magisk)?;
+    }
+    magisk.report().finish("State applied.");
+    magisk.report().info("Reboot Waydroid to apply changes.");
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::process;
+
+    fn state(json: &str) -> State {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn unknown_verdicts_are_rejected() {
+        let mut target = state(
+            r#"{"flavor": "magisk", "version": "28.1", "policies": {"com.termux": "allowed"}}"#,
+        );
+        assert!(check_target(&mut target, Path::new("/")).is_err());
+
+        let mut target = state(
+            r#"{"flavor": "magisk", "version": "28.1", "policies": {"com.termux": "allow", "com.example": "deny"}}"#,
+        );
+        check_target(&mut target, Path::new("/")).unwrap();
+    }
+
+    #[test]
+    fn module_zips_resolve_next_to_the_state_file() {
+        let dir = temp_dir().join(format!("wsu-test-state-{}", process::id()));
+        fs::create_dir_all(dir.join("modules")).unwrap();
+        fs::write(dir.join("modules/shamiko.zip"), "").unwrap();
+
+        let mut target = state(
+            r#"{"flavor": "magisk", "version": "28.1", "modules": [
+                {"id": "shamiko", "enabled": true, "zip": "modules/shamiko.zip"},
+                {"id": "lsposed", "enabled": false}
+            ]}"#,
+        );
+        check_target(&mut target, &dir).unwrap();
+        assert_eq!(
+            target.modules[0].zip.as_deref().map(Path::new),
+            Some(dir.join("modules/shamiko.zip").as_path())
+        );
+        assert_eq!(target.modules[1].zip, None);
+
+        let mut target = state(
+            r#"{"flavor": "magisk", "version": "28.1", "modules": [
+                {"id": "shamiko", "enabled": true, "zip": "shamiko.zip"}
+            ]}"#,
+        );
+        assert!(check_target(&mut target, &dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use crate::constants::*;
+use crate::context::Context;
+use crate::initrc::RcFile;
+use crate::report::Reporter;
+use anyhow::{Ok, anyhow};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Built-in templates, the first entry whose `max_sdk` covers the image wins.
+///
+/// Only bootanim.rc differs between Android versions. Magisk's rc files and
+/// loadpolicy.sh use init builtins, toybox commands and SELinux domains that
+/// are the same on Android 11 (SDK 30) and 13 (SDK 33), the versions Waydroid
+/// images ship, so one copy serves both.
+const BUILTIN: [(&str, u32, &str); 5] = [
+    // Android 10 and older have no task profiles
+    ("bootanim.rc", 29, BOOTANIM_RC_Q),
+    ("bootanim.rc", u32::MAX, BOOTANIM_RC),
+    ("magisk.rc", u32::MAX, MAGISK_RC),
+    ("kitsune.rc", u32::MAX, KITSUNE_RC),
+    ("loadpolicy.sh", u32::MAX, LOADPOLICY),
+];
+
+/// The files wsu writes into an image, picked for its Android version.
+pub struct Templates {
+    /// Stock bootanim.rc, used when the image has none.
+    pub bootanim_rc: String,
+    /// Magisk's init actions and services.
+    pub magisk_rc: String,
+    pub loadpolicy: String,
+}
+
+impl Templates {
+    /// Looks up `<templates_dir>/<sdk>/<name>`, then `<templates_dir>/<name>`,
+    /// then the built-in template for `sdk`.
+    pub fn load(ctx: &Context, sdk: Option<u32>, kitsune: bool) -> anyhow::Result<Self> {
+        let magisk_rc = if kitsune { "kitsune.rc" } else { "magisk.rc" };
+        Ok(Self {
+            bootanim_rc: template(ctx, sdk, "bootanim.rc")?,
+            magisk_rc: template(ctx, sdk, magisk_rc)?,
+            loadpolicy: template(ctx, sdk, "loadpolicy.sh")?,
+        })
+    }
+}
+
+fn template(ctx: &Context, sdk: Option<u32>, name: &str) -> anyhow::Result<String> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    if let Some(sdk) = sdk {
+        candidates.push(ctx.templates_dir.join(sdk.to_string()).join(name));
+    }
+    candidates.push(ctx.templates_dir.join(name));
+
+    if let Some(path) = candidates.iter().find(|path| path.is_file()) {
+        let content = fs::read_to_string(path)?;
+        validate(ctx.report(), name, path, &content)
+            .map_err(|e| anyhow!("Template {} is invalid: {}", path.to_string_lossy(), e))?;
+        return Ok(content);
+    }
+
+    let sdk = sdk.unwrap_or(u32::MAX);
+    BUILTIN
+        .iter()
+        .find(|(builtin, max_sdk, _)| *builtin == name && sdk <= *max_sdk)
+        .map(|(_, _, content)| content.to_string())
+        .ok_or_else(|| anyhow!("No template {}", name))
+}
+
+fn validate(report: &dyn Reporter, name: &str, path: &Path, content: &str) -> anyhow::Result<()> {
+    if name.ends_with(".sh") {
+        return validate_script(report, path);
+    }
+
+    let rc = RcFile::parse(content)?;
+    if rc.is_patched() {
+        return Err(anyhow!("contains wsu markers"));
+    }
+    if let Some(name) = rc.duplicate_services().first() {
+        return Err(anyhow!("service {} is defined more than once", name));
+    }
+    match name {
+        "bootanim.rc" if !rc.service_names().contains(&"bootanim") => {
+            Err(anyhow!("doesn't define the bootanim service"))
+        }
+        "magisk.rc" | "kitsune.rc" if !content.contains("on post-fs-data") => {
+            Err(anyhow!("has no post-fs-data action"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_script(report: &dyn Reporter, path: &Path) -> anyhow::Result<()> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-n")
+        .arg(path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    report.host_command(&cmd);
+    let status = cmd
+        .status()
+        .map_err(|e| anyhow!("couldn't run sh: {}", e))?;
+    if !status.success() {
+        return Err(anyhow!("not a valid shell script"));
+    }
+    Ok(())
+}
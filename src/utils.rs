@@ -1,28 +1,27 @@
-use crate::constants::WAYDROID_CONFIG;
-use crate::container::{WaydroidContainer, has_overlay};
-use crate::print::{msg_err, msg_err_str, msg_sub};
+use crate::constants::SEPOLICY_SPACE;
+use crate::container::WaydroidContainer;
+use crate::context::Context;
+use crate::error::WsuError;
+use crate::loopdev::{MountGuard, mount_loop, umount_recursive};
+use crate::report::Reporter;
 use anyhow::{Ok, anyhow};
-use colored::*;
-use flate2::Compression;
 use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
-use indicatif::{ProgressBar, ProgressStyle};
-use ini::configparser::ini::Ini;
 use rand::{Rng, distr::Alphanumeric};
 use reqwest::blocking::Client;
 use reqwest::header::CONTENT_LENGTH;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
 use std::env::temp_dir;
+use std::ffi::CString;
 use std::fs::File;
 use std::fs::{self, Permissions};
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::result::Result::Ok as OtherOk;
-use std::thread::sleep;
 use std::time::Duration;
 use which::which;
 use zip::read::ZipArchive;
@@ -67,22 +66,12 @@ pub fn get_magisk_info(json_str: &str) -> anyhow::Result<(String, String)> {
     Ok((parsed.magisk.version, parsed.magisk.link))
 }
 
-fn get_systemimg_path() -> anyhow::Result<PathBuf, Box<dyn std::error::Error>> {
-    let mut conf = Ini::new();
-    conf.load(WAYDROID_CONFIG)?;
-    let images = conf
-        .get("waydroid", "images_path")
-        .expect(&msg_err_str("Coldn't get path for images!"));
-    OtherOk(PathBuf::from(images).join("system.img"))
+pub fn get_systemimg_path(ctx: &Context) -> anyhow::Result<PathBuf> {
+    Ok(ctx.images_path()?.join("system.img"))
 }
 
-fn get_vendorimg_path() -> anyhow::Result<PathBuf, Box<dyn std::error::Error>> {
-    let mut conf = Ini::new();
-    conf.load(WAYDROID_CONFIG)?;
-    let images = conf
-        .get("waydroid", "images_path")
-        .expect(&msg_err_str("Coldn't get path for images!"));
-    OtherOk(PathBuf::from(images).join("vendor.img"))
+pub fn get_vendorimg_path(ctx: &Context) -> anyhow::Result<PathBuf> {
+    Ok(ctx.images_path()?.join("vendor.img"))
 }
 
 pub fn get_image_size(image: PathBuf) -> anyhow::Result<u64> {
@@ -90,122 +79,310 @@ pub fn get_image_size(image: PathBuf) -> anyhow::Result<u64> {
     Ok(file.metadata()?.len())
 }
 
-pub fn mount_system(mut waydroid: WaydroidContainer, quiet: bool) -> anyhow::Result<bool> {
-    fn run_checked_command(cmd: &str, args: &[&str]) -> anyhow::Result<()> {
-        if let OtherOk(status) = Command::new(cmd)
-            .args(args)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-        {
-            if !status.success() {
-                return Err(anyhow!("Command {} exited with failure code!", cmd));
-            }
-        } else {
-            return Err(anyhow!("Failed to run {} command!", cmd));
+fn run_checked_command(report: &dyn Reporter, cmd: &str, args: &[&str]) -> anyhow::Result<()> {
+    let mut command = Command::new(cmd);
+    command
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    report.host_command(&command);
+    if let OtherOk(status) = command.status() {
+        if !status.success() {
+            return Err(anyhow!("Command {} exited with failure code!", cmd));
         }
-        Ok(())
-    }
-
-    fn try_mount_with_retries(image: &Path, target: &Path, tries: usize) -> anyhow::Result<()> {
-        for i in 1..=tries {
-            if let OtherOk(status) = Command::new("mount")
-                .args([
-                    "-o",
-                    "rw,loop",
-                    image.to_string_lossy().trim(),
-                    target.to_string_lossy().trim(),
-                ])
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .status()
-            {
-                if status.success() {
-                    return Ok(());
-                } else if i == tries {
-                    return Err(anyhow!("Command mount exited with failure code!"));
-                }
-            } else if i == tries {
-                return Err(anyhow!("Failed to run mount command!"));
-            }
-            sleep(Duration::from_secs(1));
+    } else {
+        return Err(anyhow!("Failed to run {} command!", cmd));
+    }
+    Ok(())
+}
+
+/// Parses a size such as `3G`, `1024M`, `512K` or a plain byte count.
+pub fn parse_size(size: &str) -> anyhow::Result<u64> {
+    let size = size.trim();
+    let (number, shift) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('G') => (&size[..size.len() - 1], 30),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('K') => (&size[..size.len() - 1], 10),
+        _ => (size, 0),
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| WsuError::InvalidInput(format!("\"{}\" isn't a size", size)))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| WsuError::InvalidInput(format!("\"{}\" is too large", size)).into())
+}
+
+pub fn dir_size(dir: &Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
         }
-        Ok(())
     }
+    Ok(size)
+}
 
-    if waydroid.is_session_running(true, false)? {
-        waydroid.stop(true)?;
+/// Upper bound of what installing `apk` copies into the system image, taken
+/// from the apk's libraries and assets for every ABI.
+pub fn apk_payload_size(apk: &Path) -> anyhow::Result<u64> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(apk)?))?;
+    let mut size = fs::metadata(apk)?.len();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        if entry.name().starts_with("lib/") || entry.name().starts_with("assets/") {
+            size += entry.size();
+        }
     }
+    Ok(size)
+}
 
-    let system = get_systemimg_path().expect(&msg_err_str("Failed to get system img path!"));
-    if !system.exists() {
-        return Err(anyhow!("Couldn't find Waydroid system image!"));
+/// Runs e2fsck on `image`, reporting what it repaired. A `read_only` check
+/// only looks, errors it finds are left for the next read-write mount.
+///
+/// e2fsck exits with 1 or 2 when it corrected errors, with 4 when errors were
+/// left uncorrected and with 8 or more when it couldn't check the filesystem.
+fn fsck_image(
+    report: &dyn Reporter,
+    image: &Path,
+    name: &str,
+    quiet: bool,
+    read_only: bool,
+) -> anyhow::Result<()> {
+    let mut command = Command::new("e2fsck");
+    command
+        .args([
+            if read_only { "-n" } else { "-y" },
+            "-f",
+            image.to_string_lossy().trim(),
+        ])
+        .stdin(Stdio::null());
+    report.host_command(&command);
+    let output = command
+        .output()
+        .map_err(|_| anyhow!("Failed to run e2fsck command!"))?;
+    let code = output
+        .status
+        .code()
+        .ok_or_else(|| anyhow!("e2fsck was killed while checking the {} image!", name))?;
+    let output = String::from_utf8_lossy(&output.stdout).to_string()
+        + &String::from_utf8_lossy(&output.stderr);
+
+    if read_only && code < 8 {
+        if code != 0 {
+            report.warn(&format!(
+                "The {} image has filesystem errors, they get repaired when it's mounted for writing",
+                name
+            ));
+        }
+        return Ok(());
+    }
+    if code >= 4 {
+        return Err(anyhow!(
+            "e2fsck couldn't repair the {} image (exit code {}):\n{}",
+            name,
+            code,
+            output.trim_end()
+        ));
+    }
+    if code != 0 && !quiet {
+        report.step(&format!("Repaired {} image filesystem", name));
+        for line in output.lines().filter(|line| is_fsck_repair(line)) {
+            report.info(&format!("   {}", line.trim()));
+        }
     }
+    Ok(())
+}
 
-    run_checked_command("e2fsck", &["-y", "-f", system.to_string_lossy().trim()])?;
+fn is_fsck_repair(line: &str) -> bool {
+    let line = line.trim();
+    !(line.is_empty()
+        || line.starts_with("e2fsck ")
+        || line.starts_with("Pass ")
+        || line.contains("*****")
+        // bare "Fix? yes" answers, the problem is on the line before
+        || (line.ends_with("? yes") && line.split_whitespace().count() == 2)
+        || line.contains(" files (")
+        || line.contains(" blocks"))
+}
 
-    if get_image_size(system.clone())? < 3221225472 {
-        if !quiet {
-            msg_sub("Resizing system image");
-        }
-        run_checked_command("resize2fs", &[system.to_string_lossy().trim(), "3G"])?;
+/// Bytes left for regular files in an ext4 image, read from its superblock.
+fn ext4_free_space(image: &Path) -> anyhow::Result<u64> {
+    let mut file = File::open(image)?;
+    let mut superblock = [0u8; 1024];
+    file.seek(SeekFrom::Start(1024))?;
+    file.read_exact(&mut superblock)?;
+
+    let word = |offset: usize| {
+        u32::from_le_bytes(superblock[offset..offset + 4].try_into().unwrap()) as u64
+    };
+    if superblock[56..58] != [0x53, 0xEF] {
+        return Err(anyhow!("{} isn't an ext4 image", image.to_string_lossy()));
+    }
+    let block_size = 1024u64 << word(24);
+    let mut free = word(12);
+    let mut reserved = word(8);
+    // INCOMPAT_64BIT adds the high halves of the block counters
+    if word(96) & 0x80 != 0 {
+        free |= word(344) << 32;
+        reserved |= word(340) << 32;
     }
+    Ok(free.saturating_sub(reserved) * block_size)
+}
 
-    let vendor = get_vendorimg_path().expect(&msg_err_str("Failed to get vendor img path!"));
-    if !vendor.exists() {
-        return Err(anyhow!("Couldn't find Waydroid vendor image!"));
+/// Checks the filesystem of `image` and grows it to `target_size` bytes,
+/// or further when it has less than `needed` bytes free.
+pub fn prepare_image(
+    report: &dyn Reporter,
+    image: &Path,
+    name: &str,
+    target_size: u64,
+    needed: u64,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    fsck_image(report, image, name, quiet, false)?;
+
+    let size = get_image_size(image.to_path_buf())?;
+    let free = ext4_free_space(image)?;
+    let grown = size + needed.saturating_sub(free);
+    let new_size = target_size.max(grown).div_ceil(1048576) * 1048576;
+    if new_size <= size {
+        return Ok(());
     }
 
-    run_checked_command("e2fsck", &["-y", "-f", vendor.to_string_lossy().trim()])?;
+    let dir = match image.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let host_free = free_space(dir)?;
+    if host_free < new_size - size {
+        return Err(anyhow!(
+            "Growing the {} image to {} MiB needs {} MiB, only {} MiB free on {}",
+            name,
+            new_size >> 20,
+            (new_size - size).div_ceil(1048576),
+            host_free >> 20,
+            dir.to_string_lossy()
+        ));
+    }
 
-    if get_image_size(vendor.clone())? < 1073741824 {
-        if !quiet {
-            msg_sub("Resizing vendor image");
-        }
-        run_checked_command("resize2fs", &[vendor.to_string_lossy().trim(), "1G"])?;
+    if !quiet {
+        report.step(&format!(
+            "Resizing {} image to {} MiB",
+            name,
+            new_size >> 20
+        ));
+    }
+    let size = format!("{}M", new_size >> 20);
+    run_checked_command(
+        report,
+        "resize2fs",
+        &[image.to_string_lossy().trim(), &size],
+    )?;
+    Ok(())
+}
+
+/// Loop-mounts a system image at `mnt` and its vendor image at `mnt/vendor`.
+/// `needed` is the room the Magisk payload takes on the system image, 0 when
+/// nothing gets installed. `read_only` mounts are only checked, never
+/// repaired or resized.
+///
+/// Both images are unmounted when the returned guard is dropped, and a failed
+/// vendor mount doesn't leave the system image behind.
+pub fn mount_images(
+    ctx: &Context,
+    system: &Path,
+    vendor: &Path,
+    mnt: &Path,
+    needed: u64,
+    quiet: bool,
+    read_only: bool,
+) -> anyhow::Result<MountGuard> {
+    if !system.exists() {
+        return Err(WsuError::Mount("Couldn't find system image!".to_string()).into());
+    }
+    if !vendor.exists() {
+        return Err(WsuError::Mount("Couldn't find vendor image!".to_string()).into());
+    }
+    if read_only {
+        fsck_image(ctx.report(), system, "system", quiet, true)?;
+        fsck_image(ctx.report(), vendor, "vendor", quiet, true)?;
+    } else {
+        prepare_image(
+            ctx.report(),
+            system,
+            "system",
+            ctx.system_image_size,
+            needed,
+            quiet,
+        )?;
+        let vendor_needed = if needed > 0 { SEPOLICY_SPACE } else { 0 };
+        prepare_image(
+            ctx.report(),
+            vendor,
+            "vendor",
+            ctx.vendor_image_size,
+            vendor_needed,
+            quiet,
+        )?;
     }
 
-    let mnt = temp_dir().join("waydroidsu/mnt");
-    let vendor_mnt = mnt.join("vendor");
     if !mnt.exists() {
-        fs::create_dir_all(&mnt)?;
+        fs::create_dir_all(mnt)?;
     }
 
     if !quiet {
-        msg_sub("Mounting system image");
+        ctx.report().step("Mounting system image");
     }
-    try_mount_with_retries(&system, &mnt, 5)?;
+    let system_guard = mount_loop(system, mnt, read_only)?;
 
     if !quiet {
-        msg_sub("Mounting vendor image");
+        ctx.report().step("Mounting vendor image");
+    }
+    mount_loop(vendor, &mnt.join("vendor"), read_only)?.keep();
+    Ok(system_guard)
+}
+
+pub fn mount_system(
+    ctx: &Context,
+    mut waydroid: WaydroidContainer,
+    needed: u64,
+    quiet: bool,
+    read_only: bool,
+) -> anyhow::Result<bool> {
+    if waydroid.is_session_running(true, false)? {
+        waydroid.stop(true)?;
     }
-    try_mount_with_retries(&vendor, &vendor_mnt, 5)?;
 
+    let system = get_systemimg_path(ctx)?;
+    if !system.exists() {
+        return Err(WsuError::Mount("Couldn't find Waydroid system image!".to_string()).into());
+    }
+    let vendor = get_vendorimg_path(ctx)?;
+    if !vendor.exists() {
+        return Err(WsuError::Mount("Couldn't find Waydroid vendor image!".to_string()).into());
+    }
+
+    // stays mounted across the install steps, umount_system takes it down
+    let mnt = temp_dir().join("waydroidsu/mnt");
+    mount_images(ctx, &system, &vendor, &mnt, needed, quiet, read_only)?.keep();
     Ok(true)
 }
 
-pub fn umount_system(quiet: bool) -> anyhow::Result<bool> {
-    let mnt = temp_dir().join("waydroidsu/mnt");
+pub fn umount_images(report: &dyn Reporter, mnt: &Path, quiet: bool) -> anyhow::Result<()> {
     if !quiet {
-        msg_sub("Umounting system and vendor image");
-    }
-    let tries = 5;
-    for i in 1..=tries {
-        if let OtherOk(status) = Command::new("umount").arg("-R").arg(mnt.clone()).status() {
-            if !status.success() {
-                if i == tries {
-                    return Err(anyhow!("Command umount exited with failure code!"));
-                }
-            } else {
-                break;
-            }
-        } else {
-            if i == tries {
-                return Err(anyhow!("Failed to run umount command!"));
-            }
-        }
-        sleep(Duration::from_secs(1));
+        report.step("Umounting system and vendor image");
     }
+    umount_recursive(mnt)
+}
+
+pub fn umount_system(report: &dyn Reporter, quiet: bool) -> anyhow::Result<bool> {
+    umount_images(report, &temp_dir().join("waydroidsu/mnt"), quiet)?;
     Ok(true)
 }
 
@@ -213,22 +390,28 @@ pub fn is_mounted_at(target_mount: &str) -> anyhow::Result<bool> {
     let file = File::open("/proc/mounts")?;
     let reader = BufReader::new(file);
 
-    for line in reader.lines() {
-        if let OtherOk(line) = line {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 2 && parts[1] == target_mount {
-                return Ok(true);
-            }
+    for line in reader.lines().map_while(Result::ok) {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() >= 2 && parts[1] == target_mount {
+            return Ok(true);
         }
     }
 
     Ok(false)
 }
 
-pub fn download_file(url: &str, output_path: &str, quiet: bool) -> anyhow::Result<()> {
+pub fn download_file(
+    report: &dyn Reporter,
+    url: &str,
+    output_path: &str,
+    quiet: bool,
+) -> anyhow::Result<()> {
     let client = Client::builder().timeout(Duration::from_secs(60)).build()?;
 
-    let response = client.get(url).send()?;
+    let response = client
+        .get(url)
+        .send()
+        .map_err(|e| WsuError::Network(e.to_string()))?;
 
     let total_size = response
         .headers()
@@ -237,18 +420,6 @@ pub fn download_file(url: &str, output_path: &str, quiet: bool) -> anyhow::Resul
         .and_then(|s| s.parse().ok())
         .unwrap_or(0);
 
-    let pb = if !quiet {
-        let bar = ProgressBar::new(total_size);
-        bar.set_style(
-            ProgressStyle::with_template(" [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                .unwrap()
-                .progress_chars("#>-"),
-        );
-        Some(bar)
-    } else {
-        None
-    };
-
     let mut source = response;
     let file = File::create(output_path)?;
     let mut writer = BufWriter::new(file);
@@ -262,30 +433,161 @@ pub fn download_file(url: &str, output_path: &str, quiet: bool) -> anyhow::Resul
         }
         writer.write_all(&buffer[..n])?;
         downloaded += n as u64;
-        if let Some(pb) = &pb {
-            pb.set_position(downloaded);
+        if !quiet {
+            report.progress(downloaded, total_size);
         }
     }
-
-    if let Some(pb) = pb {
-        pb.finish();
+    if !quiet {
+        report.progress_end();
     }
 
     Ok(())
 }
 
-pub fn get_arch() -> (&'static str, bool) {
-    let arch = std::env::consts::ARCH;
-    if arch == "x86_64" {
-        return ("x86_64", true);
-    } else if arch == "aarch64" {
-        return ("arm64-v8a", true);
-    } else {
-        msg_err(&format!("{} {}", arch.bold(), "isn't supported!"));
-        return (arch, false);
+/// ABIs Magisk is installed for, as reported by the Android image.
+pub struct Abi {
+    pub abi: String,
+    /// 32-bit companion ABI, `None` on 64-bit-only images.
+    pub abi32: Option<String>,
+    /// Native bridge library (libhoudini, libndk_translation) if the image translates arm apps.
+    pub native_bridge: Option<String>,
+}
+
+/// Supported primary ABIs and the 32-bit ABIs of the same instruction set.
+const SUPPORTED_ABIS: [(&str, &[&str]); 2] = [
+    ("x86_64", &["x86"]),
+    ("arm64-v8a", &["armeabi-v7a", "armeabi"]),
+];
+
+impl Abi {
+    /// Init scripts of the zygotes this image starts.
+    pub fn zygote_rcs(&self) -> &'static [&'static str] {
+        if self.abi32.is_some() {
+            &["init.zygote32.rc", "init.zygote64_32.rc"]
+        } else {
+            &["init.zygote64.rc"]
+        }
+    }
+
+    /// ABIs zygote runs natively, Zygisk libraries are never loaded through the native bridge.
+    pub fn zygote_abis(&self) -> Vec<&str> {
+        let mut abis = vec![self.abi.as_str()];
+        if let Some(abi32) = &self.abi32 {
+            abis.push(abi32);
+        }
+        abis
     }
 }
 
+pub fn host_abi() -> anyhow::Result<&'static str> {
+    match env::consts::ARCH {
+        "x86_64" => Ok("x86_64"),
+        "aarch64" => Ok("arm64-v8a"),
+        arch => Err(anyhow!("{} isn't supported!", arch)),
+    }
+}
+
+fn prop_list(props: &HashMap<String, String>, keys: &[&str]) -> Option<Vec<String>> {
+    keys.iter().find_map(|key| {
+        props
+            .get(*key)
+            .filter(|v| !v.is_empty())
+            .map(|v| v.split(',').map(|abi| abi.trim().to_string()).collect())
+    })
+}
+
+const NATIVE_BRIDGE_PROP: &str = "ro.dalvik.vm.native.bridge";
+
+/// The bridge library a native bridge prop names, "0" turns it off.
+fn native_bridge(value: &str) -> Option<String> {
+    Some(value.trim().to_string()).filter(|bridge| !bridge.is_empty() && bridge != "0")
+}
+
+/// Reads the ABI lists from the image mounted at `rootfs`, `abi` overrides the primary ABI.
+pub fn get_abi(rootfs: &Path, abi: Option<&str>) -> anyhow::Result<Abi> {
+    let mut props = HashMap::new();
+    for build_prop in ["vendor/build.prop", "system/build.prop"] {
+        if let OtherOk(file_props) = read_prop_file(&rootfs.join(build_prop)) {
+            props.extend(file_props);
+        }
+    }
+
+    let abilist = prop_list(
+        &props,
+        &["ro.product.cpu.abilist", "ro.vendor.product.cpu.abilist"],
+    );
+    let abilist32 = prop_list(
+        &props,
+        &[
+            "ro.product.cpu.abilist32",
+            "ro.vendor.product.cpu.abilist32",
+        ],
+    );
+
+    let Some(abilist) = abilist else {
+        return Err(anyhow!(
+            "No ABI list in {}",
+            rootfs.join("system/build.prop").to_string_lossy()
+        ));
+    };
+    let primary = abi.map_or_else(|| abilist[0].clone(), str::to_string);
+    let Some((_, family)) = SUPPORTED_ABIS.iter().find(|(abi, _)| *abi == primary) else {
+        return Err(WsuError::InvalidInput(format!("{} isn't supported!", primary)).into());
+    };
+
+    // An image that lists no 32-bit ABI of the same instruction set has no 32-bit zygote
+    let abi32 = abilist32
+        .unwrap_or_default()
+        .into_iter()
+        .find(|abi| family.contains(&abi.as_str()));
+
+    let native_bridge = props
+        .get(NATIVE_BRIDGE_PROP)
+        .and_then(|bridge| native_bridge(bridge));
+
+    Ok(Abi {
+        abi: primary,
+        abi32,
+        native_bridge,
+    })
+}
+
+/// ABIs of the running container's image.
+pub fn get_running_abi(ctx: &Context, abi: Option<&str>) -> anyhow::Result<Abi> {
+    // the rootfs is only populated while the container runs
+    if !ctx.rootfs().join("system/build.prop").exists() {
+        return Err(WsuError::ContainerNotRunning.into());
+    }
+    let mut abi = get_abi(&ctx.rootfs(), abi)?;
+
+    // waydroid.cfg and waydroid_base.prop override the image's bridge, that's
+    // where installing libhoudini or libndk_translation puts it
+    let bridge = match ctx.waydroid_property(NATIVE_BRIDGE_PROP)? {
+        Some(bridge) => Some(bridge),
+        None if ctx.base_prop().exists() => read_prop_file(&ctx.base_prop())?
+            .get(NATIVE_BRIDGE_PROP)
+            .cloned(),
+        None => None,
+    };
+    if let Some(bridge) = bridge {
+        abi.native_bridge = native_bridge(&bridge);
+    }
+    Ok(abi)
+}
+
+/// Android SDK level of the image mounted at `rootfs`.
+pub fn get_sdk(rootfs: &Path) -> anyhow::Result<u32> {
+    let build_prop = rootfs.join("system/build.prop");
+    let props = read_prop_file(&build_prop)
+        .map_err(|e| anyhow!("Couldn't read {}: {}", build_prop.to_string_lossy(), e))?;
+    let sdk = ["ro.build.version.sdk", "ro.system.build.version.sdk"]
+        .iter()
+        .find_map(|key| props.get(*key))
+        .ok_or_else(|| anyhow!("No SDK level in {}", build_prop.to_string_lossy()))?;
+    sdk.parse()
+        .map_err(|_| anyhow!("Invalid SDK level \"{}\"", sdk))
+}
+
 pub fn create_dir_check(dir: PathBuf, erasing: bool) -> anyhow::Result<()> {
     if !dir.exists() {
         fs::create_dir_all(dir.clone())?;
@@ -299,21 +601,19 @@ pub fn create_dir_check(dir: PathBuf, erasing: bool) -> anyhow::Result<()> {
 pub fn chmod_755_recursive(dir: PathBuf) -> anyhow::Result<()> {
     fs::set_permissions(dir.clone(), Permissions::from_mode(0o755))?;
     if let OtherOk(entries) = fs::read_dir(dir) {
-        for entry in entries {
-            if let OtherOk(entry) = entry {
-                let path = entry.path();
-                if path.is_dir() {
-                    chmod_755_recursive(path)?;
-                } else {
-                    fs::set_permissions(path, Permissions::from_mode(0o755))?;
-                }
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                chmod_755_recursive(path)?;
+            } else {
+                fs::set_permissions(path, Permissions::from_mode(0o755))?;
             }
         }
     }
     Ok(())
 }
 
-pub fn cp_dir(source: PathBuf, destination: PathBuf) -> anyhow::Result<()> {
+pub fn cp_dir(report: &dyn Reporter, source: PathBuf, destination: PathBuf) -> anyhow::Result<()> {
     if let Some(name) = source.file_name() {
         let dest = destination.join(name);
         if !dest.exists() {
@@ -328,7 +628,7 @@ pub fn cp_dir(source: PathBuf, destination: PathBuf) -> anyhow::Result<()> {
                 let target_path = dest.join(item_name);
 
                 if item_path.is_dir() {
-                    cp_dir(item_path.clone(), dest.clone())?;
+                    cp_dir(report, item_path.clone(), dest.clone())?;
                 } else {
                     if item_path.exists() {
                         if target_path.exists() {
@@ -336,7 +636,7 @@ pub fn cp_dir(source: PathBuf, destination: PathBuf) -> anyhow::Result<()> {
                         }
                         fs::copy(&item_path, &target_path)?;
                     } else {
-                        msg_err(&format!(
+                        report.warn(&format!(
                             "Warning: File \"{}\" doesn't exist or was moved.",
                             item_path.to_string_lossy()
                         ));
@@ -349,20 +649,6 @@ pub fn cp_dir(source: PathBuf, destination: PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn gzip_compress(input_path: &str, output_path: &str) -> anyhow::Result<()> {
-    let input = File::open(input_path)?;
-    let reader = BufReader::new(input);
-
-    let output = File::create(output_path)?;
-    let writer = BufWriter::new(output);
-
-    let mut encoder = GzEncoder::new(writer, Compression::default());
-    std::io::copy(&mut reader.take(u64::MAX), &mut encoder)?;
-    encoder.finish()?; // flush + close
-
-    Ok(())
-}
-
 pub fn gzip_decompress(input_path: &str, output_path: &str) -> anyhow::Result<()> {
     let input = File::open(input_path)?;
     let reader = GzDecoder::new(BufReader::new(input));
@@ -383,14 +669,12 @@ pub fn generate_random_string(len: usize) -> String {
         .collect()
 }
 
-pub fn create_tmpdir() -> anyhow::Result<()> {
-    let has_overlay = has_overlay().expect(&msg_err_str(
-        "Couldn't reach the \"mount_overlays\" config.",
-    ));
+pub fn create_tmpdir(ctx: &Context) -> anyhow::Result<()> {
+    let has_overlay = ctx.has_overlay()?;
     let tempdir = temp_dir().join("waydroidsu");
     if tempdir.exists() {
-        if !has_overlay && is_mounted_at("mnt")? {
-            umount_system(true)?;
+        if !has_overlay && is_mounted_at(&tempdir.join("mnt").to_string_lossy())? {
+            umount_system(ctx.report(), true)?;
         }
         fs::remove_dir_all(&tempdir)?;
     }
@@ -410,8 +694,46 @@ pub fn remove_check(file: PathBuf) -> anyhow::Result<bool> {
     Ok(exists)
 }
 
-pub fn is_waydroid_initialized() -> bool {
-    PathBuf::from(WAYDROID_CONFIG).exists()
+pub fn read_prop_file(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut props = HashMap::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once('=') {
+            props.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    Ok(props)
+}
+
+pub fn android_path_to_host(ctx: &Context, path: &str) -> anyhow::Result<PathBuf> {
+    let path = path.trim();
+    if let Some(rel) = path.strip_prefix("/data/") {
+        Ok(ctx.data_dir()?.join(rel))
+    } else {
+        Ok(ctx.rootfs().join(path.trim_start_matches('/')))
+    }
+}
+
+pub fn free_space(path: &Path) -> anyhow::Result<u64> {
+    let c_path = CString::new(path.to_string_lossy().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(anyhow!(
+            "Couldn't get free space of {}: {}",
+            path.to_string_lossy(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
 }
 
 pub fn command_exists(cmd: &str) -> bool {
@@ -422,8 +744,8 @@ pub fn root() -> bool {
     let user_out = Command::new("bash")
         .args(["-c", "whoami"])
         .output()
-        .expect(&msg_err_str("Failed to execute \"whoami\" command."));
-    let user = String::from_utf8_lossy(&user_out.stdout).trim().to_string();
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string());
+    let user = user_out.unwrap_or_default();
 
     user == "root"
 }
@@ -453,3 +775,127 @@ pub fn get_data_home() -> anyhow::Result<String> {
     }
     Err(anyhow!("Couldn't get current xdg_data_home"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextArgs;
+    use std::process;
+
+    fn abi_of(name: &str, build_prop: Option<&str>, abi: Option<&str>) -> anyhow::Result<Abi> {
+        let rootfs = temp_dir().join(format!("wsu-test-abi-{}-{}", name, process::id()));
+        fs::create_dir_all(rootfs.join("system")).unwrap();
+        if let Some(content) = build_prop {
+            fs::write(rootfs.join("system/build.prop"), content).unwrap();
+        }
+        let result = get_abi(&rootfs, abi);
+        fs::remove_dir_all(&rootfs).unwrap();
+        result
+    }
+
+    #[test]
+    fn abi32_comes_from_abilist32() {
+        let abi = abi_of(
+            "houdini",
+            Some("ro.product.cpu.abilist=x86_64,x86,arm64-v8a,armeabi-v7a\nro.product.cpu.abilist32=x86,armeabi-v7a\nro.dalvik.vm.native.bridge=libhoudini.so\n"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(abi.abi, "x86_64");
+        assert_eq!(abi.abi32.as_deref(), Some("x86"));
+        assert_eq!(abi.native_bridge.as_deref(), Some("libhoudini.so"));
+
+        let abi = abi_of(
+            "armeabi",
+            Some("ro.product.cpu.abilist=arm64-v8a,armeabi\nro.product.cpu.abilist32=armeabi\n"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(abi.abi32.as_deref(), Some("armeabi"));
+    }
+
+    #[test]
+    fn abi32_missing_without_matching_32_bit_abi() {
+        let abi = abi_of(
+            "64only",
+            Some("ro.product.cpu.abilist=x86_64,arm64-v8a\nro.product.cpu.abilist32=\nro.dalvik.vm.native.bridge=0\n"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(abi.abi32, None);
+        assert_eq!(abi.native_bridge, None);
+        assert_eq!(abi.zygote_rcs(), &["init.zygote64.rc"]);
+
+        let abi = abi_of(
+            "override",
+            Some("ro.product.cpu.abilist=x86_64,x86\nro.product.cpu.abilist32=x86\n"),
+            Some("arm64-v8a"),
+        )
+        .unwrap();
+        assert_eq!(abi.abi, "arm64-v8a");
+        assert_eq!(abi.abi32, None);
+    }
+
+    #[test]
+    fn waydroid_config_overrides_native_bridge() {
+        let dir = temp_dir().join(format!("wsu-test-bridge-{}", process::id()));
+        fs::create_dir_all(dir.join("rootfs/system")).unwrap();
+        fs::write(
+            dir.join("rootfs/system/build.prop"),
+            "ro.product.cpu.abilist=x86_64,x86\nro.product.cpu.abilist32=x86\nro.dalvik.vm.native.bridge=0\n",
+        )
+        .unwrap();
+        let config = dir.join("waydroid.cfg");
+        fs::write(&config, "[waydroid]\nmount_overlays = True\n").unwrap();
+        let ctx = Context::resolve(
+            ContextArgs {
+                waydroid_dir: Some(dir.to_string_lossy().to_string()),
+                ..Default::default()
+            },
+            |_, _, _| None,
+        )
+        .unwrap();
+        assert_eq!(get_running_abi(&ctx, None).unwrap().native_bridge, None);
+
+        fs::write(
+            ctx.base_prop(),
+            "ro.dalvik.vm.native.bridge=libndk_translation.so\n",
+        )
+        .unwrap();
+        assert_eq!(
+            get_running_abi(&ctx, None)
+                .unwrap()
+                .native_bridge
+                .as_deref(),
+            Some("libndk_translation.so")
+        );
+
+        fs::write(
+            &config,
+            "[waydroid]\nmount_overlays = True\n\n[properties]\nro.dalvik.vm.native.bridge = libhoudini.so\n",
+        )
+        .unwrap();
+        assert_eq!(
+            get_running_abi(&ctx, None)
+                .unwrap()
+                .native_bridge
+                .as_deref(),
+            Some("libhoudini.so")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn abi_needs_an_abilist() {
+        assert!(abi_of("empty", None, None).is_err());
+        assert!(abi_of("empty-override", None, Some("x86_64")).is_err());
+        assert!(
+            abi_of(
+                "unsupported",
+                Some("ro.product.cpu.abilist=riscv64\n"),
+                None
+            )
+            .is_err()
+        );
+    }
+}